@@ -0,0 +1,127 @@
+//! Throughput benchmark of the CPU frame conversion
+//!
+//! Every supported format is converted from pseudo-random frames with a
+//! single thread and with all conversion threads, outputs of both runs
+//! are compared to catch errors in splitting frames between threads.
+use std::error::Error;
+use std::time::Instant;
+
+use demosaic::{demosaic, Conversion};
+use isp;
+use parallel;
+use rggb::Algorithm;
+use yuv::Encoding;
+
+/// Resolutions measured when none are specified
+const RESOLUTIONS: [[u32; 2]; 4] = [
+    [640, 480], [1280, 720], [1920, 1080], [3840, 2160],
+];
+/// Measured formats with the demosaicing algorithm used for Bayer frames
+const CASES: [(&[u8; 4], Algorithm); 6] = [
+    (b"GREY", Algorithm::Bilinear),
+    (b"RGGB", Algorithm::Bilinear),
+    (b"RGGB", Algorithm::Malvar),
+    (b"YUYV", Algorithm::Bilinear),
+    (b"RGB3", Algorithm::Bilinear),
+    (b"BGR3", Algorithm::Bilinear),
+];
+
+fn frame_size(format: &[u8; 4], res: [u32; 2]) -> usize {
+    let pixels = (res[0]*res[1]) as usize;
+    match format {
+        b"YUYV" => 2*pixels,
+        b"RGB3" | b"BGR3" => 3*pixels,
+        _ => pixels,
+    }
+}
+
+/// Xorshift noise, conversion speed does not depend on the frame content,
+/// but random data prevents shortcuts on uniform frames
+fn noise(len: usize) -> Vec<u8> {
+    let mut s = 0x2545_f491u32;
+    (0..len).map(|_| {
+        s ^= s << 13;
+        s ^= s >> 17;
+        s ^= s << 5;
+        (s >> 24) as u8
+    }).collect()
+}
+
+/// Convert frame using `threads` threads, returns throughput in megapixels
+/// per second together with the converted frame
+fn measure(
+    format: &[u8; 4], algorithm: Algorithm, res: [u32; 2], frame: &[u8],
+    frames: u32, threads: usize,
+) -> (f64, Vec<[u8; 3]>) {
+    parallel::set_threads(threads);
+    let conv = Conversion {
+        algorithm, yuv: Encoding::default(), isp: isp::Params::default(),
+    };
+    let mut buf = vec![[0u8; 3]; (res[0]*res[1]) as usize];
+    // warm-up, also touches all output pages
+    demosaic(*format, res, &mut buf, frame, &conv);
+    let t = Instant::now();
+    for _ in 0..frames {
+        demosaic(*format, res, &mut buf, frame, &conv);
+    }
+    let secs = t.elapsed().as_secs_f64();
+    let mp = f64::from(res[0]*res[1])*f64::from(frames)/1e6;
+    (mp/secs, buf)
+}
+
+/// Measure conversion throughput and print it as a table
+pub(crate) fn run(
+    resolutions: &[[u32; 2]], frames: u32, threads: usize,
+) -> Result<(), Box<Error>> {
+    let resolutions = if resolutions.is_empty() {
+        &RESOLUTIONS[..]
+    } else {
+        resolutions
+    };
+    parallel::set_threads(threads);
+    let threads = parallel::threads();
+
+    println!(
+        "{:<6} {:<16} {:>9} {:>13} {:>13}",
+        "format", "algorithm", "size", "1 thread",
+        format!("{} threads", threads),
+    );
+    for &res in resolutions {
+        for &(format, algorithm) in CASES.iter() {
+            let frame = noise(frame_size(format, res));
+            let (single, expected) =
+                measure(format, algorithm, res, &frame, frames, 1);
+            let (multi, buf) =
+                measure(format, algorithm, res, &frame, frames, threads);
+            let name = String::from_utf8_lossy(format);
+            if buf != expected {
+                Err(format!(
+                    "{} {}x{}: multi-threaded conversion differs from \
+                    single-threaded", name, res[0], res[1],
+                ))?
+            }
+            let algorithm = match format {
+                b"RGGB" => algorithm.name(),
+                _ => "-",
+            };
+            println!(
+                "{:<6} {:<16} {:>9} {:>8.1} MP/s {:>8.1} MP/s",
+                name, algorithm, format!("{}x{}", res[0], res[1]),
+                single, multi,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parse frame resolution in the `<width>x<height>` format
+pub(crate) fn parse_resolution(s: &str) -> Result<[u32; 2], &'static str> {
+    let err = "resolution must be in the <width>x<height> format with even \
+        width and height of at least 8 pixels";
+    let mut iter = s.split('x').map(|v| v.trim().parse::<u32>());
+    match (iter.next(), iter.next(), iter.next()) {
+        (Some(Ok(w)), Some(Ok(h)), None)
+            if w >= 8 && h >= 8 && w % 2 == 0 && h % 2 == 0 => Ok([w, h]),
+        _ => Err(err),
+    }
+}
@@ -0,0 +1,213 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+/// Lens distortion model
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DistortionModel {
+    /// OpenCV radial-tangential model with coefficients `k1, k2, p1, p2`
+    /// optionally followed by `k3` and rational coefficients `k4, k5, k6`
+    RadTan,
+    /// OpenCV fisheye (equidistant) model with coefficients `k1, k2, k3, k4`
+    Fisheye,
+}
+
+/// Pinhole camera intrinsics with lens distortion
+#[derive(Clone, Debug)]
+pub struct Intrinsics {
+    /// Focal lengths in pixels
+    pub f: [f64; 2],
+    /// Principal point in pixels
+    pub c: [f64; 2],
+    pub model: DistortionModel,
+    pub dist: Vec<f64>,
+    /// Resolution of images used for calibration
+    pub resolution: Option<[u32; 2]>,
+}
+
+/// Find value of the `key` field in YAML or JSON text, returns text which
+/// follows the colon after the key
+fn find_key<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut start = 0;
+    while let Some(pos) = text[start..].find(key) {
+        let begin = start + pos;
+        let end = begin + key.len();
+        start = end;
+
+        if text[..begin].chars().next_back().map_or(false, is_word) {
+            continue;
+        }
+        let rest = text[end..].trim_start_matches(|c| c == '"' || c == '\'');
+        if rest.chars().next().map_or(false, is_word) { continue; }
+        let rest = rest.trim_start();
+        if rest.starts_with(':') { return Some(&rest[1..]); }
+    }
+    None
+}
+
+/// Find the first key from the list which is present in the text
+fn find_any<'a>(text: &'a str, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().filter_map(|k| find_key(text, k)).next()
+}
+
+/// Read numbers from the first (possibly nested) list in the text, this
+/// works both for JSON arrays and for `data: [...]` fields of OpenCV and
+/// ROS YAML files
+fn parse_list(text: &str) -> Result<Vec<f64>, Box<Error>> {
+    let start = text.find('[').ok_or("list not found")?;
+    let mut depth = 0;
+    let mut end = None;
+    for (i, c) in text[start..].char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 { end = Some(start + i); break; }
+            },
+            _ => (),
+        }
+    }
+    let end = end.ok_or("unterminated list")?;
+    let list = &text[start..end];
+    let nums = list
+        .split(|c: char| c == '[' || c == ']' || c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()?;
+    Ok(nums)
+}
+
+/// Read scalar value which follows the key
+fn parse_scalar(text: &str) -> &str {
+    let end = text
+        .find(|c| c == '\n' || c == ',' || c == '}')
+        .unwrap_or(text.len());
+    text[..end].trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+impl Intrinsics {
+    /// Load intrinsics from OpenCV (`cv::FileStorage`) or ROS YAML file,
+    /// or from JSON file with the same field names
+    pub fn load(path: &str) -> Result<Self, Box<Error>> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path, e).into())
+    }
+
+    fn parse(text: &str) -> Result<Self, Box<Error>> {
+        let k = find_any(text, &["camera_matrix", "cameraMatrix", "K"])
+            .ok_or("camera matrix not found")?;
+        let k = parse_list(k)?;
+        if k.len() != 9 { Err("camera matrix must have 9 elements")? }
+
+        let dist = match find_any(text, &[
+            "distortion_coefficients", "dist_coeffs", "distCoeffs", "D",
+        ]) {
+            Some(d) => parse_list(d)?,
+            None => vec![],
+        };
+
+        // OpenCV files do not name the model, missing key means the
+        // radial-tangential one
+        let model = match find_key(text, "distortion_model").map(parse_scalar) {
+            None | Some("plumb_bob") | Some("radtan")
+                | Some("rational_polynomial") => DistortionModel::RadTan,
+            Some("equidistant") | Some("fisheye") => DistortionModel::Fisheye,
+            Some(m) => Err(format!("unsupported distortion model: {}", m))?,
+        };
+        match model {
+            DistortionModel::RadTan => match dist.len() {
+                0 | 4 | 5 | 8 => (),
+                _ => Err("radial-tangential model requires 4, 5 or 8 \
+                    distortion coefficients")?,
+            },
+            DistortionModel::Fisheye => if dist.len() != 4 {
+                Err("fisheye model requires 4 distortion coefficients")?
+            },
+        }
+
+        let width = find_key(text, "image_width").map(parse_scalar);
+        let height = find_key(text, "image_height").map(parse_scalar);
+        let resolution = match (width, height) {
+            (Some(w), Some(h)) => Some([w.parse()?, h.parse()?]),
+            _ => None,
+        };
+
+        Ok(Intrinsics {
+            f: [k[0], k[4]], c: [k[2], k[5]], model, dist, resolution,
+        })
+    }
+
+    /// Scale intrinsics to the given image resolution
+    pub fn scaled(&self, res: [u32; 2]) -> Self {
+        let mut out = self.clone();
+        if let Some(calib_res) = self.resolution {
+            for i in 0..2 {
+                let k = (res[i] as f64)/(calib_res[i] as f64);
+                out.f[i] *= k;
+                out.c[i] *= k;
+            }
+        }
+        out.resolution = Some(res);
+        out
+    }
+
+    /// Apply distortion to normalized image coordinates
+    pub fn distort(&self, p: [f64; 2]) -> [f64; 2] {
+        let [x, y] = p;
+        let d = |i: usize| self.dist.get(i).cloned().unwrap_or(0.);
+        let r2 = x*x + y*y;
+        match self.model {
+            DistortionModel::RadTan => {
+                let (k1, k2, p1, p2, k3) = (d(0), d(1), d(2), d(3), d(4));
+                let (k4, k5, k6) = (d(5), d(6), d(7));
+                let radial = (1. + r2*(k1 + r2*(k2 + r2*k3)))
+                    /(1. + r2*(k4 + r2*(k5 + r2*k6)));
+                [
+                    x*radial + 2.*p1*x*y + p2*(r2 + 2.*x*x),
+                    y*radial + p1*(r2 + 2.*y*y) + 2.*p2*x*y,
+                ]
+            },
+            DistortionModel::Fisheye => {
+                let r = r2.sqrt();
+                if r < 1e-12 { return p; }
+                let theta = r.atan();
+                let t2 = theta*theta;
+                let theta_d = theta*(1. + t2*(d(0) + t2*(d(1)
+                    + t2*(d(2) + t2*d(3)))));
+                let k = theta_d/r;
+                [x*k, y*k]
+            },
+        }
+    }
+
+    /// Project normalized image coordinates into pixel coordinates
+    pub fn project(&self, p: [f64; 2]) -> [f64; 2] {
+        let d = self.distort(p);
+        [self.f[0]*d[0] + self.c[0], self.f[1]*d[1] + self.c[1]]
+    }
+
+    /// Build remap table for undistortion: for each pixel of the rectified
+    /// image it contains normalized texture coordinates of the corresponding
+    /// point in the distorted image
+    ///
+    /// Rectified image uses the same camera matrix as the distorted one.
+    pub fn undistort_map(&self, res: [u32; 2]) -> Vec<[f32; 2]> {
+        let intr = self.scaled(res);
+        let mut map = Vec::with_capacity((res[0]*res[1]) as usize);
+        for v in 0..res[1] {
+            for u in 0..res[0] {
+                let x = ((u as f64) - intr.c[0])/intr.f[0];
+                let y = ((v as f64) - intr.c[1])/intr.f[1];
+                let p = intr.project([x, y]);
+                // pixel centers have integer coordinates
+                map.push([
+                    ((p[0] + 0.5)/(res[0] as f64)) as f32,
+                    ((p[1] + 0.5)/(res[1] as f64)) as f32,
+                ]);
+            }
+        }
+        map
+    }
+}
@@ -0,0 +1,679 @@
+//! Offline intrinsic calibration from checkerboard images
+//!
+//! Initial focal lengths are estimated from board homographies with the
+//! principal point fixed at the image center, after which all intrinsic
+//! parameters, distortion coefficients and board poses are refined with
+//! the Levenberg-Marquardt algorithm minimizing reprojection error.
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use png;
+
+use calib::{DistortionModel, Intrinsics};
+use chessboard::{self, GreyImage};
+
+/// Minimal number of views with detected board
+const MIN_VIEWS: usize = 3;
+/// Number of intrinsic parameters: focal lengths, principal point and
+/// `k1, k2, p1, p2, k3` distortion coefficients
+const N_INTR: usize = 9;
+/// Number of pose parameters of a view: rotation and translation vectors
+const N_POSE: usize = 6;
+const MAX_ITERATIONS: usize = 100;
+
+type Mat3 = [[f64; 3]; 3];
+
+fn mat_mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut out = [[0.; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = (0..3).map(|k| a[i][k]*b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1]*b[2] - a[2]*b[1], a[2]*b[0] - a[0]*b[2], a[0]*b[1] - a[1]*b[0]]
+}
+
+fn norm3(a: [f64; 3]) -> f64 {
+    (a[0]*a[0] + a[1]*a[1] + a[2]*a[2]).sqrt()
+}
+
+/// Solve linear system `a*x = b` with `n` unknowns using Gaussian
+/// elimination, `a` is stored in the row-major order
+fn solve(mut a: Vec<f64>, mut b: Vec<f64>, n: usize) -> Option<Vec<f64>> {
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| {
+                a[i*n + col].abs().partial_cmp(&a[j*n + col].abs()).unwrap()
+            })?;
+        if a[pivot*n + col].abs() < 1e-300 { return None; }
+        if pivot != col {
+            for k in 0..n { a.swap(pivot*n + k, col*n + k); }
+            b.swap(pivot, col);
+        }
+        for row in col + 1..n {
+            let k = a[row*n + col]/a[col*n + col];
+            if k == 0. { continue; }
+            for c in col..n { a[row*n + c] -= k*a[col*n + c]; }
+            b[row] -= k*b[col];
+        }
+    }
+    let mut x = vec![0.; n];
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|c| a[row*n + c]*x[c]).sum();
+        x[row] = (b[row] - s)/a[row*n + row];
+    }
+    if x.iter().all(|v| v.is_finite()) { Some(x) } else { None }
+}
+
+/// Eigenvector of the symmetric `n`x`n` matrix corresponding to the
+/// smallest eigenvalue, computed with the Jacobi eigenvalue algorithm
+fn smallest_eigenvector(a: &[f64], n: usize) -> Vec<f64> {
+    let mut a = a.to_vec();
+    let mut v = vec![0.; n*n];
+    for i in 0..n { v[i*n + i] = 1.; }
+
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i*n + j]*a[i*n + j])
+            .sum();
+        if off < 1e-30 { break; }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p*n + q];
+                if apq.abs() < 1e-300 { continue; }
+                let theta = (a[q*n + q] - a[p*n + p])/(2.*apq);
+                let t = if theta == 0. {
+                    1.
+                } else {
+                    theta.signum()/(theta.abs() + (theta*theta + 1.).sqrt())
+                };
+                let c = 1./(t*t + 1.).sqrt();
+                let s = t*c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k*n + p], a[k*n + q]);
+                    a[k*n + p] = c*akp - s*akq;
+                    a[k*n + q] = s*akp + c*akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p*n + k], a[q*n + k]);
+                    a[p*n + k] = c*apk - s*aqk;
+                    a[q*n + k] = s*apk + c*aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k*n + p], v[k*n + q]);
+                    v[k*n + p] = c*vkp - s*vkq;
+                    v[k*n + q] = s*vkp + c*vkq;
+                }
+            }
+        }
+    }
+    let min = (0..n)
+        .min_by(|&i, &j| a[i*n + i].partial_cmp(&a[j*n + j]).unwrap())
+        .unwrap();
+    (0..n).map(|k| v[k*n + min]).collect()
+}
+
+/// Similarity transform which moves centroid of points into the origin
+/// and makes their mean distance from it equal to `sqrt(2)`
+fn normalization(points: &[[f64; 2]]) -> Mat3 {
+    let n = points.len() as f64;
+    let cx = points.iter().map(|p| p[0]).sum::<f64>()/n;
+    let cy = points.iter().map(|p| p[1]).sum::<f64>()/n;
+    let d = points.iter()
+        .map(|p| ((p[0] - cx).powi(2) + (p[1] - cy).powi(2)).sqrt())
+        .sum::<f64>()/n;
+    let s = if d > 0. { 2f64.sqrt()/d } else { 1. };
+    [[s, 0., -s*cx], [0., s, -s*cy], [0., 0., 1.]]
+}
+
+fn transform(m: &Mat3, p: [f64; 2]) -> [f64; 2] {
+    let z = m[2][0]*p[0] + m[2][1]*p[1] + m[2][2];
+    [
+        (m[0][0]*p[0] + m[0][1]*p[1] + m[0][2])/z,
+        (m[1][0]*p[0] + m[1][1]*p[1] + m[1][2])/z,
+    ]
+}
+
+/// Estimate homography which maps `src` points into `dst` points using
+/// normalized direct linear transform
+fn homography(src: &[[f64; 2]], dst: &[[f64; 2]]) -> Mat3 {
+    let ts = normalization(src);
+    let td = normalization(dst);
+    let mut ata = vec![0.; 81];
+    for (s, d) in src.iter().zip(dst.iter()) {
+        let [x, y] = transform(&ts, *s);
+        let [u, v] = transform(&td, *d);
+        let rows = [
+            [-x, -y, -1., 0., 0., 0., u*x, u*y, u],
+            [0., 0., 0., -x, -y, -1., v*x, v*y, v],
+        ];
+        for r in rows.iter() {
+            for i in 0..9 {
+                for j in 0..9 { ata[i*9 + j] += r[i]*r[j]; }
+            }
+        }
+    }
+    let h = smallest_eigenvector(&ata, 9);
+    let hn = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], h[8]]];
+
+    let s = td[0][0];
+    let td_inv = [
+        [1./s, 0., -td[0][2]/s], [0., 1./s, -td[1][2]/s], [0., 0., 1.],
+    ];
+    mat_mul(&mat_mul(&td_inv, &hn), &ts)
+}
+
+/// Estimate focal lengths from homographies assuming that principal point
+/// is at `c`, uses orthogonality of the board axes and their diagonals
+fn init_focal(homographies: &[Mat3], c: [f64; 2]) -> Option<[f64; 2]> {
+    let (mut ata, mut atb) = (vec![0.; 4], vec![0.; 2]);
+    let mut add = |a: [f64; 2], b: f64| {
+        for i in 0..2 {
+            for j in 0..2 { ata[2*i + j] += a[i]*a[j]; }
+            atb[i] += a[i]*b;
+        }
+    };
+    for hm in homographies {
+        let col = |j: usize| [
+            hm[0][j] - c[0]*hm[2][j], hm[1][j] - c[1]*hm[2][j], hm[2][j],
+        ];
+        let unit = |v: [f64; 3]| {
+            let n = norm3(v);
+            [v[0]/n, v[1]/n, v[2]/n]
+        };
+        let (h, v) = (col(0), col(1));
+        let d1 = unit([h[0] + v[0], h[1] + v[1], h[2] + v[2]]);
+        let d2 = unit([h[0] - v[0], h[1] - v[1], h[2] - v[2]]);
+        let (h, v) = (unit(h), unit(v));
+        add([h[0]*v[0], h[1]*v[1]], -h[2]*v[2]);
+        add([d1[0]*d2[0], d1[1]*d2[1]], -d1[2]*d2[2]);
+    }
+    let f = solve(ata, atb, 2)?;
+    if f[0] <= 0. || f[1] <= 0. { return None; }
+    Some([1./f[0].sqrt(), 1./f[1].sqrt()])
+}
+
+/// Convert rotation vector into rotation matrix
+fn rodrigues(r: [f64; 3]) -> Mat3 {
+    let theta = norm3(r);
+    if theta < 1e-12 {
+        return [[1., -r[2], r[1]], [r[2], 1., -r[0]], [-r[1], r[0], 1.]];
+    }
+    let k = [r[0]/theta, r[1]/theta, r[2]/theta];
+    let (s, c) = theta.sin_cos();
+    let mut m = [[0.; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            m[i][j] = (1. - c)*k[i]*k[j] + if i == j { c } else { 0. };
+        }
+    }
+    m[0][1] -= s*k[2]; m[1][0] += s*k[2];
+    m[0][2] += s*k[1]; m[2][0] -= s*k[1];
+    m[1][2] -= s*k[0]; m[2][1] += s*k[0];
+    m
+}
+
+/// Convert approximately orthonormal matrix into rotation vector
+fn rotation_vector(m: &Mat3) -> [f64; 3] {
+    // quaternion extraction, the largest component is computed first
+    let tr = m[0][0] + m[1][1] + m[2][2];
+    let q = if tr > m[0][0].max(m[1][1]).max(m[2][2]) {
+        let s = 2.*(1. + tr).sqrt();
+        [
+            s/4., (m[2][1] - m[1][2])/s,
+            (m[0][2] - m[2][0])/s, (m[1][0] - m[0][1])/s,
+        ]
+    } else if m[0][0] >= m[1][1] && m[0][0] >= m[2][2] {
+        let s = 2.*(1. + m[0][0] - m[1][1] - m[2][2]).sqrt();
+        [
+            (m[2][1] - m[1][2])/s, s/4.,
+            (m[0][1] + m[1][0])/s, (m[0][2] + m[2][0])/s,
+        ]
+    } else if m[1][1] >= m[2][2] {
+        let s = 2.*(1. + m[1][1] - m[0][0] - m[2][2]).sqrt();
+        [
+            (m[0][2] - m[2][0])/s, (m[0][1] + m[1][0])/s,
+            s/4., (m[1][2] + m[2][1])/s,
+        ]
+    } else {
+        let s = 2.*(1. + m[2][2] - m[0][0] - m[1][1]).sqrt();
+        [
+            (m[1][0] - m[0][1])/s, (m[0][2] + m[2][0])/s,
+            (m[1][2] + m[2][1])/s, s/4.,
+        ]
+    };
+    let v = [q[1], q[2], q[3]];
+    let sin = norm3(v);
+    if sin < 1e-12 { return [2.*v[0], 2.*v[1], 2.*v[2]]; }
+    let theta = 2.*sin.atan2(q[0]);
+    [v[0]*theta/sin, v[1]*theta/sin, v[2]*theta/sin]
+}
+
+/// Estimate board pose from homography and camera matrix
+fn init_pose(hm: &Mat3, f: [f64; 2], c: [f64; 2]) -> [f64; 6] {
+    // columns of K^-1*H
+    let col = |j: usize| [
+        (hm[0][j] - c[0]*hm[2][j])/f[0], (hm[1][j] - c[1]*hm[2][j])/f[1],
+        hm[2][j],
+    ];
+    let (a1, a2, a3) = (col(0), col(1), col(2));
+    let mut k = 2./(norm3(a1) + norm3(a2));
+    // board must be in front of the camera
+    if a3[2] < 0. { k = -k; }
+    let r1 = [k*a1[0], k*a1[1], k*a1[2]];
+    let r2 = [k*a2[0], k*a2[1], k*a2[2]];
+    let r3 = cross(r1, r2);
+    let m = [
+        [r1[0], r2[0], r3[0]], [r1[1], r2[1], r3[1]], [r1[2], r2[2], r3[2]],
+    ];
+    let r = rotation_vector(&m);
+    [r[0], r[1], r[2], k*a3[0], k*a3[1], k*a3[2]]
+}
+
+fn intrinsics(p: &[f64], res: [u32; 2]) -> Intrinsics {
+    Intrinsics {
+        f: [p[0], p[1]],
+        c: [p[2], p[3]],
+        model: DistortionModel::RadTan,
+        dist: p[4..N_INTR].to_vec(),
+        resolution: Some(res),
+    }
+}
+
+/// Append reprojection errors of the view to `out`
+fn residuals(
+    intr: &Intrinsics, pose: &[f64], object: &[[f64; 2]], image: &[[f64; 2]],
+    out: &mut Vec<f64>,
+) {
+    let r = rodrigues([pose[0], pose[1], pose[2]]);
+    for (o, i) in object.iter().zip(image.iter()) {
+        let x = (0..3)
+            .map(|k| r[k][0]*o[0] + r[k][1]*o[1] + pose[3 + k])
+            .collect::<Vec<f64>>();
+        let p = intr.project([x[0]/x[2], x[1]/x[2]]);
+        out.push(p[0] - i[0]);
+        out.push(p[1] - i[1]);
+    }
+}
+
+fn cost(
+    params: &[f64], object: &[[f64; 2]], views: &[Vec<[f64; 2]>],
+    res: [u32; 2],
+) -> f64 {
+    let intr = intrinsics(params, res);
+    let mut r = Vec::new();
+    for (v, image) in views.iter().enumerate() {
+        let pose = &params[N_INTR + N_POSE*v..N_INTR + N_POSE*(v + 1)];
+        residuals(&intr, pose, object, image, &mut r);
+    }
+    r.iter().map(|v| v*v).sum()
+}
+
+/// Minimize reprojection error with the Levenberg-Marquardt algorithm,
+/// Jacobian is computed numerically view by view
+fn refine(
+    params: &mut Vec<f64>, object: &[[f64; 2]], views: &[Vec<[f64; 2]>],
+    res: [u32; 2],
+) {
+    let n = params.len();
+    let mut current = cost(params, object, views, res);
+    let mut lambda = 1e-3;
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut jtj = vec![0.; n*n];
+        let mut jtr = vec![0.; n];
+        let intr = intrinsics(params, res);
+        for (v, image) in views.iter().enumerate() {
+            let idx: Vec<usize> = (0..N_INTR)
+                .chain(N_INTR + N_POSE*v..N_INTR + N_POSE*(v + 1))
+                .collect();
+            let pose = |p: &[f64]| {
+                let mut out = [0.; N_POSE];
+                out.copy_from_slice(&p[N_INTR + N_POSE*v..][..N_POSE]);
+                out
+            };
+            let mut r0 = Vec::new();
+            residuals(&intr, &pose(params), object, image, &mut r0);
+
+            let cols: Vec<Vec<f64>> = idx.iter().map(|&j| {
+                let mut p = params.clone();
+                let h = 1e-6*p[j].abs().max(1.);
+                p[j] += h;
+                let mut r = Vec::new();
+                let intr = intrinsics(&p, res);
+                residuals(&intr, &pose(&p), object, image, &mut r);
+                r.iter().zip(r0.iter()).map(|(a, b)| (a - b)/h).collect()
+            }).collect();
+
+            for (a, &ja) in idx.iter().enumerate() {
+                for (b, &jb) in idx.iter().enumerate() {
+                    let s: f64 = cols[a].iter().zip(cols[b].iter())
+                        .map(|(x, y)| x*y).sum();
+                    jtj[ja*n + jb] += s;
+                }
+                jtr[ja] += cols[a].iter().zip(r0.iter())
+                    .map(|(x, y)| x*y).sum::<f64>();
+            }
+        }
+
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut a = jtj.clone();
+            for i in 0..n { a[i*n + i] += lambda*(jtj[i*n + i] + 1e-9); }
+            let b = jtr.iter().map(|v| -v).collect();
+            if let Some(delta) = solve(a, b, n) {
+                let candidate: Vec<f64> = params.iter().zip(delta.iter())
+                    .map(|(p, d)| p + d).collect();
+                let new = cost(&candidate, object, views, res);
+                if new < current {
+                    let gain = (current - new)/current;
+                    *params = candidate;
+                    current = new;
+                    lambda = (lambda/10.).max(1e-12);
+                    improved = gain > 1e-12;
+                    break;
+                }
+            }
+            lambda *= 10.;
+        }
+        if !improved { break; }
+    }
+}
+
+/// Calibration result
+pub(crate) struct Calibration {
+    pub intrinsics: Intrinsics,
+    /// RMS reprojection error of each view in pixels
+    pub view_errors: Vec<f64>,
+    /// RMS reprojection error over all corners in pixels
+    pub error: f64,
+}
+
+/// Compute intrinsics from board corners detected in several views,
+/// `object` contains positions of the corners on the board
+pub(crate) fn calibrate(
+    object: &[[f64; 2]], views: &[Vec<[f64; 2]>], res: [u32; 2],
+) -> Result<Calibration, Box<Error>> {
+    if views.len() < MIN_VIEWS {
+        Err(format!(
+            "at least {} views with detected board are required, got {}",
+            MIN_VIEWS, views.len(),
+        ))?
+    }
+    let homographies: Vec<Mat3> = views.iter()
+        .map(|image| homography(object, image))
+        .collect();
+    // pixel centers have integer coordinates
+    let c = [(res[0] as f64 - 1.)/2., (res[1] as f64 - 1.)/2.];
+    let f = init_focal(&homographies, c)
+        .ok_or("failed to estimate initial focal length")?;
+
+    let mut params = vec![f[0], f[1], c[0], c[1], 0., 0., 0., 0., 0.];
+    for hm in homographies.iter() {
+        params.extend_from_slice(&init_pose(hm, f, c));
+    }
+    refine(&mut params, object, views, res);
+
+    let intr = intrinsics(&params, res);
+    let mut total = 0.;
+    let view_errors = views.iter().enumerate().map(|(v, image)| {
+        let mut r = Vec::new();
+        let pose = &params[N_INTR + N_POSE*v..N_INTR + N_POSE*(v + 1)];
+        residuals(&intr, pose, object, image, &mut r);
+        let sum: f64 = r.iter().map(|v| v*v).sum();
+        total += sum;
+        (sum/(object.len() as f64)).sqrt()
+    }).collect::<Vec<f64>>();
+    let error = (total/((object.len()*views.len()) as f64)).sqrt();
+    if !error.is_finite() { Err("calibration did not converge")? }
+
+    Ok(Calibration { intrinsics: intr, view_errors, error })
+}
+
+/// Load 8-bit PNG image, color images are converted to greyscale
+fn load_png(path: &Path) -> Result<GreyImage, Box<Error>> {
+    let decoder = png::Decoder::new(File::open(path)?);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+
+    // default decoder transformations produce 8-bit samples
+    let (w, h) = (info.width as usize, info.height as usize);
+    let n = info.color_type.samples();
+    let data = buf.chunks(info.line_size)
+        .flat_map(|row| row[..w*n].chunks(n))
+        .map(|p| match n {
+            1 | 2 => p[0] as f32,
+            _ => (p[0] as f32 + 2.*(p[1] as f32) + p[2] as f32)/4.,
+        })
+        .collect();
+    Ok(GreyImage::new(data, w, h))
+}
+
+/// Load binary PGM image with 8 or 16 bits per sample
+fn load_pgm(path: &Path) -> Result<GreyImage, Box<Error>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    // header consists of magic number, width, height and maximum value
+    // separated by whitespace, comments start with `#`
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        match data.get(pos) {
+            None => Err("unexpected end of PGM header")?,
+            Some(b'#') => {
+                while data.get(pos).map_or(false, |&c| c != b'\n') { pos += 1; }
+            },
+            Some(c) if c.is_ascii_whitespace() => pos += 1,
+            Some(_) => {
+                let start = pos;
+                let is_field = |c: &u8| !c.is_ascii_whitespace();
+                while data.get(pos).map_or(false, is_field) { pos += 1; }
+                let field = String::from_utf8_lossy(&data[start..pos]);
+                fields.push(field.into_owned());
+            },
+        }
+    }
+    // single whitespace character separates header from the data
+    pos += 1;
+
+    if fields[0] != "P5" { Err("only binary PGM images are supported")? }
+    let w: usize = fields[1].parse()?;
+    let h: usize = fields[2].parse()?;
+    let max: u32 = fields[3].parse()?;
+    if max == 0 || max > 65535 { Err("invalid PGM maximum value")? }
+
+    let bytes = if max > 255 { 2 } else { 1 };
+    let pixels = data.get(pos..pos + w*h*bytes)
+        .ok_or("PGM image data is truncated")?;
+    let k = 255./(max as f32);
+    let data = pixels.chunks(bytes)
+        .map(|c| match bytes {
+            1 => c[0] as f32*k,
+            _ => ((c[0] as u32) << 8 | c[1] as u32) as f32*k,
+        })
+        .collect();
+    Ok(GreyImage::new(data, w, h))
+}
+
+/// Lowercase file extension
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map_or(String::new(), |e| e.to_lowercase())
+}
+
+/// Check if file is an image used for calibration, raw frames saved in
+/// the calibration capture mode are skipped
+fn is_image(path: &Path) -> bool {
+    let raw = path.file_stem()
+        .and_then(|s| s.to_str())
+        .map_or(false, |s| s.ends_with("_raw"));
+    match extension(path).as_str() {
+        "png" | "pgm" => !raw,
+        _ => false,
+    }
+}
+
+/// Format matrix in the `cv::FileStorage` YAML format
+fn opencv_matrix(name: &str, rows: usize, cols: usize, data: &[f64]) -> String {
+    let data = data.iter()
+        .map(|v| format!("{:e}", v))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!(
+        "{}: !!opencv-matrix\n   rows: {}\n   cols: {}\n   dt: d\n   \
+        data: [ {} ]\n",
+        name, rows, cols, data,
+    )
+}
+
+/// Calibrate camera using images from the directory and write result
+/// into the OpenCV calibration file
+pub(crate) fn run(
+    dir: &str, board: [usize; 2], square: f64, output: &str,
+) -> Result<(), Box<Error>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|p| is_image(p));
+    paths.sort();
+    if paths.is_empty() { Err(format!("no PNG or PGM images in {}", dir))? }
+
+    let mut names = Vec::new();
+    let mut views = Vec::new();
+    let mut res: Option<[u32; 2]> = None;
+    for path in paths.iter() {
+        let name = path.display().to_string();
+        let img = match extension(path).as_str() {
+            "pgm" => load_pgm(path),
+            _ => load_png(path),
+        }.map_err(|e| format!("{}: {}", name, e))?;
+        let img_res = [img.width as u32, img.height as u32];
+        match res {
+            Some(r) if r != img_res => Err(format!(
+                "{}: image size {}x{} differs from {}x{}",
+                name, img_res[0], img_res[1], r[0], r[1],
+            ))?,
+            _ => res = Some(img_res),
+        }
+        match chessboard::detect(&img, board) {
+            Some(corners) => {
+                eprintln!("{}: board detected", name);
+                names.push(name);
+                views.push(corners.iter()
+                    .map(|p| [p[0] as f64, p[1] as f64])
+                    .collect());
+            },
+            None => eprintln!("{}: board not detected, skipped", name),
+        }
+    }
+    let res = res.unwrap();
+
+    let object: Vec<[f64; 2]> = (0..board[1])
+        .flat_map(|r| (0..board[0]).map(move |c| [c as f64, r as f64]))
+        .map(|p| [p[0]*square, p[1]*square])
+        .collect();
+    let calib = calibrate(&object, &views, res)?;
+    let intr = &calib.intrinsics;
+
+    println!("Reprojection errors (RMS, pixels):");
+    for (name, err) in names.iter().zip(calib.view_errors.iter()) {
+        println!("    {}: {:.4}", name, err);
+    }
+    println!("Views: {}", views.len());
+    println!("Focal length: {:.3} {:.3}", intr.f[0], intr.f[1]);
+    println!("Principal point: {:.3} {:.3}", intr.c[0], intr.c[1]);
+    println!("Distortion (k1, k2, p1, p2, k3): {:.6?}", intr.dist);
+    println!("Reprojection error (RMS): {:.4}", calib.error);
+
+    let camera_matrix = [
+        intr.f[0], 0., intr.c[0], 0., intr.f[1], intr.c[1], 0., 0., 1.,
+    ];
+    let mut file = BufWriter::new(File::create(output)?);
+    writeln!(file, "%YAML:1.0\n---")?;
+    writeln!(file, "nr_of_frames: {}", views.len())?;
+    writeln!(file, "image_width: {}\nimage_height: {}", res[0], res[1])?;
+    writeln!(file, "board_width: {}\nboard_height: {}", board[0], board[1])?;
+    writeln!(file, "square_size: {:e}", square)?;
+    let matrices = [
+        opencv_matrix("camera_matrix", 3, 3, &camera_matrix),
+        opencv_matrix("distortion_coefficients", 5, 1, &intr.dist),
+    ];
+    for m in matrices.iter() { file.write_all(m.as_bytes())?; }
+    writeln!(file, "avg_reprojection_error: {:e}", calib.error)?;
+    let errors = opencv_matrix(
+        "per_view_reprojection_errors", views.len(), 1, &calib.view_errors,
+    );
+    file.write_all(errors.as_bytes())?;
+    file.flush()?;
+    println!("Saved: {}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_intrinsics() {
+        let res = [640, 480];
+        let truth = Intrinsics {
+            f: [800., 780.],
+            c: [322., 238.],
+            model: DistortionModel::RadTan,
+            dist: vec![-0.2, 0., 0., 0., 0.],
+            resolution: Some(res),
+        };
+        let (board, square) = ([9, 6], 0.025);
+        let object: Vec<[f64; 2]> = (0..board[1])
+            .flat_map(|r| (0..board[0]).map(move |c| [c as f64, r as f64]))
+            .map(|p| [p[0]*square, p[1]*square])
+            .collect();
+
+        // board centered in front of the camera and tilted in several ways
+        let rotations = [
+            [0.3, 0., 0.], [-0.3, 0., 0.1], [0., 0.35, 0.],
+            [0., -0.35, -0.1], [0.25, 0.25, 0.2], [-0.2, 0.3, -0.2],
+        ];
+        let views: Vec<Vec<[f64; 2]>> = rotations.iter().map(|&rv| {
+            let r = rodrigues(rv);
+            let center = [0.1, 0.0625];
+            let t = (0..3).map(|k| {
+                let z = if k == 2 { 0.45 } else { 0. };
+                z - r[k][0]*center[0] - r[k][1]*center[1]
+            }).collect::<Vec<f64>>();
+            object.iter().map(|o| {
+                let x = (0..3)
+                    .map(|k| r[k][0]*o[0] + r[k][1]*o[1] + t[k])
+                    .collect::<Vec<f64>>();
+                truth.project([x[0]/x[2], x[1]/x[2]])
+            }).collect()
+        }).collect();
+
+        let calib = calibrate(&object, &views, res).unwrap();
+        let intr = &calib.intrinsics;
+        assert!(calib.error < 1e-3, "reprojection error {}", calib.error);
+        for i in 0..2 {
+            assert!((intr.f[i] - truth.f[i]).abs() < 0.5, "f {:?}", intr.f);
+            assert!((intr.c[i] - truth.c[i]).abs() < 0.5, "c {:?}", intr.c);
+        }
+        assert!((intr.dist[0] - truth.dist[0]).abs() < 0.01,
+            "k1 {}", intr.dist[0]);
+    }
+
+    #[test]
+    fn requires_several_views() {
+        let object = vec![[0., 0.], [1., 0.], [0., 1.], [1., 1.]];
+        let views = vec![object.clone(); MIN_VIEWS - 1];
+        assert!(calibrate(&object, &views, [640, 480]).is_err());
+    }
+}
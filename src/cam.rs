@@ -1,11 +1,16 @@
-use std::{thread, error, str};
+use std::{thread, error, mem, str};
 
 use rscam::{Camera, Config, ResolutionInfo, IntervalInfo};
 
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use demosaic::demosaic;
+use defects::correct;
+use demosaic::{demosaic, Conversion};
+use flat;
+use sync::Sink;
+use v4l2;
+use yuv::Encoding;
 
 const BP: [u8; 3] = [0, 0, 255];
 const MAX_FPS: u32 = 60;
@@ -17,12 +22,45 @@ pub struct Cam {
     format: [u8; 4],
     frame_size: usize,
     pixels: usize,
+    /// Y'CbCr encoding reported by the driver
+    yuv: Encoding,
 }
 
+#[derive(Clone)]
 pub struct FrameBuf {
     pub buf: Vec<[u8; 3]>,
+    /// Undemosaiced frame data as received from the camera
+    pub raw: Vec<u8>,
     pub ts: u64,
     pub hist: [u32; 256],
+    /// Number of frame drops detected from the timestamp gaps
+    pub drops: u64,
+    /// Number of frames of unexpected size received from the camera
+    pub bad_frames: u64,
+    /// `buf` and `hist` correspond to `raw`, conversion is skipped by the
+    /// worker while frames are converted on GPU only
+    pub converted: bool,
+    /// Fixed-point flat-field gains of the raw samples or of the converted
+    /// RGB channels depending on the format, see `flat::corrects_raw`
+    pub flat: Option<Arc<Vec<u16>>>,
+}
+
+impl FrameBuf {
+    /// Convert raw data into RGB and update histogram if it was not done
+    /// by the worker
+    pub fn convert(
+        &mut self, format: [u8; 4], res: [u32; 2], conv: &Conversion,
+    ) {
+        if self.converted { return; }
+        demosaic(format, res, &mut self.buf, &self.raw, conv);
+        if let Some(ref gains) = self.flat {
+            if !flat::corrects_raw(format) {
+                flat::apply_rgb(&mut self.buf, gains, res[1] as usize);
+            }
+        }
+        self.hist = calc_hist(&self.buf);
+        self.converted = true;
+    }
 }
 
 impl Cam {
@@ -86,45 +124,125 @@ impl Cam {
             ..Default::default()
         }).map_err(Box::new)?;
 
+        let is_yuyv = &format == b"YUYV";
+        let yuv = match v4l2::color_info(dev) {
+            Ok(c) => {
+                if is_yuyv && !Encoding::reports_range(c.quantization) {
+                    eprintln!("Driver does not report YUV range, assuming \
+                        full range, use --yuv-range to override");
+                }
+                Encoding::from_v4l2(c.colorspace, c.ycbcr_enc, c.quantization)
+            },
+            Err(err) => {
+                eprintln!("Failed to get colorspace: {}", err);
+                Encoding::default()
+            },
+        };
+        if is_yuyv {
+            eprintln!("YUV encoding: {}", yuv.name());
+        }
+
         let pixels = (resolution.0*resolution.1) as usize;
-        Ok(Cam {camera, resolution, interval, format, frame_size, pixels})
+        Ok(Cam {
+            camera, resolution, interval, format, frame_size, pixels, yuv,
+        })
     }
 
-    pub fn run_worker(self, pause: Arc<AtomicBool>)
-        -> Arc<Mutex<FrameBuf>>
-    {
-        let cam_mutex = Arc::new(Mutex::new(FrameBuf {
+    /// Start capture thread, copies of captured frames are additionally
+    /// sent to the synchronization sink, raw samples listed in `correction`
+    /// are replaced and flat-field `gains` are applied before demosaicing,
+    /// conversion into RGB is skipped while `cpu_convert` is false. Frames
+    /// are processed in a separate buffer which is swapped with the shared
+    /// one, so readers are not blocked by processing and copying.
+    pub fn run_worker(
+        self, pause: Arc<AtomicBool>, conversion: Arc<Mutex<Conversion>>,
+        sink: Sink, correction: Arc<Mutex<Vec<u32>>>,
+        gains: Arc<Mutex<Option<Arc<Vec<u16>>>>>, cpu_convert: Arc<AtomicBool>,
+    ) -> Arc<Mutex<FrameBuf>> {
+        let mut fb = FrameBuf {
             buf: vec![BP; self.pixels],
+            raw: vec![0; self.frame_size],
             ts: 0,
             hist: [0; 256],
-        }));
+            drops: 0,
+            bad_frames: 0,
+            converted: true,
+            flat: None,
+        };
+        let cam_mutex = Arc::new(Mutex::new(fb.clone()));
         let mutex = cam_mutex.clone();
 
         thread::spawn(move|| {
             let mut prev = 0u64;
+            let mut bad_frames = 0u64;
+            let (width, height) = (
+                self.resolution.0 as usize, self.resolution.1 as usize,
+            );
+            let step = if self.is_bayer() { 2 } else { 1 };
+            let raw_samples = self.is_bayer() || self.is_grey();
 
             loop {
                 let frame = self.camera.capture()
                     .expect("failed to capture camera frame");
 
-                if pause.load(Ordering::Relaxed) { continue; }
+                // frames skipped during pause are not counted as drops
+                if pause.load(Ordering::Relaxed) {
+                    prev = 0;
+                    continue;
+                }
 
                 let t = frame.get_timestamp();
 
-                if is_drop(t, prev, self.interval) {
-                    //println!("Frame drop");
-                }
-
-                let mut guard = mutex.lock().unwrap();
+                // gains are replaced by the main thread, frames carry them
+                // for conversion after display and synchronization
+                fb.flat = gains.lock().unwrap().clone();
                 if frame.len() == self.frame_size {
-                    demosaic(&self, &mut guard.buf, &frame);
+                    fb.raw.copy_from_slice(&frame);
+                    // multi-byte pixels of other formats can not be
+                    // corrected sample by sample
+                    if raw_samples {
+                        let defects = correction.lock().unwrap();
+                        if !defects.is_empty() {
+                            correct(&mut fb.raw, width, step, &defects);
+                        }
+                    }
+                    let conv = *conversion.lock().unwrap();
+                    if let Some(ref gains) = fb.flat {
+                        if flat::corrects_raw(self.format) {
+                            let black = conv.isp.black_level.round() as i32;
+                            flat::apply(&mut fb.raw, gains, height, black);
+                        }
+                    }
+                    fb.converted = false;
+                    if cpu_convert.load(Ordering::Relaxed) {
+                        fb.convert(self.format, self.get_resolution(), &conv);
+                    }
                 } else {
-                    println!("Bad frame len: {}", frame.len());
-                    guard.buf.iter_mut().for_each(|p| *p = BP);
+                    bad_frames += 1;
+                    fb.buf.iter_mut().for_each(|p| *p = BP);
+                    fb.raw.iter_mut().for_each(|p| *p = 0);
+                    fb.hist = calc_hist(&fb.buf);
+                    fb.converted = true;
+                };
+                fb.ts = t;
+                fb.bad_frames = bad_frames;
+                let copy = if sink.wants_frames() {
+                    Some(fb.clone())
+                } else {
+                    None
                 };
 
-                guard.ts = t;
-                guard.hist = calc_hist(&guard.buf);
+                {
+                    let mut guard = mutex.lock().unwrap();
+                    fb.drops = guard.drops;
+                    if prev != 0 && is_drop(t, prev, self.interval) {
+                        fb.drops += 1;
+                    }
+                    mem::swap(&mut *guard, &mut fb);
+                }
+                if let Some(copy) = copy {
+                    sink.send(copy);
+                }
                 prev = t;
             }
         });
@@ -144,22 +262,22 @@ impl Cam {
         self.format
     }
 
-    pub fn get_pixels(&self) -> usize {
-        self.pixels
-    }
-
-    pub fn get_frame_size(&self) -> usize {
-        self.frame_size
+    /// Y'CbCr encoding of YUV frames reported by the driver
+    pub fn get_yuv_encoding(&self) -> Encoding {
+        self.yuv
     }
 
     pub fn is_grey(&self) -> bool {
         &self.get_format() == b"GREY"
     }
+
+    pub fn is_bayer(&self) -> bool {
+        &self.get_format() == b"RGGB"
+    }
 }
 
 fn is_drop(t: u64, prev: u64, interval: (u32, u32)) -> bool {
     let dt = t - prev;
-    //println!("{:?} {}", dt, (interval.0 as u64)*1_000_000/(interval.1 as u64));
     dt > u64::from(interval.0)*1_100_000/u64::from(interval.1)
 }
 
@@ -0,0 +1,321 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{ColorVertex, EngineState};
+use cam::FrameBuf;
+use chessboard::{self, GreyImage};
+use save;
+
+/// Number of coverage cells along X and Y axes
+const COVERAGE_CELLS: [usize; 2] = [16, 12];
+const COVERAGE_COLOR: [f32; 4] = [0., 1., 0., 0.25];
+/// Maximum corner displacement in pixels between consecutive detections
+/// for the board to be considered steady
+const STEADY_DIST: f32 = 2.;
+/// Minimal distance between outlines of automatically accepted views
+/// relative to the image diagonal
+const NOVELTY_DIST: f32 = 0.05;
+/// Half-size of corner crosses in screen pixels
+const CORNER_SIZE: f32 = 5.;
+/// Colors of the board rows in the corner overlay
+const ROW_COLORS: [[f32; 4]; 6] = [
+    [1., 0., 0., 1.], [1., 0.5, 0., 1.], [1., 1., 0., 1.],
+    [0., 1., 0., 1.], [0., 0.5, 1., 1.], [1., 0., 1., 1.],
+];
+
+/// Result of the checkerboard detection together with the processed frame
+struct Detection {
+    ts: u64,
+    /// Detected corners sorted in rows, `None` if board was not found
+    corners: Option<Vec<[f32; 2]>>,
+    raw: Vec<u8>,
+    buf: Vec<[u8; 3]>,
+}
+
+/// Calibration data collection state
+pub(crate) struct Capture {
+    /// Detect checkerboard and show overlay
+    pub on: bool,
+    /// Automatically accept steady views which differ from the already
+    /// accepted ones
+    pub auto: bool,
+    pub pattern: [usize; 2],
+    dir: PathBuf,
+    resolution: [u32; 2],
+    is_grey: bool,
+    /// Frames hold raw Bayer or greyscale samples
+    raw_samples: bool,
+    cam_mutex: Arc<Mutex<FrameBuf>>,
+    /// Latest result of the detection thread
+    result: Arc<Mutex<Option<Detection>>>,
+    /// Flag of the running detection thread, cleared to stop it
+    active: Option<Arc<AtomicBool>>,
+    /// Latest detection result
+    detection: Option<Detection>,
+    steady: bool,
+    /// Outlines of accepted views
+    views: Vec<[[f32; 2]; 4]>,
+    coverage: Vec<bool>,
+}
+
+/// Outer corners of the detected board
+fn outline(corners: &[[f32; 2]], pattern: [usize; 2]) -> [[f32; 2]; 4] {
+    let (n, cols) = (corners.len(), pattern[0]);
+    [corners[0], corners[cols - 1], corners[n - 1], corners[n - cols]]
+}
+
+/// Check if point lies inside convex quadrilateral
+fn inside(quad: &[[f32; 2]; 4], p: [f32; 2]) -> bool {
+    let mut sign = 0.;
+    for i in 0..4 {
+        let (a, b) = (quad[i], quad[(i + 1) % 4]);
+        let cross = (b[0] - a[0])*(p[1] - a[1]) - (b[1] - a[1])*(p[0] - a[0]);
+        if cross*sign < 0. { return false; }
+        if cross != 0. { sign = cross; }
+    }
+    true
+}
+
+/// Mean distance from corners of one outline to the nearest corners of
+/// another, does not depend on the corner order
+fn outline_dist(a: &[[f32; 2]; 4], b: &[[f32; 2]; 4]) -> f32 {
+    a.iter().map(|p| {
+        b.iter()
+            .map(|q| ((p[0] - q[0]).powi(2) + (p[1] - q[1]).powi(2)).sqrt())
+            .fold(::std::f32::MAX, f32::min)
+    }).sum::<f32>()/4.
+}
+
+impl Capture {
+    /// Create capture state, detection thread runs only while capture is on
+    pub fn new(
+        pattern: [usize; 2], dir: &str, resolution: [u32; 2], is_grey: bool,
+        is_bayer: bool, cam_mutex: Arc<Mutex<FrameBuf>>,
+    ) -> Self {
+        Capture {
+            on: false,
+            auto: false,
+            pattern,
+            dir: PathBuf::from(dir),
+            resolution,
+            is_grey,
+            raw_samples: is_bayer || is_grey,
+            cam_mutex,
+            result: Arc::new(Mutex::new(None)),
+            active: None,
+            detection: None,
+            steady: false,
+            views: Vec::new(),
+            coverage: vec![false; COVERAGE_CELLS[0]*COVERAGE_CELLS[1]],
+        }
+    }
+
+    /// Start or stop detection thread which processes the latest camera
+    /// frame
+    pub fn set_on(&mut self, on: bool) {
+        self.on = on;
+        if let Some(active) = self.active.take() {
+            active.store(false, Ordering::SeqCst);
+        }
+        *self.result.lock().unwrap() = None;
+        self.detection = None;
+        self.steady = false;
+        if on {
+            self.active = Some(self.start());
+        }
+    }
+
+    /// Spawn detection thread, it exits once the returned flag is cleared
+    fn start(&self) -> Arc<AtomicBool> {
+        let active = Arc::new(AtomicBool::new(true));
+        let thread_active = active.clone();
+        let result = self.result.clone();
+        let cam_mutex = self.cam_mutex.clone();
+        let pattern = self.pattern;
+        let (w, h) = (self.resolution[0] as usize, self.resolution[1] as usize);
+
+        thread::spawn(move || {
+            let mut prev = 0u64;
+            while thread_active.load(Ordering::SeqCst) {
+                let (ts, raw, buf) = {
+                    let guard = cam_mutex.lock().unwrap();
+                    // frame converted only on GPU is converted on CPU by
+                    // the main loop while capture is on
+                    if guard.ts == prev || !guard.converted {
+                        drop(guard);
+                        thread::sleep(Duration::from_millis(5));
+                        continue;
+                    }
+                    (guard.ts, guard.raw.clone(), guard.buf.clone())
+                };
+                prev = ts;
+
+                let img = GreyImage::from_rgb(&buf, w, h);
+                let corners = chessboard::detect(&img, pattern);
+                let mut result = result.lock().unwrap();
+                // result of a stopped thread belongs to the previous session
+                if thread_active.load(Ordering::SeqCst) {
+                    *result = Some(Detection { ts, corners, raw, buf });
+                }
+            }
+        });
+        active
+    }
+
+    /// Number of accepted views
+    pub fn views(&self) -> usize {
+        self.views.len()
+    }
+
+    /// Fraction of the image area covered by the accepted views
+    pub fn coverage(&self) -> f32 {
+        let n = self.coverage.iter().filter(|&&c| c).count();
+        (n as f32)/(self.coverage.len() as f32)
+    }
+
+    /// Fetch new detection result, returns `true` if the current view
+    /// should be accepted automatically
+    pub fn update(&mut self) -> bool {
+        let det = match self.result.lock().unwrap().take() {
+            Some(det) => det,
+            None => return false,
+        };
+
+        self.steady = match (self.corners(), &det.corners) {
+            (Some(prev), &Some(ref cur)) => {
+                prev.iter().zip(cur.iter()).all(|(p, c)| {
+                    (p[0] - c[0]).abs() < STEADY_DIST
+                        && (p[1] - c[1]).abs() < STEADY_DIST
+                })
+            },
+            _ => false,
+        };
+        self.detection = Some(det);
+
+        if !self.auto || !self.steady { return false; }
+        let cur = match self.corners() {
+            Some(corners) => outline(corners, self.pattern),
+            None => return false,
+        };
+        let res = self.resolution;
+        let diag = ((res[0]*res[0] + res[1]*res[1]) as f32).sqrt();
+        self.views.iter().all(|v| outline_dist(v, &cur) > NOVELTY_DIST*diag)
+    }
+
+    /// Save frame of the latest successful detection into the dataset
+    /// directory, raw Bayer and greyscale samples are saved as a PGM image
+    /// alongside with the demosaiced PNG image
+    pub fn accept(&mut self) -> Result<String, Box<Error>> {
+        let det = match self.detection {
+            Some(ref det) if det.corners.is_some() => det,
+            _ => Err("checkerboard not detected, frame was not saved")?,
+        };
+        fs::create_dir_all(&self.dir)?;
+        let path = |suffix: &str| {
+            let path = self.dir.join(format!("{}{}", det.ts, suffix));
+            path.to_string_lossy().into_owned()
+        };
+
+        let png_path = path(".png");
+        save::png(&png_path, &det.buf, self.resolution, self.is_grey)?;
+        if self.raw_samples {
+            save::pgm(&path("_raw.pgm"), &det.raw, self.resolution[1])?;
+        }
+
+        let quad = outline(det.corners.as_ref().unwrap(), self.pattern);
+        let [nx, ny] = COVERAGE_CELLS;
+        let res = self.resolution;
+        for (i, c) in self.coverage.iter_mut().enumerate() {
+            let x = ((i % nx) as f32 + 0.5)*(res[0] as f32)/(nx as f32);
+            let y = ((i / nx) as f32 + 0.5)*(res[1] as f32)/(ny as f32);
+            *c |= inside(&quad, [x, y]);
+        }
+        self.views.push(quad);
+        Ok(format!("view {} saved: {}", self.views.len(), png_path))
+    }
+
+    /// Returns `Some(true)` if board is detected in the latest processed
+    /// frame and `None` if no frames were processed yet
+    pub fn found(&self) -> Option<bool> {
+        self.detection.as_ref().map(|d| d.corners.is_some())
+    }
+
+    /// Corners detected in the latest processed frame
+    pub fn corners(&self) -> Option<&[[f32; 2]]> {
+        match self.detection {
+            Some(Detection { corners: Some(ref c), .. }) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+/// Build crosses over the detected corners connected by lines in the
+/// detection order, capture always uses the first camera
+pub(crate) fn corners(state: &EngineState) -> Vec<ColorVertex> {
+    let mut out = Vec::new();
+    let corners = match state.capture.corners() {
+        Some(corners) => corners,
+        None => return out,
+    };
+    let tile = &state.tiles[0];
+    let pc = &tile.push_consts;
+    let res = tile.resolution;
+    let dims = tile.dims;
+    let s = [2.*CORNER_SIZE/dims[0], 2.*CORNER_SIZE/dims[1]];
+    let cols = state.capture.pattern[0];
+
+    // pixel centers have integer coordinates
+    let points: Vec<[f32; 2]> = corners.iter()
+        .map(|p| pc.to_ndc([p[0] + 0.5, p[1] + 0.5], res))
+        .collect();
+    for (i, &p) in points.iter().enumerate() {
+        let color = ROW_COLORS[(i/cols) % ROW_COLORS.len()];
+        let mut push = |a: [f32; 2], b: [f32; 2]| {
+            out.push(ColorVertex { position: a, color });
+            out.push(ColorVertex { position: b, color });
+        };
+        push([p[0] - s[0], p[1] - s[1]], [p[0] + s[0], p[1] + s[1]]);
+        push([p[0] - s[0], p[1] + s[1]], [p[0] + s[0], p[1] - s[1]]);
+        if let Some(&next) = points.get(i + 1) {
+            push(p, next);
+        }
+    }
+    out
+}
+
+/// Build triangles over the image cells covered by the accepted views
+pub(crate) fn coverage(state: &EngineState) -> Vec<ColorVertex> {
+    let mut out = Vec::new();
+    let tile = &state.tiles[0];
+    let pc = &tile.push_consts;
+    let res = tile.resolution;
+    let [nx, ny] = COVERAGE_CELLS;
+    let (cw, ch) = ((res[0] as f32)/(nx as f32), (res[1] as f32)/(ny as f32));
+    for (i, _) in state.capture.coverage.iter().enumerate().filter(|c| *c.1) {
+        let (x, y) = ((i % nx) as f32*cw, (i / nx) as f32*ch);
+        let a = pc.to_ndc([x, y], res);
+        let b = pc.to_ndc([x + cw, y], res);
+        let c = pc.to_ndc([x + cw, y + ch], res);
+        let d = pc.to_ndc([x, y + ch], res);
+        for &position in [a, b, c, a, c, d].iter() {
+            out.push(ColorVertex { position, color: COVERAGE_COLOR });
+        }
+    }
+    out
+}
+
+/// Parse checkerboard size in the `<columns>x<rows>` format
+pub(crate) fn parse_board(s: &str) -> Result<[usize; 2], &'static str> {
+    let err = "board size must be in the <columns>x<rows> format with \
+        at least 2 inner corners along each axis";
+    let mut iter = s.split('x').map(|v| v.trim().parse::<usize>());
+    match (iter.next(), iter.next(), iter.next()) {
+        (Some(Ok(c)), Some(Ok(r)), None) if c >= 2 && r >= 2 => Ok([c, r]),
+        _ => Err(err),
+    }
+}
@@ -0,0 +1,500 @@
+//! Checkerboard detection
+//!
+//! Corner candidates are found as saddle points of the smoothed image
+//! (negative determinant of the Hessian) on a downscaled copy of the image.
+//! Starting from the strongest candidates the grid is grown by predicting
+//! positions of neighbouring corners, each accepted corner must look like
+//! an X-junction of four alternating squares. Found corners are refined
+//! with sub-pixel precision on the full resolution image.
+use std::collections::{HashMap, VecDeque};
+
+/// Maximum width of the image used for the candidate search
+const SEARCH_WIDTH: usize = 800;
+/// Smoothing applied before the saddle point search
+const SIGMA: f32 = 1.5;
+/// Maximum number of candidates per expected corner
+const CANDIDATES_PER_CORNER: usize = 4;
+/// Minimal response of candidates relative to the typical corner response
+const MIN_RESPONSE: f32 = 0.05;
+/// Number of seed corners tried before giving up
+const MAX_SEEDS: usize = 20;
+/// Search radius for predicted corners relative to the grid spacing
+const MATCH_RADIUS: f32 = 0.35;
+/// Minimal intensity difference between dark and light squares
+const MIN_CONTRAST: f32 = 12.;
+
+/// Greyscale image with floating point pixels
+pub struct GreyImage {
+    pub data: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl GreyImage {
+    pub fn new(data: Vec<f32>, width: usize, height: usize) -> Self {
+        assert_eq!(data.len(), width*height);
+        GreyImage { data, width, height }
+    }
+
+    /// Create greyscale image from RGB pixels
+    pub fn from_rgb(buf: &[[u8; 3]], width: usize, height: usize) -> Self {
+        let data = buf.iter()
+            .map(|p| (p[0] as f32 + 2.*(p[1] as f32) + p[2] as f32)/4.)
+            .collect();
+        Self::new(data, width, height)
+    }
+
+    #[inline(always)]
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y*self.width + x]
+    }
+
+    /// Bilinearly interpolated pixel value, coordinates are clamped to the
+    /// image borders
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = x.max(0.).min((self.width - 1) as f32);
+        let y = y.max(0.).min((self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let top = self.get(x0, y0)*(1. - fx) + self.get(x1, y0)*fx;
+        let bottom = self.get(x0, y1)*(1. - fx) + self.get(x1, y1)*fx;
+        top*(1. - fy) + bottom*fy
+    }
+
+    /// Downscale image by an integer factor using box filter
+    fn downscale(&self, k: usize) -> Self {
+        if k == 1 {
+            return GreyImage::new(self.data.clone(), self.width, self.height);
+        }
+        let (w, h) = (self.width/k, self.height/k);
+        let mut data = vec![0.; w*h];
+        let norm = 1./((k*k) as f32);
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = 0.;
+                for dy in 0..k {
+                    for dx in 0..k {
+                        sum += self.get(k*x + dx, k*y + dy);
+                    }
+                }
+                data[y*w + x] = sum*norm;
+            }
+        }
+        GreyImage::new(data, w, h)
+    }
+
+    /// Separable gaussian blur
+    fn blur(&self, sigma: f32) -> Self {
+        let r = (3.*sigma).ceil() as isize;
+        let kernel: Vec<f32> = (-r..=r)
+            .map(|i| (-((i*i) as f32)/(2.*sigma*sigma)).exp())
+            .collect();
+        let norm: f32 = kernel.iter().sum();
+        let kernel: Vec<f32> = kernel.iter().map(|v| v/norm).collect();
+
+        let (w, h) = (self.width as isize, self.height as isize);
+        let clamp = |v: isize, max: isize| v.max(0).min(max - 1) as usize;
+        let mut tmp = vec![0.; self.data.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = 0.;
+                for (i, k) in kernel.iter().enumerate() {
+                    let xx = clamp(x + i as isize - r, w);
+                    sum += k*self.get(xx, y as usize);
+                }
+                tmp[(y*w + x) as usize] = sum;
+            }
+        }
+        let mut out = vec![0.; self.data.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = 0.;
+                for (i, k) in kernel.iter().enumerate() {
+                    let yy = clamp(y + i as isize - r, h);
+                    sum += k*tmp[yy*self.width + x as usize];
+                }
+                out[(y*w + x) as usize] = sum;
+            }
+        }
+        GreyImage::new(out, self.width, self.height)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Candidate {
+    pos: [f32; 2],
+    response: f32,
+}
+
+fn sub(a: [f32; 2], b: [f32; 2]) -> [f32; 2] { [a[0] - b[0], a[1] - b[1]] }
+fn add(a: [f32; 2], b: [f32; 2]) -> [f32; 2] { [a[0] + b[0], a[1] + b[1]] }
+fn neg(a: [f32; 2]) -> [f32; 2] { [-a[0], -a[1]] }
+fn norm(a: [f32; 2]) -> f32 { (a[0]*a[0] + a[1]*a[1]).sqrt() }
+
+/// Find saddle points of the image, `expected` is the number of corners
+/// on the board
+fn find_candidates(img: &GreyImage, expected: usize) -> Vec<Candidate> {
+    let (w, h) = (img.width, img.height);
+    let mut resp = vec![0f32; w*h];
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let c = img.get(x, y);
+            let dxx = img.get(x + 1, y) + img.get(x - 1, y) - 2.*c;
+            let dyy = img.get(x, y + 1) + img.get(x, y - 1) - 2.*c;
+            let dxy = (img.get(x + 1, y + 1) + img.get(x - 1, y - 1)
+                - img.get(x + 1, y - 1) - img.get(x - 1, y + 1))/4.;
+            resp[y*w + x] = (dxy*dxy - dxx*dyy).max(0.);
+        }
+    }
+
+    let max = resp.iter().cloned().fold(0., f32::max);
+    if max <= 0. { return vec![]; }
+    let threshold = 1e-3*max;
+
+    // non-maximum suppression
+    const R: usize = 3;
+    let mut candidates = Vec::new();
+    for y in R..h.saturating_sub(R) {
+        'outer: for x in R..w.saturating_sub(R) {
+            let v = resp[y*w + x];
+            if v < threshold { continue; }
+            for yy in y - R..=y + R {
+                for xx in x - R..=x + R {
+                    let n = resp[yy*w + xx];
+                    if n > v || (n == v && (yy, xx) < (y, x)) {
+                        continue 'outer;
+                    }
+                }
+            }
+            // quadratic sub-pixel peak refinement
+            let fit = |a: f32, b: f32, c: f32| {
+                let d = a - 2.*b + c;
+                if d.abs() < 1e-12 { 0. } else { (0.5*(a - c)/d).max(-0.5).min(0.5) }
+            };
+            let ox = fit(resp[y*w + x - 1], v, resp[y*w + x + 1]);
+            let oy = fit(resp[(y - 1)*w + x], v, resp[(y + 1)*w + x]);
+            candidates.push(Candidate {
+                pos: [x as f32 + ox, y as f32 + oy],
+                response: v,
+            });
+        }
+    }
+    candidates.sort_by(|a, b| b.response.partial_cmp(&a.response).unwrap());
+    candidates.truncate(CANDIDATES_PER_CORNER*expected);
+
+    // weak saddle points are produced by noise along the square edges,
+    // response of real corners is compared against a typical board corner
+    let typical = match candidates.get(expected/2) {
+        Some(c) => c.response,
+        None => return vec![],
+    };
+    candidates.retain(|c| c.response > MIN_RESPONSE*typical);
+    candidates
+}
+
+/// Check that the neighbourhood of the point looks like a junction of four
+/// alternating squares by counting sign changes of intensity on a circle
+fn is_x_corner(img: &GreyImage, p: [f32; 2], radius: f32) -> bool {
+    const N: usize = 32;
+    let mut vals = [0f32; N];
+    for (i, v) in vals.iter_mut().enumerate() {
+        let phi = 2.*::std::f32::consts::PI*(i as f32)/(N as f32);
+        *v = img.sample(p[0] + radius*phi.cos(), p[1] + radius*phi.sin());
+    }
+    let min = vals.iter().cloned().fold(::std::f32::MAX, f32::min);
+    let max = vals.iter().cloned().fold(::std::f32::MIN, f32::max);
+    if max - min < MIN_CONTRAST { return false; }
+
+    // count transitions with hysteresis to ignore noise
+    let mid = (max + min)/2.;
+    let band = 0.15*(max - min);
+    let mut signs = vals.iter().filter_map(|&v| {
+        if v > mid + band { Some(true) } else if v < mid - band { Some(false) } else { None }
+    }).collect::<Vec<bool>>();
+    if signs.len() < N/2 { return false; }
+    signs.dedup();
+    let mut changes = signs.len() - 1;
+    if signs.first() != signs.last() { changes += 1; }
+    changes == 4
+}
+
+struct Node {
+    pos: [f32; 2],
+    u: [f32; 2],
+    v: [f32; 2],
+}
+
+/// Index of the nearest unused candidate within the radius which looks
+/// like a corner of squares with the given size
+fn nearest(
+    img: &GreyImage, cands: &[Candidate], used: &[bool],
+    p: [f32; 2], radius: f32, size: f32,
+) -> Option<usize> {
+    let mut found: Vec<(f32, usize)> = cands.iter()
+        .enumerate()
+        .filter(|&(i, _)| !used[i])
+        .map(|(i, c)| (norm(sub(c.pos, p)), i))
+        .filter(|&(d, _)| d < radius)
+        .collect();
+    found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    found.into_iter()
+        .map(|(_, i)| i)
+        .find(|&i| is_x_corner(img, cands[i].pos, 0.3*size))
+}
+
+/// Grow grid of corners starting from the seed candidate, returns map from
+/// grid coordinates to corner positions
+fn grow(
+    img: &GreyImage, cands: &[Candidate], seed: usize, pattern: [usize; 2],
+) -> Option<HashMap<(i32, i32), [f32; 2]>> {
+    let p = cands[seed].pos;
+
+    // initial grid directions are given by two nearest non-collinear
+    // neighbours of the seed which look like corners
+    let mut by_dist: Vec<(f32, [f32; 2])> = cands.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != seed)
+        .map(|(_, c)| { let d = sub(c.pos, p); (norm(d), d) })
+        .collect();
+    by_dist.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut neighbours = by_dist.into_iter()
+        .filter(|&(d, q)| d > 2. && is_x_corner(img, add(p, q), 0.3*d))
+        .take(8)
+        .collect::<Vec<_>>();
+    if neighbours.is_empty() { return None; }
+    let (du, u) = neighbours.remove(0);
+    let &(_, v) = neighbours.iter().find(|&&(d, v)| {
+        let cos = (u[0]*v[0] + u[1]*v[1])/(du*d);
+        cos.abs() < 0.5 && d < 2.*du
+    })?;
+
+    if !is_x_corner(img, p, 0.3*du) { return None; }
+
+    let max_extent = pattern[0].max(pattern[1]) as i32;
+    let mut used = vec![false; cands.len()];
+    used[seed] = true;
+    let mut nodes: HashMap<(i32, i32), Node> = HashMap::new();
+    nodes.insert((0, 0), Node { pos: p, u, v });
+    let mut queue = VecDeque::new();
+    queue.push_back((0, 0));
+    let (mut min_i, mut max_i, mut min_j, mut max_j) = (0, 0, 0, 0);
+
+    while let Some((i, j)) = queue.pop_front() {
+        let (pos, u, v) = {
+            let n = &nodes[&(i, j)];
+            (n.pos, n.u, n.v)
+        };
+        let steps = [((1, 0), u), ((-1, 0), neg(u)), ((0, 1), v), ((0, -1), neg(v))];
+        for &((di, dj), step) in steps.iter() {
+            let key = (i + di, j + dj);
+            if nodes.contains_key(&key) { continue; }
+            let spacing = norm(step);
+            let pred = add(pos, step);
+            let radius = MATCH_RADIUS*spacing;
+            let idx = match nearest(img, cands, &used, pred, radius, spacing) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let q = cands[idx].pos;
+
+            let (ni, nj) = key;
+            if ni.max(max_i) - ni.min(min_i) >= max_extent
+                || nj.max(max_j) - nj.min(min_j) >= max_extent
+            {
+                return None;
+            }
+            min_i = min_i.min(ni); max_i = max_i.max(ni);
+            min_j = min_j.min(nj); max_j = max_j.max(nj);
+
+            used[idx] = true;
+            let d = sub(q, pos);
+            let (nu, nv) = match (di, dj) {
+                (1, _) => (d, v),
+                (-1, _) => (neg(d), v),
+                (_, 1) => (u, d),
+                _ => (u, neg(d)),
+            };
+            nodes.insert(key, Node { pos: q, u: nu, v: nv });
+            queue.push_back(key);
+        }
+    }
+
+    let ni = (max_i - min_i + 1) as usize;
+    let nj = (max_j - min_j + 1) as usize;
+    let fits = (ni == pattern[0] && nj == pattern[1])
+        || (ni == pattern[1] && nj == pattern[0]);
+    if !fits || nodes.len() != ni*nj { return None; }
+
+    Some(nodes.into_iter()
+        .map(|((i, j), n)| ((i - min_i, j - min_j), n.pos))
+        .collect())
+}
+
+/// Sort corners into rows of `pattern[0]` corners, first corner is the one
+/// closest to the top left corner of the image
+fn order(grid: HashMap<(i32, i32), [f32; 2]>, pattern: [usize; 2])
+    -> Vec<[f32; 2]>
+{
+    let ni = grid.keys().map(|k| k.0).max().unwrap() + 1;
+    let nj = grid.keys().map(|k| k.1).max().unwrap() + 1;
+    let transpose = ni as usize != pattern[0];
+    let (cols, rows) = (pattern[0] as i32, pattern[1] as i32);
+
+    let get = |c: i32, r: i32, flip_c: bool, flip_r: bool| {
+        let c = if flip_c { cols - 1 - c } else { c };
+        let r = if flip_r { rows - 1 - r } else { r };
+        let key = if transpose { (r, c) } else { (c, r) };
+        grid[&key]
+    };
+    debug_assert_eq!((ni*nj) as usize, grid.len());
+
+    let flips = [(false, false), (true, false), (false, true), (true, true)];
+    let &(fc, fr) = flips.iter().min_by(|a, b| {
+        let pa = get(0, 0, a.0, a.1);
+        let pb = get(0, 0, b.0, b.1);
+        (pa[0] + pa[1]).partial_cmp(&(pb[0] + pb[1])).unwrap()
+    }).unwrap();
+
+    let mut corners = Vec::with_capacity((cols*rows) as usize);
+    for r in 0..rows {
+        for c in 0..cols {
+            corners.push(get(c, r, fc, fr));
+        }
+    }
+    corners
+}
+
+/// Refine corner position using the fact that image gradients in the
+/// neighbourhood are orthogonal to the vector from the corner
+fn refine(img: &GreyImage, p: [f32; 2], half_win: usize) -> [f32; 2] {
+    let (w, h) = (img.width as isize, img.height as isize);
+    let hw = half_win as isize;
+    let sigma2 = (half_win as f32/2.).powi(2).max(1.);
+    let mut p = p;
+    for _ in 0..10 {
+        let (cx, cy) = (p[0].round() as isize, p[1].round() as isize);
+        if cx - hw < 1 || cy - hw < 1 || cx + hw >= w - 1 || cy + hw >= h - 1 {
+            break;
+        }
+        let (mut a, mut b, mut c, mut bx, mut by) = (0f64, 0f64, 0f64, 0f64, 0f64);
+        for y in cy - hw..=cy + hw {
+            for x in cx - hw..=cx + hw {
+                let (xu, yu) = (x as usize, y as usize);
+                let gx = (img.get(xu + 1, yu) - img.get(xu - 1, yu)) as f64/2.;
+                let gy = (img.get(xu, yu + 1) - img.get(xu, yu - 1)) as f64/2.;
+                let d2 = ((x - cx)*(x - cx) + (y - cy)*(y - cy)) as f32;
+                let wgt = (-d2/(2.*sigma2)).exp() as f64;
+                let (gxx, gxy, gyy) = (wgt*gx*gx, wgt*gx*gy, wgt*gy*gy);
+                a += gxx; b += gxy; c += gyy;
+                bx += gxx*(x as f64) + gxy*(y as f64);
+                by += gxy*(x as f64) + gyy*(y as f64);
+            }
+        }
+        let det = a*c - b*b;
+        if det.abs() < 1e-9 { break; }
+        let nx = ((c*bx - b*by)/det) as f32;
+        let ny = ((a*by - b*bx)/det) as f32;
+        let shift = norm(sub([nx, ny], p));
+        if shift > half_win as f32 { break; }
+        p = [nx, ny];
+        if shift < 0.01 { break; }
+    }
+    p
+}
+
+/// Detect checkerboard with `pattern[0]` x `pattern[1]` inner corners
+///
+/// Returns corner positions in pixels sorted in rows of `pattern[0]` corners.
+pub fn detect(img: &GreyImage, pattern: [usize; 2]) -> Option<Vec<[f32; 2]>> {
+    assert!(pattern[0] >= 2 && pattern[1] >= 2);
+    let k = (img.width + SEARCH_WIDTH - 1)/SEARCH_WIDTH;
+    let small = img.downscale(k.max(1)).blur(SIGMA);
+
+    let n = pattern[0]*pattern[1];
+    let cands = find_candidates(&small, n);
+    if cands.len() < n { return None; }
+
+    let grid = (0..cands.len().min(MAX_SEEDS))
+        .filter_map(|seed| grow(&small, &cands, seed, pattern))
+        .next()?;
+    let corners = order(grid, pattern);
+
+    // spacing between neighbouring corners defines refinement window
+    let spacing = corners.windows(2)
+        .map(|w| norm(sub(w[1], w[0])))
+        .fold(::std::f32::MAX, f32::min);
+    let scale = k as f32;
+    let half_win = ((0.25*spacing*scale) as usize).max(2).min(12);
+    let blurred = img.blur(1.);
+    Some(corners.iter()
+        .map(|p| {
+            // centers of downscaled pixels are shifted by half of the factor
+            let p = [(p[0] + 0.5)*scale - 0.5, (p[1] + 0.5)*scale - 0.5];
+            refine(&blurred, p, half_win)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render board with `pattern` inner corners, square size `sq` and the
+    /// first inner corner at `origin` rotated by `angle`, pixels are
+    /// supersampled to get anti-aliased edges
+    fn render(
+        w: usize, h: usize, pattern: [usize; 2], sq: f32, origin: [f32; 2],
+        angle: f32,
+    ) -> GreyImage {
+        let (sin, cos) = angle.sin_cos();
+        let n = 4;
+        let mut data = Vec::with_capacity(w*h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = 0.;
+                for s in 0..n*n {
+                    let px = x as f32 - 0.5 + ((s % n) as f32 + 0.5)/n as f32;
+                    let py = y as f32 - 0.5 + ((s / n) as f32 + 0.5)/n as f32;
+                    let (dx, dy) = (px - origin[0], py - origin[1]);
+                    let bx = (cos*dx + sin*dy)/sq;
+                    let by = (-sin*dx + cos*dy)/sq;
+                    let outside = bx < -1. || by < -1.
+                        || bx >= pattern[0] as f32 || by >= pattern[1] as f32;
+                    let dark = !outside
+                        && (bx.floor() as i32 + by.floor() as i32) % 2 == 0;
+                    sum += if dark { 30. } else { 220. };
+                }
+                data.push(sum/(n*n) as f32);
+            }
+        }
+        GreyImage::new(data, w, h)
+    }
+
+    #[test]
+    fn detects_corners_in_rows() {
+        let pattern = [7, 5];
+        let (sq, origin, angle) = (40., [150.3, 110.6], 0.1f32);
+        let img = render(640, 480, pattern, sq, origin, angle);
+        let corners = detect(&img, pattern).expect("board not detected");
+        assert_eq!(corners.len(), pattern[0]*pattern[1]);
+
+        let (sin, cos) = angle.sin_cos();
+        for (i, p) in corners.iter().enumerate() {
+            let (c, r) = ((i % pattern[0]) as f32, (i / pattern[0]) as f32);
+            let expected = [
+                origin[0] + sq*(c*cos - r*sin),
+                origin[1] + sq*(c*sin + r*cos),
+            ];
+            let err = norm(sub(*p, expected));
+            assert!(err < 0.2, "corner {} at {:?}, expected {:?}",
+                i, p, expected);
+        }
+    }
+
+    #[test]
+    fn rejects_image_without_board() {
+        let img = GreyImage::new(vec![128.; 320*240], 320, 240);
+        assert!(detect(&img, [7, 5]).is_none());
+    }
+}
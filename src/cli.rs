@@ -1,20 +1,238 @@
 use vulkano::swapchain::PresentMode;
+use grid::{parse_color, parse_step};
+use reticle::parse_point;
+use capture::parse_board;
+use bench::parse_resolution;
+use rggb::{parse_algorithm, Algorithm};
+use yuv::{parse_matrix, parse_range, Matrix, Range};
+use noise::{parse_format, parse_roi, ExportFormat, Roi};
+use snapshot::{parse_save_format, SaveFormat};
 
 #[derive(StructOpt)]
 #[structopt(
     name = "cam-vis",
     about = "Simple camera visualization tool")]
 pub(crate) struct Cli {
-    /// Path to camera device
-    pub camera: String,
+    /// Paths to camera devices, several cameras are shown as tiles
+    pub camera: Vec<String>,
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
     #[structopt(long = "mode", short = "m",
         parse(try_from_str = "parse_mode"),
         default_value="fifo")]
     /// Vulkan present mode: immediate, mailbox, fifo or relaxed
     pub mode: PresentMode,
-    #[structopt(long = "grid-step", short = "g", default_value="64")]
+    #[structopt(long = "grid-step", short = "g",
+        parse(try_from_str = "parse_step"),
+        default_value="64")]
     /// Grid step in pixels
     pub grid_step: u32,
+    #[structopt(long = "grid-step-x",
+        parse(try_from_str = "parse_step"))]
+    /// Horizontal grid step in pixels, overrides `--grid-step`
+    pub grid_step_x: Option<u32>,
+    #[structopt(long = "grid-step-y",
+        parse(try_from_str = "parse_step"))]
+    /// Vertical grid step in pixels, overrides `--grid-step`
+    pub grid_step_y: Option<u32>,
+    #[structopt(long = "grid-color",
+        parse(try_from_str = "parse_color"),
+        default_value="0000ff")]
+    /// Grid color in the RRGGBB or RRGGBBAA hex format
+    pub grid_color: [f32; 4],
+    #[structopt(long = "grid-centered")]
+    /// Draw grid relative to the image center instead of the top left corner
+    pub grid_centered: bool,
+    #[structopt(long = "grid-labels")]
+    /// Show grid line coordinates
+    pub grid_labels: bool,
+    #[structopt(long = "align-color",
+        parse(try_from_str = "parse_color"),
+        default_value="ff00ff")]
+    /// Alignment overlay color in the RRGGBB or RRGGBBAA hex format
+    pub align_color: [f32; 4],
+    #[structopt(long = "circles", raw(require_delimiter = "true"))]
+    /// Comma separated radii in pixels of circles around the image center
+    pub circles: Vec<f32>,
+    #[structopt(long = "marker",
+        parse(try_from_str = "parse_point"),
+        raw(number_of_values = "1", use_delimiter = "false"))]
+    /// Reference marker position in the x,y format (can be repeated)
+    pub markers: Vec<[f32; 2]>,
+    #[structopt(long = "reticle")]
+    /// Path to file with additional alignment shapes
+    pub reticle: Option<String>,
+    #[structopt(long = "calib")]
+    /// Path to OpenCV or ROS calibration file (YAML or JSON) used for
+    /// undistortion
+    pub calib: Option<String>,
+    #[structopt(long = "board",
+        parse(try_from_str = "parse_board"),
+        default_value="9x6")]
+    /// Number of inner checkerboard corners for calibration capture in
+    /// the <columns>x<rows> format
+    pub board: [usize; 2],
+    #[structopt(long = "calib-dir", default_value="calib_data")]
+    /// Directory for frames saved in calibration capture mode
+    pub calib_dir: String,
+    #[structopt(long = "sync-tolerance",
+        parse(try_from_str = "parse_tolerance"),
+        default_value="5")]
+    /// Maximum difference in milliseconds between timestamps of frames from
+    /// different cameras shown or recorded together
+    pub sync_tolerance: u64,
+    #[structopt(long = "sync-display")]
+    /// Show the latest set of frames matched by timestamps instead of the
+    /// latest frame of each camera
+    pub sync_display: bool,
+    #[structopt(long = "record-dir", default_value="recordings")]
+    /// Directory for recorded frames
+    pub record_dir: String,
+    #[structopt(long = "record-format",
+        parse(try_from_str = "parse_save_format"),
+        default_value="png")]
+    /// Format of recorded frames, same as for `--save-format`
+    pub record_format: SaveFormat,
+    #[structopt(long = "noise-frames",
+        parse(try_from_str = "parse_noise_frames"),
+        default_value="64")]
+    /// Number of frames accumulated for noise measurement
+    pub noise_frames: u32,
+    #[structopt(long = "noise-roi",
+        parse(try_from_str = "parse_roi"),
+        raw(number_of_values = "1", use_delimiter = "false"))]
+    /// Region summarized by noise measurement in addition to the full frame
+    /// in the x,y,w,h format (can be repeated)
+    pub noise_rois: Vec<Roi>,
+    #[structopt(long = "noise-format",
+        parse(try_from_str = "parse_format"),
+        default_value="npy")]
+    /// Format of exported noise maps: npy (32-bit float) or tiff (16-bit,
+    /// values multiplied by 256)
+    pub noise_format: ExportFormat,
+    #[structopt(long = "defects-dir", default_value="defects")]
+    /// Directory with per-camera defect maps
+    pub defects_dir: String,
+    #[structopt(long = "defect-threshold", default_value="24")]
+    /// Minimal difference in levels between a pixel and its neighbors for
+    /// the pixel to be detected as hot or dead
+    pub defect_threshold: f32,
+    #[structopt(long = "correct-defects")]
+    /// Correct pixels from the defect maps in raw data
+    pub correct_defects: bool,
+    #[structopt(long = "flat-dir", default_value="flat")]
+    /// Directory with per-camera flat-field gain maps
+    pub flat_dir: String,
+    #[structopt(long = "correct-flat")]
+    /// Correct lens vignetting and color shading using the flat-field gain
+    /// maps
+    pub correct_flat: bool,
+    #[structopt(long = "demosaic",
+        parse(try_from_str = "parse_algorithm"),
+        default_value="bilinear")]
+    /// Demosaicing algorithm for raw Bayer cameras: bilinear or malvar
+    pub demosaic: Algorithm,
+    #[structopt(long = "yuv-matrix",
+        parse(try_from_str = "parse_matrix"))]
+    /// YUV matrix of YUYV cameras: bt601, bt709 or bt2020, overrides the
+    /// value reported by the driver
+    pub yuv_matrix: Option<Matrix>,
+    #[structopt(long = "yuv-range",
+        parse(try_from_str = "parse_range"))]
+    /// YUV quantization range of YUYV cameras: full or limited, overrides
+    /// the value reported by the driver, full range is assumed if the
+    /// driver does not report it
+    pub yuv_range: Option<Range>,
+    #[structopt(long = "isp")]
+    /// Apply black level, white balance, color matrix and gamma from the
+    /// camera profiles to raw Bayer frames
+    pub isp: bool,
+    #[structopt(long = "isp-dir", default_value="isp")]
+    /// Directory with per-camera color processing profiles
+    pub isp_dir: String,
+    #[structopt(long = "auto-wb")]
+    /// Estimate white balance of processed raw Bayer frames continuously
+    /// assuming that the scene is grey on average
+    pub auto_wb: bool,
+    #[structopt(long = "gpu-convert")]
+    /// Demosaic and convert frames for display in a compute shader
+    pub gpu_convert: bool,
+    #[structopt(long = "convert-threads", default_value="0")]
+    /// Number of threads converting each camera frame on CPU, 0 divides all
+    /// cores between the cameras
+    pub convert_threads: usize,
+    #[structopt(long = "rotate", short = "r",
+        parse(try_from_str = "parse_rotation"),
+        default_value="0")]
+    /// Clockwise image rotation in degrees: 0, 90, 180 or 270
+    pub rotate: u32,
+    #[structopt(long = "flip-h")]
+    /// Mirror image horizontally (applied after rotation)
+    pub flip_h: bool,
+    #[structopt(long = "flip-v")]
+    /// Mirror image vertically (applied after rotation)
+    pub flip_v: bool,
+    #[structopt(long = "save-oriented")]
+    /// Apply rotation and mirroring to saved frames
+    pub save_oriented: bool,
+    #[structopt(long = "save-dir", default_value=".")]
+    /// Directory for saved frames
+    pub save_dir: String,
+    #[structopt(long = "save-name", default_value="{ts}")]
+    /// Saved frame file name without extension, {ts}, {cam}, {dev} and
+    /// {fourcc} are replaced with the frame timestamp in microseconds,
+    /// camera index, device name and pixel format
+    pub save_name: String,
+    #[structopt(long = "save-format",
+        parse(try_from_str = "parse_save_format"),
+        default_value="png")]
+    /// Format of saved frames: png, tiff (8-bit data in 16-bit samples), pnm
+    /// (PPM or PGM), raw (frame data with JSON metadata) or dng (raw Bayer
+    /// only)
+    pub save_format: SaveFormat,
+}
+
+#[derive(StructOpt)]
+pub(crate) enum Command {
+    #[structopt(name = "calibrate")]
+    /// Compute camera intrinsics from checkerboard images (PNG or PGM) and
+    /// write them into an OpenCV calibration file
+    Calibrate {
+        /// Directory with checkerboard images
+        dir: String,
+        #[structopt(long = "board",
+            parse(try_from_str = "parse_board"),
+            default_value="9x6")]
+        /// Number of inner checkerboard corners in the <columns>x<rows>
+        /// format
+        board: [usize; 2],
+        #[structopt(long = "square", default_value="1")]
+        /// Size of the checkerboard square, defines units of the board
+        /// positions
+        square: f64,
+        #[structopt(long = "output", short = "o",
+            default_value="calibration.yaml")]
+        /// Path to the output calibration file
+        output: String,
+    },
+    #[structopt(name = "bench")]
+    /// Measure throughput of the CPU frame conversion in megapixels per
+    /// second for each supported format
+    Bench {
+        #[structopt(long = "resolution",
+            parse(try_from_str = "parse_resolution"),
+            raw(number_of_values = "1", use_delimiter = "false"))]
+        /// Frame resolution in the <width>x<height> format (can be
+        /// repeated), 640x480, 1280x720, 1920x1080 and 3840x2160 by default
+        resolutions: Vec<[u32; 2]>,
+        #[structopt(long = "frames", default_value="50")]
+        /// Number of converted frames in each measurement
+        frames: u32,
+        #[structopt(long = "threads", default_value="0")]
+        /// Number of threads compared with the single-threaded conversion,
+        /// 0 uses all cores
+        threads: usize,
+    },
 }
 
 fn parse_mode(s: &str) -> Result<PresentMode, &'static str> {
@@ -28,3 +246,29 @@ fn parse_mode(s: &str) -> Result<PresentMode, &'static str> {
         _ => Err("unknown present mode")?,
     })
 }
+
+/// Parse tolerance in milliseconds into microseconds
+fn parse_tolerance(s: &str) -> Result<u64, &'static str> {
+    match s.parse::<f64>() {
+        Ok(v) if v >= 0. => Ok((v*1000.).round() as u64),
+        _ => Err("tolerance must be a non-negative number of milliseconds"),
+    }
+}
+
+/// Sums of squared 8-bit samples fit into 32 bits for up to 65536 frames
+fn parse_noise_frames(s: &str) -> Result<u32, &'static str> {
+    match s.parse::<u32>() {
+        Ok(v) if v >= 2 && v <= 65536 => Ok(v),
+        _ => Err("number of frames must be between 2 and 65536"),
+    }
+}
+
+fn parse_rotation(s: &str) -> Result<u32, &'static str> {
+    match s {
+        "0" => Ok(0),
+        "90" => Ok(90),
+        "180" => Ok(180),
+        "270" => Ok(270),
+        _ => Err("rotation must be 0, 90, 180 or 270"),
+    }
+}
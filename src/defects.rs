@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::{ColorVertex, EngineState};
+use tile::Tile;
+
+/// Temporal standard deviation below which a pixel is considered constant
+const STUCK_STD: f32 = 0.05;
+/// Minimal median temporal noise of the neighbors required to report a
+/// constant pixel as stuck
+const STUCK_NOISE: f32 = 0.5;
+/// Half size of the defect marker in screen pixels
+const MARKER_SIZE: f32 = 6.;
+
+/// Defect map settings
+pub(crate) struct Defects {
+    /// Directory with per-camera defect maps
+    pub dir: PathBuf,
+    /// Minimal difference between the pixel and its neighbors for a pixel
+    /// to be reported as hot or dead
+    pub threshold: f32,
+    pub overlay_on: bool,
+    pub correct_on: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DefectKind {
+    /// Pixel is darker than its neighbors
+    Dead,
+    /// Pixel is brighter than its neighbors
+    Hot,
+    /// Pixel value does not change over time
+    Stuck,
+}
+
+impl DefectKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DefectKind::Dead => "dead",
+            DefectKind::Hot => "hot",
+            DefectKind::Stuck => "stuck",
+        }
+    }
+
+    fn color(self) -> [f32; 4] {
+        match self {
+            DefectKind::Dead => [0., 0.5, 1., 1.],
+            DefectKind::Hot => [1., 0., 0., 1.],
+            DefectKind::Stuck => [1., 1., 0., 1.],
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "dead" => Some(DefectKind::Dead),
+            "hot" => Some(DefectKind::Hot),
+            "stuck" => Some(DefectKind::Stuck),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Defect {
+    pub pos: [u32; 2],
+    pub kind: DefectKind,
+}
+
+/// Defective pixels of a single camera
+pub(crate) struct DefectMap {
+    pub resolution: [u32; 2],
+    /// Defects sorted by their position in the frame
+    pub defects: Vec<Defect>,
+}
+
+impl DefectMap {
+    pub fn new(resolution: [u32; 2]) -> Self {
+        DefectMap { resolution, defects: Vec::new() }
+    }
+
+    /// Load map from file, returns empty map if file does not exist
+    pub fn load(path: &Path, resolution: [u32; 2])
+        -> Result<Self, Box<Error>>
+    {
+        let mut map = DefectMap::new(resolution);
+        if !path.exists() { return Ok(map); }
+
+        let reader = BufReader::new(File::open(path)?);
+        let mut has_res = false;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let v: Vec<&str> = line.split_whitespace().collect();
+            match v[..] {
+                ["resolution", res] => {
+                    let [w, h] = resolution;
+                    if res != format!("{}x{}", w, h) {
+                        Err("map resolution does not match the camera")?
+                    }
+                    has_res = true;
+                },
+                [x, y, kind] if has_res => {
+                    let pos = [x.parse::<u32>()?, y.parse::<u32>()?];
+                    if pos[0] >= resolution[0] || pos[1] >= resolution[1] {
+                        Err("defect position is outside of the frame")?
+                    }
+                    let kind = match DefectKind::parse(kind) {
+                        Some(kind) => kind,
+                        None => Err(format!("unknown defect: {}", kind))?,
+                    };
+                    map.defects.push(Defect { pos, kind });
+                },
+                _ => Err(format!("invalid defect map line: {}", line))?,
+            }
+        }
+        map.merge(Vec::new());
+        Ok(map)
+    }
+
+    pub fn save(&self, path: &Path, camera: &str) -> Result<(), Box<Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut bw = BufWriter::new(File::create(path)?);
+        writeln!(bw, "# defect map of {}: x y kind", camera)?;
+        let res = self.resolution;
+        writeln!(bw, "resolution {}x{}", res[0], res[1])?;
+        for d in self.defects.iter() {
+            writeln!(bw, "{} {} {}", d.pos[0], d.pos[1], d.kind.name())?;
+        }
+        bw.flush()?;
+        Ok(())
+    }
+
+    fn index(&self, d: &Defect) -> u32 {
+        d.pos[1]*self.resolution[0] + d.pos[0]
+    }
+
+    /// Add defects which are not in the map yet, returns number of the
+    /// added defects
+    pub fn merge(&mut self, defects: Vec<Defect>) -> usize {
+        let n = self.defects.len();
+        // existing entries come first, so they are kept by deduplication
+        self.defects.extend(defects);
+        let w = self.resolution[0];
+        let index = |d: &Defect| d.pos[1]*w + d.pos[0];
+        let mut defects: Vec<(usize, Defect)> = self.defects.drain(..)
+            .enumerate()
+            .collect();
+        defects.sort_by_key(|&(i, ref d)| (index(d), i));
+        defects.dedup_by_key(|&mut (_, ref d)| index(d));
+        self.defects = defects.into_iter().map(|(_, d)| d).collect();
+        self.defects.len() - n
+    }
+
+    /// Sorted frame indices of the defective pixels
+    pub fn indices(&self) -> Vec<u32> {
+        self.defects.iter().map(|d| self.index(d)).collect()
+    }
+
+    pub fn count(&self, kind: DefectKind) -> usize {
+        self.defects.iter().filter(|d| d.kind == kind).count()
+    }
+}
+
+/// Path to the per-camera file in `dir` (defect map or color processing
+/// profile), device path separators are replaced with underscores, e.g.
+/// `/dev/video0` is stored as `<dir>/dev_video0.txt`
+pub(crate) fn map_path(dir: &Path, camera: &str) -> PathBuf {
+    let name = camera.trim_start_matches('/').replace('/', "_");
+    dir.join(format!("{}.txt", name))
+}
+
+/// Check if raw frames of the tile camera have one sample per pixel, so
+/// they can be corrected
+fn has_raw_samples(tile: &Tile) -> bool {
+    tile.is_bayer || tile.is_grey
+}
+
+/// Distance between neighboring samples of the same color
+fn color_step(tile: &Tile) -> usize {
+    if tile.is_bayer { 2 } else { 1 }
+}
+
+fn median(v: &mut [f32]) -> f32 {
+    v.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = v.len();
+    if n % 2 == 1 { v[n/2] } else { (v[n/2 - 1] + v[n/2])/2. }
+}
+
+/// Find defective pixels by comparing per-pixel temporal mean and standard
+/// deviation with the medians of the neighbors of the same color
+fn find(
+    mean: &[f32], std: &[f32], res: [u32; 2], step: usize, threshold: f32,
+) -> Vec<Defect> {
+    let (w, h) = (res[0] as isize, res[1] as isize);
+    let s = step as isize;
+    let mut out = Vec::new();
+    let mut means = Vec::with_capacity(8);
+    let mut stds = Vec::with_capacity(8);
+    for y in 0..h {
+        for x in 0..w {
+            means.clear();
+            stds.clear();
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (nx, ny) = (x + dx*s, y + dy*s);
+                    if (dx == 0 && dy == 0) || nx < 0 || ny < 0
+                        || nx >= w || ny >= h
+                    {
+                        continue;
+                    }
+                    let j = (ny*w + nx) as usize;
+                    means.push(mean[j]);
+                    stds.push(std[j]);
+                }
+            }
+            if means.is_empty() { continue; }
+
+            let i = (y*w + x) as usize;
+            let (m, sd) = (mean[i], std[i]);
+            let (med_mean, med_std) = (median(&mut means), median(&mut stds));
+            let kind = if sd < STUCK_STD && med_std >= STUCK_NOISE {
+                DefectKind::Stuck
+            } else if m - med_mean > threshold {
+                DefectKind::Hot
+            } else if med_mean - m > threshold {
+                DefectKind::Dead
+            } else {
+                continue;
+            };
+            out.push(Defect { pos: [x as u32, y as u32], kind });
+        }
+    }
+    out
+}
+
+/// Replace defective samples with the average of the nearest non-defective
+/// samples of the same color, `defects` are sorted sample indices and
+/// `step` is the distance between samples of the same color
+pub fn correct(raw: &mut [u8], width: usize, step: usize, defects: &[u32]) {
+    let height = raw.len()/width;
+    let (w, h, s) = (width as isize, height as isize, step as isize);
+    for &i in defects {
+        let i = i as usize;
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        let (mut sum, mut n) = (0u32, 0u32);
+        for &(dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)].iter() {
+            let (nx, ny) = (x + dx*s, y + dy*s);
+            if nx < 0 || ny < 0 || nx >= w || ny >= h { continue; }
+            let j = (ny*w + nx) as usize;
+            if defects.binary_search(&(j as u32)).is_ok() { continue; }
+            sum += u32::from(raw[j]);
+            n += 1;
+        }
+        if n > 0 {
+            raw[i] = ((sum + n/2)/n) as u8;
+        }
+    }
+}
+
+/// Load defect map of the tile camera from the directory, maps are used
+/// only by raw Bayer and greyscale cameras, map is left empty if it can not
+/// be loaded
+pub(crate) fn load(tile: &mut Tile, dir: &Path) -> Result<(), Box<Error>> {
+    if !has_raw_samples(tile) { return Ok(()); }
+    let path = map_path(dir, &tile.name);
+    tile.defects = DefectMap::load(&path, tile.resolution)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(())
+}
+
+/// Update defect lists used by the camera workers for correction
+pub(crate) fn apply_correction(state: &EngineState) {
+    for tile in state.tiles.iter() {
+        let indices = if state.defects.correct_on && has_raw_samples(tile) {
+            tile.defects.indices()
+        } else {
+            Vec::new()
+        };
+        *tile.correction.lock().unwrap() = indices;
+    }
+}
+
+fn save_active(state: &EngineState) -> Result<PathBuf, Box<Error>> {
+    let tile = state.tile();
+    let path = map_path(&state.defects.dir, &tile.name);
+    tile.defects.save(&path, &tile.name)?;
+    Ok(path)
+}
+
+/// Detect defects of the active camera from its finished noise
+/// measurement, merge them into the camera defect map and save it
+pub(crate) fn detect(state: &mut EngineState) -> Result<String, Box<Error>> {
+    let found = {
+        let tile = state.tile();
+        if !has_raw_samples(tile) {
+            Err("defect detection requires raw Bayer or greyscale camera")?
+        }
+        let (mean, std) = match tile.noise {
+            Some(ref m) => match m.stats() {
+                Some(stats) => stats,
+                None => Err("noise measurement is not finished")?,
+            },
+            None => Err("defect detection requires noise measurement")?,
+        };
+        let threshold = state.defects.threshold;
+        find(mean, std, tile.resolution, color_step(tile), threshold)
+    };
+    let n = found.len();
+    let added = state.tile_mut().defects.merge(found);
+    apply_correction(state);
+    let path = save_active(state)?;
+    Ok(format!(
+        "defects: {} found, {} new, {} total, saved to {}",
+        n, added, state.tile().defects.defects.len(), path.display(),
+    ))
+}
+
+/// Remove all defects of the active camera and save the empty map
+pub(crate) fn clear(state: &mut EngineState) -> Result<String, Box<Error>> {
+    state.tile_mut().defects.defects.clear();
+    apply_correction(state);
+    let path = save_active(state)?;
+    Ok(format!("defects cleared, saved to {}", path.display()))
+}
+
+/// Build square markers around the defective pixels of the tile
+pub(crate) fn markers(tile: &Tile) -> Vec<ColorVertex> {
+    let mut out = Vec::new();
+    let pc = &tile.push_consts;
+    let res = tile.resolution;
+    let dims = tile.dims;
+    let s = [2.*MARKER_SIZE/dims[0], 2.*MARKER_SIZE/dims[1]];
+    for d in tile.defects.defects.iter() {
+        let p = [d.pos[0] as f32 + 0.5, d.pos[1] as f32 + 0.5];
+        let p = pc.to_ndc(p, res);
+        // markers outside of the tile are clipped anyway
+        if p[0].abs() > 1. + s[0] || p[1].abs() > 1. + s[1] { continue; }
+        let color = d.kind.color();
+        let corners = [
+            [p[0] - s[0], p[1] - s[1]],
+            [p[0] + s[0], p[1] - s[1]],
+            [p[0] + s[0], p[1] + s[1]],
+            [p[0] - s[0], p[1] + s[1]],
+        ];
+        for k in 0..4 {
+            out.push(ColorVertex { position: corners[k], color });
+            out.push(ColorVertex { position: corners[(k + 1) % 4], color });
+        }
+    }
+    out
+}
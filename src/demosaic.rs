@@ -1,50 +1,87 @@
 use std::slice;
 
-use cam::Cam;
-use rggb;
+use isp::{self, Pipeline};
+use parallel;
+use rggb::{self, Algorithm};
+#[cfg(target_arch = "x86_64")]
+use simd;
+use yuv::{self, Coeffs, Encoding};
 
-fn demosaic_rggb(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
-    assert_eq!(buf.len(), cam.get_pixels());
-    assert_eq!(frame.len(), cam.get_frame_size());
+/// Parameters of the frame conversion into RGB
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Conversion {
+    /// Demosaicing algorithm used for raw Bayer frames
+    pub algorithm: Algorithm,
+    /// Encoding of YUV frames
+    pub yuv: Encoding,
+    /// Color processing of demosaiced raw Bayer frames
+    pub isp: isp::Params,
+}
+
+fn demosaic_rggb(
+    res: [u32; 2], buf: &mut [[u8; 3]], frame: &[u8], algorithm: Algorithm,
+) {
+    assert_eq!(buf.len(), (res[0]*res[1]) as usize);
     assert_eq!(frame.len(), buf.len());
 
     let buf2 = unsafe {
         slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, 3*buf.len())
     };
 
-    let res = cam.get_resolution();
-    rggb::demosaic(frame, buf2, res[0] as usize, res[1] as usize);
+    let (w, h) = (res[0] as usize, res[1] as usize);
+    match algorithm {
+        Algorithm::Bilinear => rggb::demosaic(frame, buf2, w, h),
+        Algorithm::Malvar => rggb::demosaic_malvar(frame, buf2, w, h),
+    }
+}
+
+#[inline(always)]
+fn yuv_to_rgb(y: u8, cb: u8, cr: u8, c: &Coeffs) -> [u8; 3] {
+    let k = c.k;
+    let y = (i32::from(y) - c.y_offset)*c.y_scale + (1 << (yuv::SHIFT - 1));
+    let (cb, cr) = (i32::from(cb) - 128, i32::from(cr) - 128);
+    let clamp = |v: i32| (v >> yuv::SHIFT).max(0).min(255) as u8;
+    [clamp(y + k[0]*cr), clamp(y + k[1]*cb + k[2]*cr), clamp(y + k[3]*cb)]
 }
 
-fn demosaic_yuyv(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
-    assert_eq!(buf.len(), cam.get_pixels());
-    assert_eq!(frame.len(), cam.get_frame_size());
+fn demosaic_yuyv(
+    res: [u32; 2], buf: &mut [[u8; 3]], frame: &[u8], yuv: Encoding,
+) {
+    assert_eq!(buf.len(), (res[0]*res[1]) as usize);
     assert_eq!(frame.len(), 2*buf.len());
+    assert_eq!(res[0] % 2, 0);
 
-    for (i, v) in buf.iter_mut().enumerate() {
-        let (y, cb, cr) = if i % 2 == 0 {
-            (f32::from(frame[2*i]), f32::from(frame[2*i+1]), f32::from(frame[2*i+3]))
-        } else {
-            (f32::from(frame[2*i]), f32::from(frame[2*i-1]), f32::from(frame[2*i+1]))
-        };
-
-        let mut r = y + 1.402*(cr - 128.);
-        let mut g = y - 0.344_136*(cb - 128.) - 0.714_136*(cr - 128.);
-        let mut b = y + 1.772*(cb - 128.);
-        if r > 255. { r = 255.; }
-        if r < 0. { r = 0.; }
-        if g > 255. { g = 255.; }
-        if g < 0. { g = 0.; }
-        if b > 255. { b = 255.; }
-        if b < 0. { b = 0.; }
-
-        *v = [r as u8, g as u8, b as u8];
+    let w = res[0] as usize;
+    let coeffs = yuv.coeffs();
+    parallel::for_each_band(buf, res[1] as usize, 1, |y0, band| {
+        yuyv_rows(band, &frame[2*w*y0..][..2*band.len()], &coeffs);
+    });
+}
+
+fn yuyv_rows(buf: &mut [[u8; 3]], frame: &[u8], c: &Coeffs) {
+    #[allow(unused_mut)]
+    let mut i = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        // SIMD stores write past the converted pixels, so at least one
+        // pixel is left for the scalar code
+        while i + 8 < buf.len() {
+            simd::yuyv8(buf, frame, i, c);
+            i += 8;
+        }
+    }
+
+    // two pixels of a macropixel share chroma samples
+    let pairs = buf[i..].chunks_exact_mut(2).zip(frame[2*i..].chunks_exact(4));
+    for (px, yuyv) in pairs {
+        let (cb, cr) = (yuyv[1], yuyv[3]);
+        px[0] = yuv_to_rgb(yuyv[0], cb, cr, c);
+        px[1] = yuv_to_rgb(yuyv[2], cb, cr, c);
     }
 }
 
-fn demosaic_grey(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
-    assert_eq!(buf.len(), cam.get_pixels());
-    assert_eq!(frame.len(), cam.get_frame_size());
+fn demosaic_grey(res: [u32; 2], buf: &mut [[u8; 3]], frame: &[u8]) {
+    assert_eq!(buf.len(), (res[0]*res[1]) as usize);
     assert_eq!(frame.len(), buf.len());
 
     buf.iter_mut().zip(frame).for_each(|(a, b)|
@@ -63,9 +100,8 @@ fn demosaic_grey(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
     );
 }
 
-fn demosaic_bgr3(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
-    assert_eq!(buf.len(), cam.get_pixels());
-    assert_eq!(frame.len(), cam.get_frame_size());
+fn demosaic_bgr3(res: [u32; 2], buf: &mut [[u8; 3]], frame: &[u8]) {
+    assert_eq!(buf.len(), (res[0]*res[1]) as usize);
     assert_eq!(frame.len(), 3*buf.len());
 
     for (rgba, rgb) in buf.iter_mut().zip(frame.chunks_exact(3)) {
@@ -73,9 +109,8 @@ fn demosaic_bgr3(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
     }
 }
 
-fn demosaic_rgb3(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
-    assert_eq!(buf.len(), cam.get_pixels());
-    assert_eq!(frame.len(), cam.get_frame_size());
+fn demosaic_rgb3(res: [u32; 2], buf: &mut [[u8; 3]], frame: &[u8]) {
+    assert_eq!(buf.len(), (res[0]*res[1]) as usize);
     assert_eq!(frame.len(), 3*buf.len());
 
     for (rgba, rgb) in buf.iter_mut().zip(frame.chunks_exact(3)) {
@@ -83,13 +118,53 @@ fn demosaic_rgb3(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
     }
 }
 
-pub fn demosaic(cam: &Cam, buf: &mut [[u8; 3]], frame: &[u8]) {
-    match &cam.get_format() {
-        b"YUYV" => demosaic_yuyv(cam, buf, frame),
-        b"RGGB" => demosaic_rggb(cam, buf, frame),
-        b"GREY" => demosaic_grey(cam, buf, frame),
-        b"BGR3" => demosaic_bgr3(cam, buf, frame),
-        b"RGB3" => demosaic_rgb3(cam, buf, frame),
+/// Convert frame of the given V4L2 format into RGB
+pub fn demosaic(
+    format: [u8; 4], res: [u32; 2], buf: &mut [[u8; 3]], frame: &[u8],
+    conv: &Conversion,
+) {
+    match &format {
+        b"YUYV" => demosaic_yuyv(res, buf, frame, conv.yuv),
+        b"RGGB" => {
+            demosaic_rggb(res, buf, frame, conv.algorithm);
+            if conv.isp.enabled {
+                Pipeline::new(&conv.isp).apply(buf, res[1] as usize);
+            }
+        },
+        b"GREY" => demosaic_grey(res, buf, frame),
+        b"BGR3" => demosaic_bgr3(res, buf, frame),
+        b"RGB3" => demosaic_rgb3(res, buf, frame),
         _ => unreachable!(),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::random_frame;
+    use yuv::{Matrix, Range};
+
+    #[test]
+    fn yuyv_rows_match_scalar() {
+        let matrices = [Matrix::Bt601, Matrix::Bt709, Matrix::Bt2020];
+        for &w in [10, 18, 22, 42].iter() {
+            let frame = random_frame(2*w, w as u32);
+            for &matrix in matrices.iter() {
+                for &range in [Range::Full, Range::Limited].iter() {
+                    let c = Encoding { matrix, range }.coeffs();
+                    let mut buf = vec![[0; 3]; w];
+                    yuyv_rows(&mut buf, &frame, &c);
+
+                    let expected: Vec<[u8; 3]> = frame.chunks(4)
+                        .flat_map(|p| vec![
+                            yuv_to_rgb(p[0], p[1], p[3], &c),
+                            yuv_to_rgb(p[2], p[1], p[3], &c),
+                        ])
+                        .collect();
+                    assert!(buf == expected, "width {}, {:?} {:?}",
+                        w, matrix, range);
+                }
+            }
+        }
+    }
+}
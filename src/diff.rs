@@ -0,0 +1,112 @@
+use cam::FrameBuf;
+use view::{self, ViewMode};
+
+/// Minimal channel difference for a pixel to be counted as changed
+const MOTION_THRESHOLD: i16 = 16;
+/// Maximum difference gain
+pub(crate) const MAX_GAIN: f32 = 64.;
+
+/// Frame used as a base for the difference display
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DiffMode {
+    Off,
+    /// Difference between the current and previous frames
+    Previous,
+    /// Difference between the current frame and the pinned reference one
+    Reference,
+}
+
+impl DiffMode {
+    pub fn next(self) -> Self {
+        use self::DiffMode::*;
+        match self {
+            Off => Previous,
+            Previous => Reference,
+            Reference => Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use self::DiffMode::*;
+        match self {
+            Off => "off",
+            Previous => "previous frame",
+            Reference => "reference frame",
+        }
+    }
+}
+
+/// Frame difference state of a single camera
+pub(crate) struct FrameDiff {
+    /// Frame preceding the latest one
+    prev: Option<FrameBuf>,
+    last: Option<FrameBuf>,
+    reference: Option<FrameBuf>,
+    /// Percentage of pixels changed in the latest difference
+    pub motion: f32,
+}
+
+impl FrameDiff {
+    pub fn new() -> Self {
+        FrameDiff { prev: None, last: None, reference: None, motion: 0. }
+    }
+
+    /// Remember new frame, should be called once for each received frame
+    pub fn push(&mut self, frame: &FrameBuf) {
+        self.prev = self.last.take();
+        self.last = Some(frame.clone());
+    }
+
+    /// Release stored frames except the reference one
+    pub fn clear(&mut self) {
+        self.prev = None;
+        self.last = None;
+        self.motion = 0.;
+    }
+
+    pub fn has_reference(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Use frame as the reference for the following differences
+    pub fn pin(&mut self, frame: &FrameBuf) {
+        self.reference = Some(frame.clone());
+    }
+
+    /// Convert frame into RGBA texture data with absolute per-channel
+    /// difference to the base frame multiplied by gain, both frames are
+    /// converted according to the view mode first
+    pub fn to_rgba(
+        &mut self, mode: DiffMode, view_mode: ViewMode, gain: f32,
+        frame: &FrameBuf, width: usize,
+    ) -> Vec<[u8; 4]> {
+        let cur = view::to_rgba(view_mode, frame, width);
+        let base = match mode {
+            DiffMode::Off => return cur,
+            DiffMode::Previous => self.prev.as_ref(),
+            DiffMode::Reference => self.reference.as_ref(),
+        };
+        let base = match base {
+            Some(base) => view::to_rgba(view_mode, base, width),
+            None => {
+                self.motion = 0.;
+                return vec![[0, 0, 0, 255]; cur.len()];
+            },
+        };
+
+        let mut changed = 0usize;
+        let out: Vec<[u8; 4]> = cur.iter().zip(base.iter()).map(|(c, b)| {
+            let mut p = [0, 0, 0, 255];
+            let mut is_changed = false;
+            for k in 0..3 {
+                let d = (c[k] as i16 - b[k] as i16).abs();
+                is_changed |= d >= MOTION_THRESHOLD;
+                p[k] = ((d as f32)*gain).min(255.) as u8;
+            }
+            changed += is_changed as usize;
+            p
+        }).collect();
+        self.motion = 100.*(changed as f32)/(out.len().max(1) as f32);
+        out
+    }
+}
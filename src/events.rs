@@ -1,14 +1,18 @@
 use super::EngineState;
+use overlay::pixel_size;
 use winit::Event;
 use winit::WindowEvent::*;
 use winit;
 
-use png::HasParameters;
+use defects;
+use diff::{self, DiffMode};
+use flat;
+use isp;
+use noise::{Measurement, NoiseView};
+use snapshot;
+use stereo::StereoMode;
 
 use std::sync::atomic::Ordering;
-use std::slice;
-use std::fs::File;
-use std::io::BufWriter;
 
 pub(crate) fn get_dims(state: &EngineState) -> [f32; 2] {
     [
@@ -17,12 +21,127 @@ pub(crate) fn get_dims(state: &EngineState) -> [f32; 2] {
     ]
 }
 
+/// Zoom factor change for a single mouse wheel or keyboard step
+const ZOOM_STEP: f32 = 1.5;
+/// Keyboard panning step as a fraction of the window size
+const PAN_STEP: f32 = 0.1;
+
+/// Set zoom of the active tile so one sensor pixel occupies `ratio` screen
+/// pixels, image center stays in place
+fn set_pixel_ratio(state: &mut EngineState, ratio: f32) {
+    let k = ratio/pixel_size(state, state.tile());
+    state.tile_mut().push_consts.zoom *= k;
+    state.osd.notify(format!("zoom: {:.0}%", 100.*ratio));
+}
+
+/// Move image of the active tile by the given fraction of the tile size
+fn pan(state: &mut EngineState, dx: f32, dy: f32) {
+    let pc = &mut state.tile_mut().push_consts;
+    pc.offset[0] += dx/(pc.aspect[0]*pc.zoom);
+    pc.offset[1] += dy/(pc.aspect[1]*pc.zoom);
+}
+
+/// Make tile with the given index active, maximized view switches to it
+fn select_tile(state: &mut EngineState, i: usize) {
+    if i == state.active { return; }
+    state.active = i;
+    state.recreate_swapchain |= state.maximized;
+    let msg = format!("camera {}: {}", i + 1, state.tile().name);
+    state.osd.notify(msg);
+}
+
+/// Find visible tile under the point given in window pixels
+fn tile_at(state: &EngineState, p: [f32; 2]) -> Option<usize> {
+    state.tiles.iter().position(|t| t.contains(p))
+}
+
+/// Request texture update for all tiles
+fn update_textures(state: &mut EngineState) {
+    for tile in state.tiles.iter_mut() {
+        tile.update_texture = true;
+    }
+}
+
+/// Pin current frames of all cameras as references for the difference
+fn pin_reference(state: &mut EngineState) {
+    for tile in state.tiles.iter_mut() {
+        let guard = tile.cam_mutex.lock().unwrap();
+        tile.diff.pin(&guard);
+    }
+    update_textures(state);
+}
+
+/// Change difference gain by the factor `k`
+fn scale_diff_gain(state: &mut EngineState, k: f32) {
+    state.diff_gain = (state.diff_gain*k).max(1.).min(diff::MAX_GAIN);
+    update_textures(state);
+    let msg = format!("difference gain: x{}", state.diff_gain);
+    state.osd.notify(msg);
+}
+
+/// Start noise measurement of the active camera, a running or finished one
+/// is discarded
+fn start_noise(state: &mut EngineState) {
+    let (frames, active) = (state.noise.frames, state.active);
+    let m = Measurement::start(state.tile(), frames, &state.noise.rois);
+    state.tiles[active].noise = Some(m);
+    state.tiles[active].update_texture = true;
+    let msg = format!("noise measurement: {} frames", frames);
+    state.osd.notify(msg);
+}
+
+/// Export noise maps of the active camera
+fn export_noise(state: &mut EngineState) {
+    let suffix = if state.tiles.len() > 1 {
+        format!("_cam{}", state.active)
+    } else {
+        String::new()
+    };
+    let format = state.noise.format;
+    let msg = match state.tile().noise {
+        Some(ref m) if m.is_done() => match m.export(&suffix, format) {
+            Ok(paths) => format!("Saved: {}", paths.join(", ")),
+            Err(err) => format!("Save failed: {}", err),
+        },
+        Some(_) => "noise measurement is not finished".to_string(),
+        None => "no noise measurement".to_string(),
+    };
+    state.osd.notify(msg);
+}
+
+/// Propagate orientation change to the shaders and recompute aspect ratio
+fn update_orientation(state: &mut EngineState) {
+    let orient = state.orientation.shader_matrix();
+    for tile in state.tiles.iter_mut() {
+        tile.push_consts.orient = orient;
+        tile.push_consts.offset = [0., 0.];
+    }
+    state.recreate_swapchain = true;
+    let msg = state.orientation.name();
+    state.osd.notify(msg);
+}
+
+/// Change grid step, with Shift only X step is changed, with Ctrl only Y
+fn scale_grid_step(
+    state: &mut EngineState, modifiers: winit::ModifiersState, k: f32,
+) {
+    let axes = match (modifiers.shift, modifiers.ctrl) {
+        (true, false) => [true, false],
+        (false, true) => [false, true],
+        _ => [true, true],
+    };
+    let res = state.tile().resolution;
+    state.grid.scale_step(axes, k, res);
+    let step = state.grid.step;
+    state.osd.notify(format!("grid step: {}x{}", step[0], step[1]));
+}
+
 macro_rules! indicate_on_off {
-    ($func:expr, $is_on:expr) => {
+    ($state:expr, $func:expr, $is_on:expr) => {
         if $is_on {
-            println!(concat!($func, ": ON"));
+            $state.osd.notify(concat!($func, ": ON"));
         } else {
-            println!(concat!($func, ": OFF"));
+            $state.osd.notify(concat!($func, ": OFF"));
         }
     }
 }
@@ -40,6 +159,7 @@ pub(crate) fn handle(event: Event, state: &mut EngineState) {
                 input: winit::KeyboardInput {
                     state: winit::ElementState::Pressed,
                     virtual_keycode: Some(keycode),
+                    modifiers,
                     ..
                 }, ..
             } => {
@@ -47,57 +167,364 @@ pub(crate) fn handle(event: Event, state: &mut EngineState) {
                 match keycode {
                     Escape => state.done = true,
                     Space => {
-                        state.pause.fetch_nand(true, Ordering::Relaxed);
+                        let paused = !state.pause
+                            .fetch_nand(true, Ordering::Relaxed);
+                        indicate_on_off!(state, "pause", paused);
+                    },
+                    G if modifiers.shift => {
+                        state.grid.centered = !state.grid.centered;
+                        indicate_on_off!(
+                            state, "centered grid", state.grid.centered
+                        );
+                    },
+                    G if modifiers.ctrl => {
+                        state.grid.labels = !state.grid.labels;
+                        indicate_on_off!(
+                            state, "grid labels", state.grid.labels
+                        );
                     },
                     G => {
                         state.grid_on = !state.grid_on;
-                        indicate_on_off!("grid", state.grid_on);
+                        indicate_on_off!(state, "grid", state.grid_on);
+                    },
+                    LBracket => scale_grid_step(state, modifiers, 0.5),
+                    RBracket => scale_grid_step(state, modifiers, 2.),
+                    C if modifiers.shift => {
+                        state.thirds_on = !state.thirds_on;
+                        indicate_on_off!(
+                            state, "rule of thirds", state.thirds_on
+                        );
+                    },
+                    C => {
+                        state.align_on = !state.align_on;
+                        indicate_on_off!(
+                            state, "alignment overlay", state.align_on
+                        );
+                    },
+                    U => if !state.has_calib {
+                        state.osd.notify("no calibration loaded");
+                    } else if state.active != 0 {
+                        state.osd.notify(
+                            "calibration applies only to the first camera"
+                        );
+                    } else {
+                        let pc = &mut state.tile_mut().push_consts;
+                        let on = pc.undistort == 0;
+                        pc.undistort = on as u32;
+                        indicate_on_off!(state, "undistortion", on);
                     },
                     H => {
                         state.hist_on = !state.hist_on;
-                        indicate_on_off!("histogram", state.hist_on);
+                        indicate_on_off!(state, "histogram", state.hist_on);
+                    },
+                    P => {
+                        state.pixel_grid_on = !state.pixel_grid_on;
+                        indicate_on_off!(
+                            state, "pixel grid", state.pixel_grid_on
+                        );
+                    },
+                    N => {
+                        state.pixel_values_on = !state.pixel_values_on;
+                        indicate_on_off!(
+                            state, "pixel values", state.pixel_values_on
+                        );
+                    },
+                    L => {
+                        state.linear_filter = !state.linear_filter;
+                        indicate_on_off!(
+                            state, "linear filtering", state.linear_filter
+                        );
                     },
                     F => {
-                        state.fps_on = !state.fps_on;
-                        indicate_on_off!("FPS counter", state.fps_on);
+                        state.osd.fps_on = !state.osd.fps_on;
+                        indicate_on_off!(
+                            state, "FPS counter", state.osd.fps_on
+                        );
                     },
-                    R => {
-                        state.push_consts.zoom = 1.0;
-                        state.push_consts.offset = [0., 0.];
+                    O => {
+                        state.osd.on = !state.osd.on;
+                        indicate_on_off!(state, "OSD", state.osd.on);
                     },
-                    S => {
-                        let guard = state.cam_mutex.lock().unwrap();
-
-                        let path = format!("{}.png", guard.ts);
-                        let file = File::create(&path).unwrap();
-
-                        let mut bw = BufWriter::new(file);
-                        let mut encoder = png::Encoder::new(
-                            &mut bw, state.resolution[0], state.resolution[1],
+                    V => {
+                        let is_bayer = state.tiles.iter().any(|t| t.is_bayer);
+                        state.view_mode = state.view_mode.next(is_bayer);
+                        update_textures(state);
+                        let msg = format!("view: {}", state.view_mode.name());
+                        state.osd.notify(msg);
+                    },
+                    A if modifiers.shift => if state.gpu_available {
+                        state.gpu_convert = !state.gpu_convert;
+                        indicate_on_off!(
+                            state, "GPU conversion", state.gpu_convert
+                        );
+                    } else {
+                        state.osd.notify("compute shaders are not supported");
+                    },
+                    A => if let Some(tile) = state.tiles.iter()
+                        .find(|t| t.is_bayer)
+                    {
+                        let algorithm = tile.conversion.algorithm.next();
+                        for tile in state.tiles.iter_mut() {
+                            if tile.is_bayer {
+                                tile.update_conversion(
+                                    |c| c.algorithm = algorithm,
+                                );
+                            }
+                        }
+                        let msg = format!("demosaic: {}", algorithm.name());
+                        state.osd.notify(msg);
+                    } else {
+                        state.osd.notify("demosaic requires raw Bayer camera");
+                    },
+                    Y => if &state.tile().format == b"YUYV" {
+                        let mut yuv = state.tile().conversion.yuv;
+                        if modifiers.shift {
+                            yuv.range = yuv.range.next();
+                        } else {
+                            yuv.matrix = yuv.matrix.next();
+                        }
+                        state.tile_mut().update_conversion(|c| c.yuv = yuv);
+                        state.osd.notify(format!("YUV: {}", yuv.name()));
+                    } else {
+                        state.osd.notify("YUV encoding requires YUYV camera");
+                    },
+                    I => {
+                        let res = if modifiers.shift {
+                            isp::save(state)
+                        } else {
+                            isp::toggle(state)
+                        };
+                        let msg = match res {
+                            Ok(msg) => msg,
+                            Err(err) => format!("Color processing: {}", err),
+                        };
+                        state.osd.notify(msg);
+                    },
+                    W if modifiers.shift => {
+                        state.isp.auto_wb = !state.isp.auto_wb;
+                        indicate_on_off!(
+                            state, "auto white balance", state.isp.auto_wb
                         );
-                        encoder.set(png::BitDepth::Eight);
-                        if state.is_grey {
-                            encoder.set(png::ColorType::Grayscale);
-                            let mut w = encoder.write_header().unwrap();
-                            let data: Vec<u8> = guard.buf
-                                .iter()
-                                .map(|p| p[0])
-                                .collect();
-                            w.write_image_data(&data).unwrap();
+                    },
+                    W => {
+                        state.isp.picker_on = !state.isp.picker_on;
+                        let msg = if state.isp.picker_on {
+                            "white balance: click on a grey area"
                         } else {
-                            encoder.set(png::ColorType::RGB);
-                            let mut w = encoder.write_header().unwrap();
-                            let buf = guard.buf.as_slice();
-                            let data = unsafe {
-                                slice::from_raw_parts(
-                                    buf.as_ptr() as *const u8,
-                                    3*buf.len(),
+                            "white balance: picking canceled"
+                        };
+                        state.osd.notify(msg);
+                    },
+                    R | Key0 => {
+                        let pc = &mut state.tile_mut().push_consts;
+                        pc.zoom = 1.0;
+                        pc.offset = [0., 0.];
+                    },
+                    Key1 => set_pixel_ratio(state, 1.),
+                    Key2 => set_pixel_ratio(state, 2.),
+                    Key3 => set_pixel_ratio(state, 4.),
+                    Key4 => set_pixel_ratio(state, 8.),
+                    Key5 => set_pixel_ratio(state, 16.),
+                    Add | Equals => {
+                        state.tile_mut().push_consts.zoom *= ZOOM_STEP;
+                    },
+                    Subtract | Minus => {
+                        state.tile_mut().push_consts.zoom /= ZOOM_STEP;
+                    },
+                    Left => pan(state, PAN_STEP, 0.),
+                    Right => pan(state, -PAN_STEP, 0.),
+                    Up => pan(state, 0., PAN_STEP),
+                    Down => pan(state, 0., -PAN_STEP),
+                    T => {
+                        state.orientation = if modifiers.shift {
+                            state.orientation.rotate_ccw()
+                        } else {
+                            state.orientation.rotate_cw()
+                        };
+                        update_orientation(state);
+                    },
+                    M => {
+                        state.orientation = if modifiers.shift {
+                            state.orientation.flip_v()
+                        } else {
+                            state.orientation.flip_h()
+                        };
+                        update_orientation(state);
+                    },
+                    K => {
+                        let on = !state.sync.is_displayed();
+                        let sync = &mut state.sync;
+                        match sync.set_display(on, &mut state.tiles) {
+                            Ok(()) => indicate_on_off!(
+                                state, "synchronized display", on
+                            ),
+                            Err(err) => state.osd.notify(err.to_string()),
+                        }
+                    },
+                    S if modifiers.ctrl => {
+                        let format = state.snapshot.format.next();
+                        state.snapshot.format = format;
+                        let msg = format!("save format: {}", format.name());
+                        state.osd.notify(msg);
+                    },
+                    S if modifiers.shift => {
+                        let on = !state.sync.is_recording();
+                        let cameras = state.tiles.iter()
+                            .map(snapshot::Camera::new)
+                            .collect();
+                        let msg = match state.sync.set_recording(on, cameras) {
+                            Ok(()) if on => "recording: ON".to_string(),
+                            Ok(()) => {
+                                let stats = state.sync.stats();
+                                format!(
+                                    "recording: OFF, {} sets saved, {} skipped",
+                                    stats.recorded, stats.record_dropped,
                                 )
-                            };
-                            w.write_image_data(&data).unwrap();
+                            },
+                            Err(err) => format!("Recording failed: {}", err),
+                        };
+                        state.osd.notify(msg);
+                    },
+                    S if state.capture.on => {
+                        let msg = match state.capture.accept() {
+                            Ok(msg) => msg,
+                            Err(err) => format!("Save failed: {}", err),
+                        };
+                        state.osd.notify(msg);
+                    },
+                    Tab => {
+                        // only the stereo pair is shown in stereo modes
+                        let n = match state.stereo {
+                            StereoMode::Off => state.tiles.len(),
+                            _ => 2,
+                        };
+                        let i = if modifiers.shift {
+                            (state.active + n - 1) % n
+                        } else {
+                            (state.active + 1) % n
+                        };
+                        select_tile(state, i);
+                    },
+                    X => if state.tiles.len() < 2 {
+                        state.osd.notify("stereo mode requires two cameras");
+                    } else {
+                        state.stereo = state.stereo.next();
+                        if state.active > 1 { select_tile(state, 0); }
+                        state.recreate_swapchain = true;
+                        let msg = format!("stereo: {}", state.stereo.name());
+                        state.osd.notify(msg);
+                    },
+                    D if modifiers.shift => {
+                        pin_reference(state);
+                        state.diff_mode = DiffMode::Reference;
+                        state.osd.notify("difference: reference pinned");
+                    },
+                    D => {
+                        state.diff_mode = state.diff_mode.next();
+                        let has_ref = state.tiles.iter()
+                            .all(|t| t.diff.has_reference());
+                        match state.diff_mode {
+                            DiffMode::Reference if !has_ref => {
+                                pin_reference(state);
+                            },
+                            DiffMode::Off => {
+                                for tile in state.tiles.iter_mut() {
+                                    tile.diff.clear();
+                                }
+                            },
+                            _ => (),
                         }
-                        println!("Saved: {}", path);
-                    }
+                        update_textures(state);
+                        let msg = format!(
+                            "difference: {}", state.diff_mode.name(),
+                        );
+                        state.osd.notify(msg);
+                    },
+                    F1 => start_noise(state),
+                    F2 => {
+                        state.noise.view = state.noise.view.next();
+                        update_textures(state);
+                        let has_stats = state.tiles.iter().any(|t| {
+                            t.noise.as_ref().map_or(false, |m| m.is_done())
+                        });
+                        let msg = match state.noise.view {
+                            NoiseView::Off => "noise map: off".to_string(),
+                            v if has_stats => {
+                                format!("noise map: {}", v.name())
+                            },
+                            v => format!(
+                                "noise map: {} (no measurement)", v.name(),
+                            ),
+                        };
+                        state.osd.notify(msg);
+                    },
+                    F3 => export_noise(state),
+                    F4 => {
+                        let res = if modifiers.shift {
+                            defects::clear(state)
+                        } else {
+                            defects::detect(state)
+                        };
+                        let msg = match res {
+                            Ok(msg) => msg,
+                            Err(err) => format!("Defects: {}", err),
+                        };
+                        state.osd.notify(msg);
+                    },
+                    F5 => {
+                        state.defects.overlay_on = !state.defects.overlay_on;
+                        let on = state.defects.overlay_on;
+                        indicate_on_off!(state, "defect overlay", on);
+                    },
+                    F6 => {
+                        state.defects.correct_on = !state.defects.correct_on;
+                        defects::apply_correction(state);
+                        let on = state.defects.correct_on;
+                        indicate_on_off!(state, "defect correction", on);
+                    },
+                    F7 => {
+                        let res = if modifiers.shift {
+                            flat::clear(state)
+                        } else {
+                            flat::compute(state)
+                        };
+                        let msg = match res {
+                            Ok(msg) => msg,
+                            Err(err) => format!("Flat field: {}", err),
+                        };
+                        state.osd.notify(msg);
+                    },
+                    F8 => {
+                        state.flat.correct_on = !state.flat.correct_on;
+                        flat::apply_correction(state);
+                        let on = state.flat.correct_on;
+                        indicate_on_off!(state, "flat-field correction", on);
+                    },
+                    PageUp => scale_diff_gain(state, 2.),
+                    PageDown => scale_diff_gain(state, 0.5),
+                    E => {
+                        state.guides_on = !state.guides_on;
+                        indicate_on_off!(state, "guide lines", state.guides_on);
+                    },
+                    Return => {
+                        state.maximized = !state.maximized;
+                        state.recreate_swapchain = true;
+                        indicate_on_off!(state, "maximized", state.maximized);
+                    },
+                    S => if let Err(err) = snapshot::save(state) {
+                        state.osd.notify(format!("Save failed: {}", err));
+                    },
+                    B if modifiers.shift => {
+                        state.capture.auto = !state.capture.auto;
+                        indicate_on_off!(
+                            state, "auto capture", state.capture.auto
+                        );
+                    },
+                    B => {
+                        let on = !state.capture.on;
+                        state.capture.set_on(on);
+                        indicate_on_off!(state, "calibration capture", on);
+                    },
                     _ => (),
                 }
             },
@@ -108,23 +535,29 @@ pub(crate) fn handle(event: Event, state: &mut EngineState) {
             } => {
                 use winit::MouseScrollDelta::*;
 
+                let mouse = state.mouse_coor;
+                let tile = match tile_at(state, mouse) {
+                    Some(i) => &mut state.tiles[i],
+                    None => return,
+                };
+                let zoom = tile.push_consts.zoom;
                 let new_zoom = match delta {
                     LineDelta(_, d) => if d > 0. {
-                        state.push_consts.zoom * 1.5
+                        zoom * ZOOM_STEP
                     } else {
-                        state.push_consts.zoom / 1.5
+                        zoom / ZOOM_STEP
                     },
                     PixelDelta(
                         winit::dpi::LogicalPosition { y, .. }
                     ) => {
-                        state.push_consts.zoom * 1.03f32.powf(y as f32)
+                        zoom * 1.03f32.powf(y as f32)
                     },
                 };
 
-                let dims = get_dims(state);
-                let push_consts = &mut state.push_consts;
-                let xg = 2.*state.mouse_coor[0]/(dims[0] as f32) - 1.;
-                let yg = 2.*state.mouse_coor[1]/(dims[1] as f32) - 1.;
+                let (origin, dims) = (tile.origin, tile.dims);
+                let push_consts = &mut tile.push_consts;
+                let xg = 2.*(mouse[0] - origin[0])/dims[0] - 1.;
+                let yg = 2.*(mouse[1] - origin[1])/dims[1] - 1.;
 
                 let k = (1./new_zoom - 1./push_consts.zoom)/2.;
 
@@ -140,14 +573,16 @@ pub(crate) fn handle(event: Event, state: &mut EngineState) {
                 let y = (state.hidpi*y) as f32;
                 state.mouse_coor = [x, y];
                 if state.lmb_pressed {
-                    let z = state.push_consts.zoom;
-                    let dim = get_dims(state);
                     let ic = state.init_coor;
-                    let k_x = state.push_consts.aspect[0]*(dim[0] as f32)*z;
-                    let k_y = state.push_consts.aspect[1]*(dim[1] as f32)*z;
-                    state.push_consts.offset = [
-                        state.old_offset[0] + (x - ic[0])/k_x,
-                        state.old_offset[1] + (y - ic[1])/k_y,
+                    let old_offset = state.old_offset;
+                    let tile = state.tile_mut();
+                    let z = tile.push_consts.zoom;
+                    let dim = tile.dims;
+                    let k_x = tile.push_consts.aspect[0]*dim[0]*z;
+                    let k_y = tile.push_consts.aspect[1]*dim[1]*z;
+                    tile.push_consts.offset = [
+                        old_offset[0] + (x - ic[0])/k_x,
+                        old_offset[1] + (y - ic[1])/k_y,
                     ];
                 }
             },
@@ -157,8 +592,26 @@ pub(crate) fn handle(event: Event, state: &mut EngineState) {
                 ..
             } => {
                 state.lmb_pressed = mouse_state == winit::ElementState::Pressed;
-                if state.lmb_pressed {
-                    state.old_offset = state.push_consts.offset;
+                if state.lmb_pressed && state.isp.picker_on {
+                    // picking click does not start panning
+                    state.lmb_pressed = false;
+                    let p = state.mouse_coor;
+                    let i = match tile_at(state, p) {
+                        Some(i) => i,
+                        None => return,
+                    };
+                    select_tile(state, i);
+                    state.isp.picker_on = false;
+                    let msg = match isp::pick_grey(state, p) {
+                        Ok(msg) => msg,
+                        Err(err) => format!("White balance: {}", err),
+                    };
+                    state.osd.notify(msg);
+                } else if state.lmb_pressed {
+                    if let Some(i) = tile_at(state, state.mouse_coor) {
+                        select_tile(state, i);
+                    }
+                    state.old_offset = state.tile().push_consts.offset;
                     state.init_coor = state.mouse_coor;
                 }
             },
@@ -0,0 +1,343 @@
+//! Flat-field correction of lens vignetting and color shading
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::EngineState;
+use defects::map_path;
+use parallel;
+use tile::Tile;
+
+/// Size of the gain map cell in sensor pixels
+const CELL: u32 = 32;
+/// Fixed-point precision of the per-sample gains
+const GAIN_BITS: u32 = 12;
+/// Maximal gain, limited by the fixed-point representation
+const MAX_GAIN: f32 = 15.;
+/// Minimal level of the brightest cell of the flat field above black
+const MIN_LEVEL: f32 = 32.;
+/// Level of the brightest cell above which the flat field is considered
+/// overexposed
+const MAX_LEVEL: f32 = 250.;
+
+/// Flat-field correction settings
+pub(crate) struct FlatField {
+    /// Directory with per-camera gain maps
+    pub dir: PathBuf,
+    pub correct_on: bool,
+}
+
+/// Per-channel gains of a single camera sampled at the cell centers
+pub(crate) struct GainMap {
+    pub resolution: [u32; 2],
+    /// Cell size in sensor pixels
+    cell: u32,
+    /// Number of color planes: 4 for the RGGB mosaic, 1 for greyscale and
+    /// 3 for RGB samples
+    planes: usize,
+    /// Gains of the cells, planes are stored one after another, cells of
+    /// a plane are stored row by row
+    gains: Vec<f32>,
+}
+
+/// Color plane of the sample `c` of pixel `[x, y]`
+fn plane(planes: usize, x: usize, y: usize, c: usize) -> usize {
+    match planes {
+        4 => x % 2 + 2*(y % 2),
+        3 => c,
+        _ => 0,
+    }
+}
+
+impl GainMap {
+    /// Number of cells along X and Y axes
+    fn grid(&self) -> (usize, usize) {
+        let cell = self.cell as usize;
+        let [w, h] = self.resolution;
+        ((w as usize + cell - 1)/cell, (h as usize + cell - 1)/cell)
+    }
+
+    /// Number of samples per pixel
+    fn channels(&self) -> usize {
+        if self.planes == 3 { 3 } else { 1 }
+    }
+
+    /// Build map from per-sample temporal mean of a uniformly lit target,
+    /// every plane is normalized to its brightest cell
+    pub fn compute(
+        mean: &[f32], resolution: [u32; 2], planes: usize, black_level: f32,
+    ) -> Result<Self, Box<Error>> {
+        let mut map = GainMap {
+            resolution, cell: CELL, planes, gains: Vec::new(),
+        };
+        let (gw, gh) = map.grid();
+        let nc = map.channels();
+        let w = resolution[0] as usize;
+        let cell = CELL as usize;
+        assert_eq!(mean.len(), w*(resolution[1] as usize)*nc);
+
+        let mut sum = vec![0f64; planes*gw*gh];
+        let mut n = vec![0u32; planes*gw*gh];
+        for (i, &m) in mean.iter().enumerate() {
+            let (x, y) = ((i/nc) % w, (i/nc) / w);
+            let j = (plane(planes, x, y, i % nc)*gh + y/cell)*gw + x/cell;
+            sum[j] += f64::from(m);
+            n[j] += 1;
+        }
+        let levels: Vec<f32> = sum.iter().zip(n.iter())
+            .map(|(&s, &n)| (s/f64::from(n.max(1))) as f32 - black_level)
+            .collect();
+        for levels in levels.chunks(gw*gh) {
+            let max = levels.iter().cloned().fold(0., f32::max);
+            if max < MIN_LEVEL {
+                Err("flat field is too dark")?
+            }
+            if max + black_level > MAX_LEVEL {
+                Err("flat field is overexposed")?
+            }
+            map.gains.extend(
+                levels.iter().map(|&v| (max/v.max(1.)).min(MAX_GAIN)),
+            );
+        }
+        Ok(map)
+    }
+
+    /// Load map from file, returns `None` if file does not exist
+    pub fn load(path: &Path, resolution: [u32; 2])
+        -> Result<Option<Self>, Box<Error>>
+    {
+        if !path.exists() { return Ok(None); }
+
+        let reader = BufReader::new(File::open(path)?);
+        let mut map = GainMap {
+            resolution, cell: 0, planes: 0, gains: Vec::new(),
+        };
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let v: Vec<&str> = line.split_whitespace().collect();
+            match v[..] {
+                ["resolution", res] => {
+                    let [w, h] = resolution;
+                    if res != format!("{}x{}", w, h) {
+                        Err("map resolution does not match the camera")?
+                    }
+                },
+                ["cell", cell] => map.cell = cell.parse()?,
+                ["planes", planes] => map.planes = planes.parse()?,
+                _ if map.cell > 0 && map.planes > 0 => for s in v.iter() {
+                    let k: f32 = s.parse()?;
+                    if !(k > 0. && k <= MAX_GAIN) {
+                        Err(format!("gains must be in (0, {}]", MAX_GAIN))?
+                    }
+                    map.gains.push(k);
+                },
+                _ => Err(format!("invalid gain map line: {}", line))?,
+            }
+        }
+        if map.cell == 0 || ![1, 3, 4].contains(&map.planes) {
+            Err("map must specify cell size and 1, 3 or 4 planes")?
+        }
+        let (gw, gh) = map.grid();
+        if map.gains.len() != map.planes*gw*gh {
+            Err("number of gains does not match the map size")?
+        }
+        Ok(Some(map))
+    }
+
+    pub fn save(&self, path: &Path, camera: &str) -> Result<(), Box<Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut bw = BufWriter::new(File::create(path)?);
+        writeln!(
+            bw, "# flat-field gains of {}: cell rows of every plane", camera,
+        )?;
+        let res = self.resolution;
+        writeln!(bw, "resolution {}x{}", res[0], res[1])?;
+        writeln!(bw, "cell {}", self.cell)?;
+        writeln!(bw, "planes {}", self.planes)?;
+        let (gw, _) = self.grid();
+        for row in self.gains.chunks(gw) {
+            let row: Vec<String> = row.iter()
+                .map(|k| format!("{:.4}", k))
+                .collect();
+            writeln!(bw, "{}", row.join(" "))?;
+        }
+        bw.flush()?;
+        Ok(())
+    }
+
+    pub fn max_gain(&self) -> f32 {
+        self.gains.iter().cloned().fold(0., f32::max)
+    }
+
+    /// Fixed-point gains of all frame samples interpolated bi-linearly
+    /// between the cell centers and extrapolated linearly near the image
+    /// border, where vignetting changes fastest
+    pub fn table(&self) -> Vec<u16> {
+        let [w, h] = self.resolution;
+        let (w, h) = (w as usize, h as usize);
+        let (gw, gh) = self.grid();
+        let nc = self.channels();
+        let cell = self.cell as f32;
+        // indices of the neighboring cells and weight of the second one
+        let axis = |v: usize, n: usize| {
+            let t = (v as f32 + 0.5)/cell - 0.5;
+            let i = (t.max(0.) as usize).min(n.saturating_sub(2));
+            let f = if n > 1 { t - i as f32 } else { 0. };
+            (i, (i + 1).min(n - 1), f)
+        };
+        let xs: Vec<_> = (0..w).map(|x| axis(x, gw)).collect();
+        let scale = (1 << GAIN_BITS) as f32;
+        let mut out = Vec::with_capacity(w*h*nc);
+        for y in 0..h {
+            let (y0, y1, fy) = axis(y, gh);
+            for (x, &(x0, x1, fx)) in xs.iter().enumerate() {
+                for c in 0..nc {
+                    let g = &self.gains[plane(self.planes, x, y, c)*gw*gh..];
+                    let lerp = |row: usize| {
+                        g[row*gw + x0]*(1. - fx) + g[row*gw + x1]*fx
+                    };
+                    let k = lerp(y0)*(1. - fy) + lerp(y1)*fy;
+                    let k = k.max(0.).min(MAX_GAIN);
+                    out.push((k*scale).round() as u16);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Check if frames of the format are corrected before conversion, other
+/// formats are corrected after conversion into RGB
+pub fn corrects_raw(format: [u8; 4]) -> bool {
+    &format == b"RGGB" || &format == b"GREY"
+}
+
+#[inline(always)]
+fn scale(v: &mut u8, k: u16, black: i32) {
+    let s = (i32::from(*v) - black)*i32::from(k) + (1 << (GAIN_BITS - 1));
+    *v = ((s >> GAIN_BITS) + black).clamp(0, 255) as u8;
+}
+
+/// Multiply raw samples above `black` level by the fixed-point gains, raw
+/// frame consists of `rows` rows
+pub fn apply(raw: &mut [u8], gains: &[u16], rows: usize, black: i32) {
+    assert_eq!(raw.len(), gains.len());
+    let w = raw.len()/rows;
+    parallel::for_each_band(raw, rows, 1, |y0, band| {
+        for (v, &k) in band.iter_mut().zip(gains[w*y0..].iter()) {
+            scale(v, k, black);
+        }
+    });
+}
+
+/// Multiply channels of the RGB image consisting of `rows` rows by the
+/// fixed-point gains
+pub fn apply_rgb(buf: &mut [[u8; 3]], gains: &[u16], rows: usize) {
+    assert_eq!(3*buf.len(), gains.len());
+    let w = buf.len()/rows;
+    parallel::for_each_band(buf, rows, 1, |y0, band| {
+        let samples = band.iter_mut().flat_map(|px| px.iter_mut());
+        for (v, &k) in samples.zip(gains[3*w*y0..].iter()) {
+            scale(v, k, 0);
+        }
+    });
+}
+
+/// Path to the gain map of the camera, maps are stored per camera and
+/// resolution, e.g. `<dir>/dev_video0_1920x1080.txt`
+fn gains_path(dir: &Path, tile: &Tile) -> PathBuf {
+    let [w, h] = tile.resolution;
+    map_path(dir, &format!("{}_{}x{}", tile.name, w, h))
+}
+
+/// Number of gain map planes of the tile camera
+fn planes(tile: &Tile) -> usize {
+    if tile.is_bayer {
+        4
+    } else if tile.is_grey {
+        1
+    } else {
+        3
+    }
+}
+
+/// Load gain map of the tile camera from the directory, camera is left
+/// without a map if it can not be loaded
+pub(crate) fn load(tile: &mut Tile, dir: &Path) -> Result<(), Box<Error>> {
+    let path = gains_path(dir, tile);
+    tile.flat = None;
+    let map = GainMap::load(&path, tile.resolution)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if map.as_ref().map_or(false, |m| m.planes != planes(tile)) {
+        Err(format!("{}: map does not match camera format", path.display()))?
+    }
+    tile.flat = map;
+    Ok(())
+}
+
+/// Update gains used by the camera workers for correction, the current
+/// frames are converted again
+pub(crate) fn apply_correction(state: &mut EngineState) {
+    let on = state.flat.correct_on;
+    for tile in state.tiles.iter_mut() {
+        let gains = match tile.flat {
+            Some(ref map) if on => Some(Arc::new(map.table())),
+            _ => None,
+        };
+        *tile.gains.lock().unwrap() = gains.clone();
+        // displayed frame may come from the synchronizer, so the worker
+        // receives gains separately
+        let mut guard = tile.cam_mutex.lock().unwrap();
+        guard.flat = gains;
+        guard.converted = false;
+        tile.update_texture = true;
+    }
+}
+
+/// Compute gain map of the active camera from its finished noise
+/// measurement of a uniformly lit target and save it
+pub(crate) fn compute(state: &mut EngineState) -> Result<String, Box<Error>> {
+    let map = {
+        let tile = state.tile();
+        let mean = match tile.noise {
+            Some(ref m) => match m.stats() {
+                Some((mean, _)) => mean,
+                None => Err("noise measurement is not finished")?,
+            },
+            None => Err("flat field requires noise measurement")?,
+        };
+        // gains of corrected frames are close to one and would replace
+        // the map which has corrected them
+        if tile.noise.as_ref().map_or(false, |m| m.flat_corrected()) {
+            Err("noise was measured with flat-field correction on, \
+                turn it off and measure again")?
+        }
+        let black_level = tile.conversion.isp.black_level;
+        GainMap::compute(mean, tile.resolution, planes(tile), black_level)?
+    };
+    let path = gains_path(&state.flat.dir, state.tile());
+    map.save(&path, &state.tile().name)?;
+    let max_gain = map.max_gain();
+    state.tile_mut().flat = Some(map);
+    apply_correction(state);
+    Ok(format!(
+        "flat field: max gain x{:.2}, saved to {}", max_gain, path.display(),
+    ))
+}
+
+/// Remove gain map of the active camera
+pub(crate) fn clear(state: &mut EngineState) -> Result<String, Box<Error>> {
+    let path = gains_path(&state.flat.dir, state.tile());
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    state.tile_mut().flat = None;
+    apply_correction(state);
+    Ok(format!("flat field removed: {}", path.display()))
+}
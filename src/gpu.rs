@@ -0,0 +1,94 @@
+use super::EngineState;
+use demosaic::Conversion;
+use diff::DiffMode;
+use flat;
+use noise::NoiseView;
+use rggb::Algorithm;
+use view::ViewMode;
+
+/// Size of the conversion shader work group along X and Y axes
+pub(crate) const GROUP_SIZE: u32 = 16;
+
+/// Push constants of the conversion compute shader
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ConvertParams {
+    pub width: u32,
+    pub height: u32,
+    pub format: u32,
+    pub algorithm: u32,
+    /// Fixed-point YUV conversion coefficients, see `yuv::Coeffs`
+    pub y_offset: i32,
+    pub y_scale: i32,
+    pub yuv_k: [i32; 4],
+}
+
+impl ConvertParams {
+    /// Parameters for the frame format, `None` if format is not supported
+    /// by the shader
+    pub fn new(format: [u8; 4], res: [u32; 2], conv: &Conversion)
+        -> Option<Self>
+    {
+        let format = match &format {
+            b"GREY" => 0,
+            b"RGGB" => 1,
+            b"YUYV" => 2,
+            b"RGB3" => 3,
+            b"BGR3" => 4,
+            _ => return None,
+        };
+        let algorithm = match conv.algorithm {
+            Algorithm::Bilinear => 0,
+            Algorithm::Malvar => 1,
+        };
+        let yuv = conv.yuv.coeffs();
+        Some(ConvertParams {
+            width: res[0], height: res[1], format, algorithm,
+            y_offset: yuv.y_offset, y_scale: yuv.y_scale, yuv_k: yuv.k,
+        })
+    }
+
+    /// Number of work groups covering the frame
+    pub fn groups(&self) -> [u32; 3] {
+        let n = |v: u32| (v + GROUP_SIZE - 1)/GROUP_SIZE;
+        [n(self.width), n(self.height), 1]
+    }
+}
+
+/// Raw frame data padded to whole 32-bit words read by the shader
+pub(crate) fn padded(raw: &[u8]) -> Vec<u8> {
+    let mut data = raw.to_vec();
+    data.resize((raw.len() + 3)/4*4, 0);
+    data
+}
+
+/// Check if texture of the tile can be filled by the conversion shader,
+/// i.e. the plain color image is shown, color processing of raw frames and
+/// flat-field correction of converted frames are done only on CPU
+pub(crate) fn displays_raw(state: &EngineState, i: usize) -> bool {
+    let tile = &state.tiles[i];
+    let noise_shown = state.noise.view != NoiseView::Off
+        && tile.noise.as_ref().map_or(false, |m| m.is_done());
+    let rgb_flat = state.flat.correct_on && tile.flat.is_some()
+        && !flat::corrects_raw(tile.format);
+    state.gpu_convert
+        && state.view_mode.for_source(tile.is_bayer) == ViewMode::Color
+        && state.diff_mode == DiffMode::Off
+        && !noise_shown
+        && !rgb_flat
+        && !tile.conversion.isp.enabled
+}
+
+/// Check if camera worker has to convert frames into RGB, which is needed
+/// for CPU display and for everything that processes or saves frames
+pub(crate) fn needs_cpu(state: &EngineState, i: usize) -> bool {
+    let tile = &state.tiles[i];
+    let rgb_noise = !tile.is_bayer && !tile.is_grey
+        && tile.noise.as_ref().map_or(false, |m| !m.is_done());
+    !displays_raw(state, i)
+        || state.hist_on
+        || state.pixel_values_on
+        || state.sync.records_converted()
+        || (i == 0 && state.capture.on)
+        || rgb_noise
+}
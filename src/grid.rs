@@ -0,0 +1,135 @@
+use super::{ColorVertex, EngineState};
+use text;
+use tile::Tile;
+
+/// Grid overlay settings
+pub(crate) struct Grid {
+    /// Distance between lines along X and Y axes in sensor pixels
+    pub step: [u32; 2],
+    /// Draw lines relative to the image center instead of the top left corner
+    pub centered: bool,
+    /// Show coordinates near the lines
+    pub labels: bool,
+    /// RGBA color of lines and labels
+    pub color: [f32; 4],
+}
+
+impl Grid {
+    /// Create grid settings, zero steps are replaced with 1 pixel
+    pub fn new(
+        step: [u32; 2], centered: bool, labels: bool, color: [f32; 4],
+    ) -> Self {
+        let step = [step[0].max(1), step[1].max(1)];
+        Grid { step, centered, labels, color }
+    }
+
+    /// Get origin of the grid in sensor pixels
+    fn origin(&self, res: [u32; 2]) -> [u32; 2] {
+        if self.centered { [res[0]/2, res[1]/2] } else { [0, 0] }
+    }
+
+    /// Get coordinates of the grid lines along one axis, lines lying
+    /// on the image border are skipped
+    fn positions(&self, axis: usize, res: [u32; 2]) -> Vec<u32> {
+        let step = self.step[axis];
+        let origin = self.origin(res)[axis];
+        let first = origin % step;
+        (first..res[axis])
+            .step_by(step as usize)
+            .filter(|&v| v != 0)
+            .collect()
+    }
+
+    /// Build line list in normalized sensor coordinates
+    pub fn lines(&self, res: [u32; 2]) -> Vec<[f32; 2]> {
+        let mut lines = Vec::new();
+
+        let w_f32 = (res[0] as f32)/2.;
+        for i in self.positions(0, res) {
+            let c = (i as f32)/w_f32 - 1.;
+            lines.extend_from_slice(&[[c, -1.], [c, 1.]]);
+        }
+
+        let h_f32 = (res[1] as f32)/2.;
+        for i in self.positions(1, res) {
+            let c = (i as f32)/h_f32 - 1.;
+            lines.extend_from_slice(&[[-1., c], [1., c]]);
+        }
+        lines
+    }
+
+    /// Multiply step along the selected axes by `k`, step is kept in the
+    /// range from 1 to image size
+    pub fn scale_step(&mut self, axes: [bool; 2], k: f32, res: [u32; 2]) {
+        for i in 0..2 {
+            if !axes[i] { continue; }
+            let step = ((self.step[i] as f32)*k).round() as u32;
+            self.step[i] = step.max(1).min(res[i]);
+        }
+    }
+}
+
+/// Parse grid step in pixels
+pub(crate) fn parse_step(s: &str) -> Result<u32, &'static str> {
+    match s.parse::<u32>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err("grid step must be a positive number of pixels"),
+    }
+}
+
+/// Parse color in the `RRGGBB` or `RRGGBBAA` hex format
+pub(crate) fn parse_color(s: &str) -> Result<[f32; 4], &'static str> {
+    let err = "color must be in the RRGGBB or RRGGBBAA format";
+    if (s.len() != 6 && s.len() != 8) || !s.is_ascii() { Err(err)? }
+    let mut color = [1.; 4];
+    for (i, c) in color.iter_mut().enumerate().take(s.len()/2) {
+        let v = u8::from_str_radix(&s[2*i..2*i + 2], 16).map_err(|_| err)?;
+        *c = (v as f32)/255.;
+    }
+    Ok(color)
+}
+
+/// Build coordinate labels for the visible grid lines, labels are placed
+/// at the line ends and kept inside the tile
+pub(crate) fn labels(state: &EngineState, tile: &Tile) -> Vec<ColorVertex> {
+    let mut out = Vec::new();
+    let grid = &state.grid;
+    let res = tile.resolution;
+    let pc = &tile.push_consts;
+    let dims = tile.dims;
+    let scale = (state.hidpi as f32).max(1.).round();
+    let color = [grid.color[0], grid.color[1], grid.color[2], 1.];
+    let origin = grid.origin(res);
+
+    let to_screen = |p: [f32; 2]| {
+        let c = pc.to_ndc(p, res);
+        [(c[0] + 1.)*dims[0]/2., (c[1] + 1.)*dims[1]/2.]
+    };
+
+    for axis in 0..2 {
+        for v in grid.positions(axis, res) {
+            let (p, w, h) = (v as f32, res[0] as f32, res[1] as f32);
+            let (a, b) = if axis == 0 {
+                (to_screen([p, 0.]), to_screen([p, h]))
+            } else {
+                (to_screen([0., p]), to_screen([w, p]))
+            };
+            let label = (v as i64 - origin[axis] as i64).to_string();
+            let [tw, th] = text::text_size(&label, scale);
+
+            // depending on orientation sensor line can be vertical
+            // or horizontal on the screen
+            let pos = if (a[0] - b[0]).abs() < (a[1] - b[1]).abs() {
+                if a[0] < 0. || a[0] > dims[0] { continue; }
+                let y = a[1].min(b[1]).max(0.).min(dims[1] - th);
+                [a[0] + 2.*scale, y + 2.*scale]
+            } else {
+                if a[1] < 0. || a[1] > dims[1] { continue; }
+                let x = a[0].min(b[0]).max(0.).min(dims[0] - tw);
+                [x + 2.*scale, a[1] + 2.*scale]
+            };
+            text::draw(&mut out, &label, pos, scale, color, dims);
+        }
+    }
+    out
+}
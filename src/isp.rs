@@ -0,0 +1,412 @@
+//! Color processing of demosaiced raw Bayer frames: black level subtraction,
+//! white balance, color correction matrix and tone curve
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::EngineState;
+use defects::map_path;
+use parallel;
+use tile::Tile;
+
+/// Fixed-point precision of the linear values between processing steps
+const LIN_BITS: i32 = 12;
+/// Fixed-point precision of the color correction matrix
+const CCM_BITS: i32 = 10;
+/// Maximal white balance gain, also limits fixed-point values
+const MAX_GAIN: f32 = 16.;
+/// Maximal absolute value of the color correction matrix coefficients
+const MAX_CCM: f32 = 8.;
+/// Linear sRGB to CIE XYZ matrix, white point is D65
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.4124, 0.3576, 0.1805],
+    [0.2126, 0.7152, 0.0722],
+    [0.0193, 0.1192, 0.9505],
+];
+/// Half size of the grey picker patch in sensor pixels
+const PICKER_SIZE: u32 = 8;
+/// Distance between sampled 2x2 cells of the grey world statistics
+const AUTO_WB_STEP: usize = 4;
+/// Fraction of the grey world gain change applied on every update,
+/// smooths out noise of the statistics
+const AUTO_WB_RATE: f32 = 0.1;
+
+/// Color processing settings
+pub(crate) struct Settings {
+    /// Directory with per-camera processing profiles
+    pub dir: PathBuf,
+    /// Next click on the image sets white balance from the clicked area
+    pub picker_on: bool,
+    /// Continuously estimate white balance using the grey world assumption
+    pub auto_wb: bool,
+}
+
+/// Parameters of the processing applied after demosaicing
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Params {
+    pub enabled: bool,
+    /// Raw level of black subtracted from all samples
+    pub black_level: f32,
+    /// White balance gains of red, green and blue channels
+    pub wb: [f32; 3],
+    /// Color correction matrix applied to white balanced linear RGB
+    pub ccm: [[f32; 3]; 3],
+    /// Gamma of the tone curve, linear value `v` is shown as `v^(1/gamma)`
+    pub gamma: f32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            enabled: false,
+            black_level: 0.,
+            wb: [1., 1., 1.],
+            ccm: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            gamma: 2.2,
+        }
+    }
+}
+
+impl Params {
+    /// Load parameters from profile file, returns disabled default
+    /// parameters if file does not exist
+    pub fn load(path: &Path) -> Result<Self, Box<Error>> {
+        let mut p = Params::default();
+        if !path.exists() { return Ok(p); }
+
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let mut iter = line.split_whitespace();
+            let key = iter.next().unwrap_or("");
+            let v = iter.map(|s| s.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()?;
+            match (key, &v[..]) {
+                ("black_level", &[b]) if (0. ..255.).contains(&b) => {
+                    p.black_level = b;
+                },
+                ("wb", &[r, g, b]) => {
+                    let gains = [r, g, b];
+                    if gains.iter().any(|&k| !(k > 0. && k <= MAX_GAIN)) {
+                        Err(format!(
+                            "white balance gains must be in (0, {}]",
+                            MAX_GAIN,
+                        ))?
+                    }
+                    p.wb = gains;
+                },
+                ("ccm", m) if m.len() == 9 => {
+                    if m.iter().any(|k| k.is_nan() || k.abs() > MAX_CCM) {
+                        Err(format!(
+                            "color matrix coefficients must be in [-{0}, {0}]",
+                            MAX_CCM,
+                        ))?
+                    }
+                    for (i, row) in p.ccm.iter_mut().enumerate() {
+                        row.copy_from_slice(&m[3*i..3*i + 3]);
+                    }
+                },
+                ("gamma", &[g]) if g > 0. => p.gamma = g,
+                _ => Err(format!("invalid profile line: {}", line))?,
+            }
+        }
+        Ok(p)
+    }
+
+    pub fn save(&self, path: &Path, camera: &str) -> Result<(), Box<Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut bw = BufWriter::new(File::create(path)?);
+        writeln!(bw, "# color processing profile of {}", camera)?;
+        writeln!(bw, "black_level {}", self.black_level)?;
+        let wb = self.wb;
+        writeln!(bw, "wb {} {} {}", wb[0], wb[1], wb[2])?;
+        write!(bw, "ccm")?;
+        for v in self.ccm.iter().flat_map(|row| row.iter()) {
+            write!(bw, " {}", v)?;
+        }
+        writeln!(bw)?;
+        writeln!(bw, "gamma {}", self.gamma)?;
+        bw.flush()?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        if !self.enabled { return "off".to_string(); }
+        let wb = self.wb;
+        format!(
+            "black {}, WB {:.2} {:.2} {:.2}, gamma {}",
+            self.black_level, wb[0], wb[1], wb[2], self.gamma,
+        )
+    }
+
+    /// Matrix converting CIE XYZ into raw camera RGB, inverse of the white
+    /// balance followed by the color correction into linear sRGB, together
+    /// with the camera RGB of the D65 white. Matrix is scaled so the white
+    /// has maximal component of 1, `None` if the transform is singular.
+    pub fn xyz_to_camera(&self) -> Option<([[f32; 3]; 3], [f32; 3])> {
+        let mut cam_to_xyz = [[0.; 3]; 3];
+        for (i, row) in cam_to_xyz.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                let s = SRGB_TO_XYZ[i];
+                let c = (0..3).map(|k| s[k]*self.ccm[k][j]).sum::<f32>();
+                *v = c*self.wb[j];
+            }
+        }
+        let mut m = invert(cam_to_xyz)?;
+        let white = [
+            SRGB_TO_XYZ[0].iter().sum::<f32>(), 1.,
+            SRGB_TO_XYZ[2].iter().sum::<f32>(),
+        ];
+        let mut neutral = [0.; 3];
+        for (n, row) in neutral.iter_mut().zip(m.iter()) {
+            *n = (0..3).map(|k| row[k]*white[k]).sum();
+        }
+        if neutral.iter().any(|&v| v.is_nan() || v <= 0.) {
+            return None;
+        }
+        let max = neutral.iter().cloned().fold(0., f32::max);
+        for v in m.iter_mut().flat_map(|row| row.iter_mut()) {
+            *v /= max;
+        }
+        for v in neutral.iter_mut() {
+            *v /= max;
+        }
+        Some((m, neutral))
+    }
+}
+
+/// Inverse of 3x3 matrix, `None` if it is singular
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    // cofactors of the transposed matrix
+    let c = |i: usize, j: usize| {
+        let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+        let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+        m[r0][c0]*m[r1][c1] - m[r0][c1]*m[r1][c0]
+    };
+    let det = (0..3).map(|k| m[0][k]*c(k, 0)).sum::<f32>();
+    if det.abs() < 1e-6 { return None; }
+    let mut out = [[0.; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = c(i, j)/det;
+        }
+    }
+    Some(out)
+}
+
+/// Processing parameters prepared for the per-pixel application, black
+/// level, gains and tone curve are stored as lookup tables
+pub struct Pipeline {
+    /// Fixed-point white balanced linear value of every level per channel
+    lin: [[i32; 256]; 3],
+    /// Fixed-point color correction matrix
+    ccm: [[i32; 3]; 3],
+    /// Output level of every fixed-point linear value
+    tone: Vec<u8>,
+}
+
+impl Pipeline {
+    pub fn new(p: &Params) -> Self {
+        let max = ((1 << LIN_BITS) - 1) as f32;
+        let range = 255. - p.black_level;
+        let mut lin = [[0; 256]; 3];
+        for (lut, &gain) in lin.iter_mut().zip(p.wb.iter()) {
+            for (level, v) in lut.iter_mut().enumerate() {
+                let l = ((level as f32) - p.black_level).max(0.)/range;
+                *v = (l*gain*max).round() as i32;
+            }
+        }
+        let k = (1 << CCM_BITS) as f32;
+        let mut ccm = [[0; 3]; 3];
+        for (row, src) in ccm.iter_mut().zip(p.ccm.iter()) {
+            for (v, &c) in row.iter_mut().zip(src.iter()) {
+                *v = (c*k).round() as i32;
+            }
+        }
+        let tone = (0..1 << LIN_BITS)
+            .map(|v| (255.*((v as f32)/max).powf(1./p.gamma)).round() as u8)
+            .collect();
+        Pipeline { lin, ccm, tone }
+    }
+
+    #[inline(always)]
+    fn pixel(&self, px: [u8; 3]) -> [u8; 3] {
+        let lin = &self.lin;
+        let v = [
+            lin[0][px[0] as usize], lin[1][px[1] as usize],
+            lin[2][px[2] as usize],
+        ];
+        let max = (1 << LIN_BITS) - 1;
+        let mut out = [0; 3];
+        for (o, k) in out.iter_mut().zip(self.ccm.iter()) {
+            let s = k[0]*v[0] + k[1]*v[1] + k[2]*v[2] + (1 << (CCM_BITS - 1));
+            *o = self.tone[(s >> CCM_BITS).max(0).min(max) as usize];
+        }
+        out
+    }
+
+    /// Process RGB image consisting of `rows` rows in place
+    pub fn apply(&self, buf: &mut [[u8; 3]], rows: usize) {
+        parallel::for_each_band(buf, rows, 1, |_, band| {
+            for px in band.iter_mut() {
+                *px = self.pixel(*px);
+            }
+        });
+    }
+}
+
+/// Mean raw levels of red, green and blue samples of RGGB frame inside
+/// the rectangle `[x0, y0]..[x1, y1]` with even corners, every `step`-th
+/// cell of 2x2 samples is used, cells with saturated samples are skipped
+fn channel_means(
+    raw: &[u8], res: [u32; 2], a: [u32; 2], b: [u32; 2], step: usize,
+) -> Option<[f32; 3]> {
+    let w = res[0] as usize;
+    let mut sum = [0u32; 3];
+    let mut n = 0u32;
+    for y in (a[1] as usize..b[1] as usize).step_by(2*step) {
+        let rows = (&raw[y*w..][..w], &raw[(y + 1)*w..][..w]);
+        for x in (a[0] as usize..b[0] as usize).step_by(2*step) {
+            let s = [rows.0[x], rows.0[x + 1], rows.1[x], rows.1[x + 1]];
+            if s.contains(&255) { continue; }
+            sum[0] += u32::from(s[0]);
+            sum[1] += u32::from(s[1]) + u32::from(s[2]);
+            sum[2] += u32::from(s[3]);
+            n += 1;
+        }
+    }
+    if n == 0 { return None; }
+    let n = n as f32;
+    Some([sum[0] as f32/n, sum[1] as f32/(2.*n), sum[2] as f32/n])
+}
+
+/// White balance gains making the area with the given mean raw levels grey
+fn grey_gains(means: [f32; 3], black_level: f32) -> Option<[f32; 3]> {
+    let m = [
+        means[0] - black_level, means[1] - black_level,
+        means[2] - black_level,
+    ];
+    if m.iter().any(|&v| v < 1.) { return None; }
+    let gain = |v: f32| (m[1]/v).clamp(1./MAX_GAIN, MAX_GAIN);
+    Some([gain(m[0]), 1., gain(m[2])])
+}
+
+/// Load processing profile of the camera, processing is enabled if `on`,
+/// default parameters are used if the profile can not be loaded
+pub(crate) fn load(tile: &mut Tile, dir: &Path, on: bool)
+    -> Result<(), Box<Error>>
+{
+    if !tile.is_bayer { return Ok(()); }
+    let path = map_path(dir, &tile.name);
+    let (mut params, res) = match Params::load(&path) {
+        Ok(params) => (params, Ok(())),
+        Err(err) => {
+            let err = format!("{}: {}", path.display(), err);
+            (Params::default(), Err(err.into()))
+        },
+    };
+    params.enabled = on;
+    tile.set_conversion(|c| c.isp = params);
+    res
+}
+
+/// Turn processing on or off for all raw Bayer cameras
+pub(crate) fn toggle(state: &mut EngineState) -> Result<String, Box<Error>> {
+    let on = match state.tiles.iter().find(|t| t.is_bayer) {
+        Some(tile) => !tile.conversion.isp.enabled,
+        None => Err("color processing requires raw Bayer camera")?,
+    };
+    for tile in state.tiles.iter_mut().filter(|t| t.is_bayer) {
+        tile.update_conversion(|c| c.isp.enabled = on);
+    }
+    Ok(format!("color processing: {}", if on { "ON" } else { "OFF" }))
+}
+
+/// Save processing profile of the active camera
+pub(crate) fn save(state: &EngineState) -> Result<String, Box<Error>> {
+    let tile = state.tile();
+    if !tile.is_bayer {
+        Err("color processing requires raw Bayer camera")?
+    }
+    let path = map_path(&state.isp.dir, &tile.name);
+    let params = tile.conversion.isp;
+    params.save(&path, &tile.name)?;
+    Ok(format!("color profile saved to {}", path.display()))
+}
+
+/// Set white balance of the active camera from the area around point `p`
+/// given in window pixels, grey world estimation is stopped
+pub(crate) fn pick_grey(state: &mut EngineState, p: [f32; 2])
+    -> Result<String, Box<Error>>
+{
+    let gains = {
+        let tile = state.tile();
+        if !tile.is_bayer {
+            Err("white balance requires raw Bayer camera")?
+        }
+        let ndc = [
+            2.*(p[0] - tile.origin[0])/tile.dims[0] - 1.,
+            2.*(p[1] - tile.origin[1])/tile.dims[1] - 1.,
+        ];
+        let res = tile.resolution;
+        let s = tile.push_consts.from_ndc(ndc, res);
+        if s[0] < 0. || s[1] < 0.
+            || s[0] >= res[0] as f32 || s[1] >= res[1] as f32
+        {
+            Err("point is outside of the image")?
+        }
+        let (x, y) = (s[0] as u32, s[1] as u32);
+        let a = [
+            x.saturating_sub(PICKER_SIZE) & !1,
+            y.saturating_sub(PICKER_SIZE) & !1,
+        ];
+        let b = [
+            (x + PICKER_SIZE).min(res[0]) & !1,
+            (y + PICKER_SIZE).min(res[1]) & !1,
+        ];
+        let black_level = tile.conversion.isp.black_level;
+        let guard = tile.cam_mutex.lock().unwrap();
+        match channel_means(&guard.raw, res, a, b, 1)
+            .and_then(|m| grey_gains(m, black_level))
+        {
+            Some(gains) => gains,
+            None => Err("picked area is too dark or saturated")?,
+        }
+    };
+    state.isp.auto_wb = false;
+    state.tile_mut().update_conversion(|c| c.isp.wb = gains);
+    Ok(format!(
+        "white balance: {:.2} {:.2} {:.2}", gains[0], gains[1], gains[2],
+    ))
+}
+
+/// Update white balance of the processed raw Bayer cameras using the grey
+/// world assumption if automatic white balance is on
+pub(crate) fn update(state: &mut EngineState) {
+    if !state.isp.auto_wb { return; }
+    for tile in state.tiles.iter_mut().filter(|t| t.is_bayer) {
+        let params = tile.conversion.isp;
+        if !params.enabled { continue; }
+        let res = tile.resolution;
+        let end = [res[0] & !1, res[1] & !1];
+        let means = {
+            let guard = tile.cam_mutex.lock().unwrap();
+            channel_means(&guard.raw, res, [0, 0], end, AUTO_WB_STEP)
+        };
+        let black_level = params.black_level;
+        let gains = match means.and_then(|m| grey_gains(m, black_level)) {
+            Some(gains) => gains,
+            None => continue,
+        };
+        tile.set_conversion(|c| {
+            for (wb, g) in c.isp.wb.iter_mut().zip(gains.iter()) {
+                *wb += AUTO_WB_RATE*(g - *wb);
+            }
+        });
+    }
+}
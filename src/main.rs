@@ -13,9 +13,11 @@ use vulkano::sync::GpuFuture;
 use vulkano::framebuffer::Subpass;
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
 use vulkano::framebuffer::Framebuffer;
-use vulkano::buffer::{CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool};
+use vulkano::buffer::DeviceLocalBuffer;
 use vulkano::image::Dimensions;
 use vulkano::image::StorageImage;
+use vulkano::image::ImmutableImage;
 use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::swapchain::acquire_next_image;
 use vulkano::swapchain::Swapchain;
@@ -24,9 +26,11 @@ use vulkano::pipeline::viewport::Viewport;
 use vulkano::device::Device;
 use vulkano::command_buffer::DynamicState;
 use vulkano::image::ImageUsage;
+use vulkano::pipeline::ComputePipeline;
 
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
 use std::str;
 use std::time::{Instant, Duration};
 
@@ -36,9 +40,39 @@ const DEFAULT_DIMENSIONS: [u32; 2] = [2448/4, 2048/4];
 
 mod cam;
 mod demosaic;
+mod yuv;
+mod v4l2;
+mod isp;
 mod rggb;
 mod cli;
 mod events;
+mod view;
+mod text;
+mod overlay;
+mod osd;
+mod orientation;
+mod grid;
+mod reticle;
+mod calib;
+mod chessboard;
+mod capture;
+mod calibrate;
+mod bench;
+mod parallel;
+#[cfg(target_arch = "x86_64")]
+mod simd;
+mod save;
+mod snapshot;
+mod tile;
+mod stereo;
+mod sync;
+mod diff;
+mod noise;
+mod defects;
+mod flat;
+mod gpu;
+#[cfg(test)]
+mod testutil;
 
 mod shaders;
 
@@ -46,46 +80,152 @@ mod shaders;
 struct Vertex { position: [f32; 2] }
 impl_vertex!(Vertex, position);
 
+#[derive(Debug, Clone)]
+struct ColorVertex { position: [f32; 2], color: [f32; 4] }
+impl_vertex!(ColorVertex, position, color);
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct PushConstant {
     aspect: [f32; 2],
     offset: [f32; 2],
+    /// Column-major matrix which converts display coordinates into sensor
+    /// coordinates, see `Orientation::shader_matrix`
+    orient: [[f32; 2]; 2],
     zoom: f32,
+    _pad: [f32; 3],
+    // fragment shader part of push constants starts at offset 48
+    /// Color of lines drawn in sensor space
+    line_color: [f32; 4],
+    /// Sample image through the undistortion remap texture if not zero
+    undistort: u32,
+    /// Stereo pair composition code, see `StereoMode::composite`
+    stereo: u32,
+}
+
+impl PushConstant {
+    /// Convert sensor pixel coordinates into normalized device coordinates
+    fn to_ndc(&self, p: [f32; 2], res: [u32; 2]) -> [f32; 2] {
+        let x = 2.*p[0]/(res[0] as f32) - 1.;
+        let y = 2.*p[1]/(res[1] as f32) - 1.;
+        let m = self.orient;
+        let d = [m[0][0]*x + m[0][1]*y, m[1][0]*x + m[1][1]*y];
+        [
+            (d[0] + 2.*self.offset[0])*self.aspect[0]*self.zoom,
+            (d[1] + 2.*self.offset[1])*self.aspect[1]*self.zoom,
+        ]
+    }
+
+    /// Convert normalized device coordinates into sensor pixel coordinates
+    fn from_ndc(&self, p: [f32; 2], res: [u32; 2]) -> [f32; 2] {
+        let x = p[0]/self.aspect[0]/self.zoom - 2.*self.offset[0];
+        let y = p[1]/self.aspect[1]/self.zoom - 2.*self.offset[1];
+        let m = self.orient;
+        let s = [m[0][0]*x + m[1][0]*y, m[0][1]*x + m[1][1]*y];
+        [
+            (s[0] + 1.)*(res[0] as f32)/2.,
+            (s[1] + 1.)*(res[1] as f32)/2.,
+        ]
+    }
 }
 
 struct EngineState {
     recreate_swapchain: bool,
     lmb_pressed: bool,
     grid_on: bool,
+    grid: grid::Grid,
+    align_on: bool,
+    has_calib: bool,
+    thirds_on: bool,
     hist_on: bool,
-    fps_on: bool,
-    is_grey: bool,
+    pixel_grid_on: bool,
+    pixel_values_on: bool,
+    linear_filter: bool,
     done: bool,
     hidpi: f64,
     dimensions: [f64; 2],
     init_coor: [f32; 2],
     mouse_coor: [f32; 2],
     old_offset: [f32; 2],
-    resolution: [u32; 2],
-    push_consts: PushConstant,
     pause: Arc<AtomicBool>,
-    cam_mutex: Arc<Mutex<cam::FrameBuf>>,
+    /// Convert frames for display in the compute shader
+    gpu_convert: bool,
+    /// Device queue supports compute shaders
+    gpu_available: bool,
+    /// Viewport covering the whole window
     dyn_state: DynamicState,
-    frame_ts: u64,
+    tiles: Vec<tile::Tile>,
+    /// Index of the tile controlled by keyboard and shown in the OSD
+    active: usize,
+    /// Show only the active tile
+    maximized: bool,
+    stereo: stereo::StereoMode,
+    /// Show horizontal guide lines for stereo pair alignment
+    guides_on: bool,
+    view_mode: view::ViewMode,
+    diff_mode: diff::DiffMode,
+    /// Multiplier applied to the frame difference
+    diff_gain: f32,
+    noise: noise::Noise,
+    defects: defects::Defects,
+    flat: flat::FlatField,
+    isp: isp::Settings,
+    orientation: orientation::Orientation,
+    save_oriented: bool,
+    snapshot: snapshot::Snapshot,
+    osd: osd::Osd,
+    capture: capture::Capture,
+    sync: sync::Synchronizer,
+}
+
+impl EngineState {
+    fn tile(&self) -> &tile::Tile {
+        &self.tiles[self.active]
+    }
+
+    fn tile_mut(&mut self) -> &mut tile::Tile {
+        &mut self.tiles[self.active]
+    }
 }
 
-fn rgb2rgba(pix: &[u8; 3]) -> [u8; 4] {
-    [pix[0], pix[1], pix[2], 255]
+/// Create vertex buffer with lines in normalized sensor coordinates,
+/// returns `None` if there are no lines
+fn build_lines(device: &Arc<Device>, lines: Vec<[f32; 2]>)
+    -> Option<Arc<CpuAccessibleBuffer<[Vertex]>>>
+{
+    if lines.is_empty() { return None; }
+    let buf = CpuAccessibleBuffer::<[Vertex]>::from_iter(
+        device.clone(), vulkano::buffer::BufferUsage::all(),
+        lines.into_iter().map(|v| Vertex { position: v })
+    ).expect("failed to create buffer");
+    Some(buf)
 }
 
 fn main() -> Result<(), Box<std::error::Error>> {
     let args = cli::Cli::from_args();
 
-    eprintln!("Waiting for camera... ");
-    let cam = cam::Cam::new(&args.camera)?;
+    if let Some(cli::Command::Calibrate {
+        ref dir, board, square, ref output,
+    }) = args.cmd {
+        return calibrate::run(dir, board, square, output);
+    }
+    if let Some(cli::Command::Bench {
+        ref resolutions, frames, threads,
+    }) = args.cmd {
+        return bench::run(resolutions, frames, threads);
+    }
+    if args.camera.is_empty() {
+        Err("camera device is not specified")?
+    }
 
-    let resolution = cam.get_resolution();
+    parallel::set_threads(args.convert_threads);
+    parallel::set_cameras(args.camera.len());
+
+    let mut cams = Vec::new();
+    for camera in args.camera.iter() {
+        eprintln!("Waiting for camera {}... ", camera);
+        cams.push(cam::Cam::new(camera)?);
+    }
 
     let mut dimensions = DEFAULT_DIMENSIONS;
 
@@ -119,14 +259,61 @@ fn main() -> Result<(), Box<std::error::Error>> {
         [(queue, 0.5)].iter().cloned()
     ).expect("failed to create device");
     let queue = queues.next().unwrap();
+    let gpu_available = queue.family().supports_compute();
+    if args.gpu_convert && !gpu_available {
+        eprintln!("Compute shaders are not supported, using CPU conversion");
+    }
 
 
     let pause = Arc::new(AtomicBool::new(false));
-    let is_grey = cam.is_grey();
-    let cam_mutex = cam.run_worker(pause.clone());
-
-
-
+    let orientation = orientation::Orientation::new(
+        args.rotate, args.flip_h, args.flip_v,
+    );
+    let push_consts = PushConstant {
+        aspect: [1.0, 1.0], zoom: 1.0, offset: [0., 0.],
+        orient: orientation.shader_matrix(),
+        _pad: [0.; 3], line_color: args.grid_color, undistort: 0,
+        stereo: 0,
+    };
+    let (mut sync, sinks) = sync::Synchronizer::new(
+        cams.len(), args.sync_tolerance, &args.record_dir, args.record_format,
+    );
+    let mut tiles: Vec<tile::Tile> = cams.into_iter()
+        .zip(args.camera.iter())
+        .zip(sinks)
+        .map(|((cam, name), sink)| {
+            let mut yuv = cam.get_yuv_encoding();
+            if let Some(matrix) = args.yuv_matrix { yuv.matrix = matrix; }
+            if let Some(range) = args.yuv_range { yuv.range = range; }
+            let conversion = demosaic::Conversion {
+                algorithm: args.demosaic, yuv,
+                isp: isp::Params::default(),
+            };
+            tile::Tile::new(
+                name, cam, pause.clone(), conversion, sink, push_consts,
+            )
+        })
+        .collect();
+    sync.start(&mut tiles, args.sync_display);
+    // stale per-camera files should not prevent viewing the camera
+    for tile in tiles.iter_mut() {
+        if let Err(err) = defects::load(tile, Path::new(&args.defects_dir)) {
+            eprintln!("Failed to load defect map: {}", err);
+        }
+        if let Err(err) = flat::load(tile, Path::new(&args.flat_dir)) {
+            eprintln!("Failed to load flat-field gains: {}", err);
+        }
+        let isp_dir = Path::new(&args.isp_dir);
+        if let Err(err) = isp::load(tile, isp_dir, args.isp) {
+            eprintln!("Failed to load color profile: {}", err);
+        }
+    }
+    // calibration capture and undistortion use the first camera
+    let resolution = tiles[0].resolution;
+    let capture = capture::Capture::new(
+        args.board, &args.calib_dir, resolution, tiles[0].is_grey,
+        tiles[0].is_bayer, tiles[0].cam_mutex.clone(),
+    );
 
     let (mut swapchain, mut images) = {
         let caps = surface.capabilities(physical)
@@ -155,25 +342,44 @@ fn main() -> Result<(), Box<std::error::Error>> {
        ].iter().cloned()
     ).expect("failed to create buffer");
 
-    let mut grid = Vec::new();
-
-    let w_f32 = (resolution[0] as f32)/2.;
-    for i in (args.grid_step..resolution[0]).step_by(args.grid_step as usize) {
-        let c = (i as f32)/w_f32 - 1.;
-        grid.extend_from_slice(&[[c, -1.], [c, 1.]]);
-    }
+    let grid = grid::Grid::new(
+        [
+            args.grid_step_x.unwrap_or(args.grid_step),
+            args.grid_step_y.unwrap_or(args.grid_step),
+        ],
+        args.grid_centered, args.grid_labels, args.grid_color,
+    );
+    let mut grid_params = (grid.step, grid.centered);
+    let mut grid_vertex_buffers: Vec<_> = tiles.iter()
+        .map(|t| build_lines(&device, grid.lines(t.resolution)))
+        .collect();
 
-    let h_f32 = (resolution[1] as f32)/2.;
-    for i in (args.grid_step..resolution[1]).step_by(args.grid_step as usize) {
-        let c = (i as f32)/h_f32 - 1.;
-        grid.extend_from_slice(&[[-1., c], [1., c]]);
+    let custom_reticle = match args.reticle {
+        Some(ref path) => Some(reticle::Reticle::load(path)?),
+        None => None,
+    };
+    let mut align_vertex_buffers = Vec::new();
+    let mut thirds_vertex_buffers = Vec::new();
+    for tile in tiles.iter() {
+        let res = tile.resolution;
+        let mut align = reticle::Reticle::crosshair(res);
+        align.extend(reticle::Reticle::circles(res, &args.circles));
+        align.shapes.extend(
+            args.markers.iter().map(|&p| reticle::Shape::Marker(p))
+        );
+        if let Some(ref custom) = custom_reticle {
+            align.extend(custom.clone());
+        }
+        align_vertex_buffers.push(build_lines(&device, align.lines(res)));
+        thirds_vertex_buffers.push(build_lines(
+            &device, reticle::Reticle::thirds(res).lines(res),
+        ));
     }
 
-    let grid_vertex_buffer = CpuAccessibleBuffer::<[Vertex]>::from_iter(
-        device.clone(), vulkano::buffer::BufferUsage::all(),
-        grid.iter().map(|v| Vertex { position: [v[0], v[1]] } )
-    ).expect("failed to create buffer");
-
+    let fs5 = shaders::fs5::Shader::load(device.clone())
+        .expect("fs5: failed to create shader module");
+    let cs = shaders::cs::Shader::load(device.clone())
+        .expect("cs: failed to create shader module");
 
     let vs = shaders::vs::Shader::load(device.clone())
         .expect("failed to create shader module");
@@ -190,6 +396,11 @@ fn main() -> Result<(), Box<std::error::Error>> {
     let fs3 = shaders::fs3::Shader::load(device.clone())
         .expect("fs2: failed to create shader module");
 
+    let vs4 = shaders::vs4::Shader::load(device.clone())
+        .expect("vs4: failed to create shader module");
+    let fs4 = shaders::fs4::Shader::load(device.clone())
+        .expect("fs4: failed to create shader module");
+
     let renderpass = Arc::new(
         single_pass_renderpass!(device.clone(),
             attachments: {
@@ -207,17 +418,55 @@ fn main() -> Result<(), Box<std::error::Error>> {
         ).unwrap()
     );
 
-    let texture = StorageImage::with_usage(
+    let textures: Vec<_> = tiles.iter().map(|tile| {
+        let res = tile.resolution;
+        StorageImage::with_usage(
+            device.clone(),
+            Dimensions::Dim2d { width: res[0], height: res[1] },
+            vulkano::format::R8G8B8A8Srgb,
+            ImageUsage {
+                transfer_destination: true, sampled: true,
+                ..ImageUsage::none()
+            },
+            Some(queue.family()),
+        ).unwrap()
+    }).collect();
+
+
+    // remap texture contains coordinates in the distorted image for each
+    // pixel of the rectified one, without calibration dummy texture is used,
+    // calibration is applied only to the first camera
+    let intrinsics = match args.calib {
+        Some(ref path) => Some(calib::Intrinsics::load(path)?),
+        None => None,
+    };
+    let (remap_data, remap_res) = match intrinsics {
+        Some(ref intr) => (intr.undistort_map(resolution), resolution),
+        None => (vec![[0., 0.]], [1, 1]),
+    };
+    let (remap, remap_future) = ImmutableImage::from_iter(
+        remap_data.into_iter(),
+        Dimensions::Dim2d { width: remap_res[0], height: remap_res[1] },
+        vulkano::format::R32G32Sfloat,
+        queue.clone(),
+    ).expect("failed to create remap texture");
+    let (no_remap, no_remap_future) = ImmutableImage::from_iter(
+        vec![[0f32, 0.]].into_iter(),
+        Dimensions::Dim2d { width: 1, height: 1 },
+        vulkano::format::R32G32Sfloat,
+        queue.clone(),
+    ).expect("failed to create remap texture");
+    let remap_sampler = vulkano::sampler::Sampler::new(
         device.clone(),
-        Dimensions::Dim2d { width: resolution[0], height: resolution[1] },
-        vulkano::format::R8G8B8A8Srgb,
-        ImageUsage {
-            transfer_destination: true, sampled: true, ..ImageUsage::none()
-        },
-        Some(queue.family()),
+        vulkano::sampler::Filter::Nearest,
+        vulkano::sampler::Filter::Nearest,
+        vulkano::sampler::MipmapMode::Nearest,
+        vulkano::sampler::SamplerAddressMode::ClampToEdge,
+        vulkano::sampler::SamplerAddressMode::ClampToEdge,
+        vulkano::sampler::SamplerAddressMode::ClampToEdge,
+        0.0, 1.0, 0.0, 0.0
     ).unwrap();
 
-
     let addr_mode = vulkano::sampler::SamplerAddressMode::ClampToBorder(
         vulkano::sampler::BorderColor::IntTransparentBlack
     );
@@ -229,6 +478,14 @@ fn main() -> Result<(), Box<std::error::Error>> {
         addr_mode, addr_mode, addr_mode,
         0.0, 1.0, 0.0, 0.0
     ).unwrap();
+    let linear_sampler = vulkano::sampler::Sampler::new(
+        device.clone(),
+        vulkano::sampler::Filter::Linear,
+        vulkano::sampler::Filter::Linear,
+        vulkano::sampler::MipmapMode::Nearest,
+        addr_mode, addr_mode, addr_mode,
+        0.0, 1.0, 0.0, 0.0
+    ).unwrap();
 
     let pipeline = Arc::new(vulkano::pipeline::GraphicsPipeline::start()
         .vertex_input_single_buffer::<Vertex>()
@@ -242,12 +499,30 @@ fn main() -> Result<(), Box<std::error::Error>> {
         .expect("Failed to build main pipeline")
     );
 
+    let convert_pipeline = Arc::new(
+        ComputePipeline::new(device.clone(), &cs.main_entry_point(), &())
+            .expect("Failed to build conversion pipeline")
+    );
+
+    let stereo_pipeline = Arc::new(vulkano::pipeline::GraphicsPipeline::start()
+        .vertex_input_single_buffer::<Vertex>()
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_strip()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs5.main_entry_point(), ())
+        .blend_alpha_blending()
+        .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+        .build(device.clone())
+        .expect("Failed to build stereo pipeline")
+    );
+
     let grid_pipeline = Arc::new(vulkano::pipeline::GraphicsPipeline::start()
         .vertex_input_single_buffer::<Vertex>()
         .vertex_shader(vs2.main_entry_point(), ())
         .line_list()
         .viewports_dynamic_scissors_irrelevant(1)
         .fragment_shader(fs2.main_entry_point(), ())
+        .blend_alpha_blending()
         .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
         .build(device.clone())
         .expect("Failed to build grid pipeline")
@@ -265,11 +540,66 @@ fn main() -> Result<(), Box<std::error::Error>> {
         .expect("Failed to build main pipeline")
     );
 
-    let set = Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
-        .add_sampled_image(texture.clone(), sampler.clone()).unwrap()
-        .build().unwrap()
+    let overlay_pipeline = Arc::new(vulkano::pipeline::GraphicsPipeline::start()
+        .vertex_input_single_buffer::<ColorVertex>()
+        .vertex_shader(vs4.main_entry_point(), ())
+        .line_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs4.main_entry_point(), ())
+        .blend_alpha_blending()
+        .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+        .build(device.clone())
+        .expect("Failed to build overlay pipeline")
+    );
+
+    let text_pipeline = Arc::new(vulkano::pipeline::GraphicsPipeline::start()
+        .vertex_input_single_buffer::<ColorVertex>()
+        .vertex_shader(vs4.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .fragment_shader(fs4.main_entry_point(), ())
+        .blend_alpha_blending()
+        .render_pass(Subpass::from(renderpass.clone(), 0).unwrap())
+        .build(device.clone())
+        .expect("Failed to build text pipeline")
     );
 
+    let mut sets = Vec::new();
+    let mut linear_sets = Vec::new();
+    for (i, texture) in textures.iter().enumerate() {
+        let remap = if i == 0 { &remap } else { &no_remap };
+        sets.push(Arc::new(PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(texture.clone(), sampler.clone()).unwrap()
+            .add_sampled_image(remap.clone(), remap_sampler.clone()).unwrap()
+            .build().unwrap()
+        ));
+        linear_sets.push(Arc::new(
+            PersistentDescriptorSet::start(pipeline.clone(), 0)
+                .add_sampled_image(texture.clone(), linear_sampler.clone())
+                .unwrap()
+                .add_sampled_image(remap.clone(), remap_sampler.clone())
+                .unwrap()
+                .build().unwrap()
+        ));
+    }
+
+    // stereo pair is composed from textures of the first two cameras
+    let stereo_sets = if textures.len() >= 2 {
+        let set = |sampler: &Arc<vulkano::sampler::Sampler>| Arc::new(
+            PersistentDescriptorSet::start(stereo_pipeline.clone(), 0)
+                .add_sampled_image(textures[0].clone(), sampler.clone())
+                .unwrap()
+                .add_sampled_image(textures[1].clone(), sampler.clone())
+                .unwrap()
+                .add_sampled_image(remap.clone(), remap_sampler.clone())
+                .unwrap()
+                .build().unwrap()
+        );
+        Some((set(&sampler), set(&linear_sampler)))
+    } else {
+        None
+    };
+
     let mut framebuffers: Vec<Arc<Framebuffer<_,_>>> = images.iter()
         .map(|image|
             Arc::new(Framebuffer::start(renderpass.clone())
@@ -277,7 +607,11 @@ fn main() -> Result<(), Box<std::error::Error>> {
                  .build().unwrap())
         ).collect::<Vec<Arc<Framebuffer<_,_>>>>();
 
-    let prev_frame = Box::new(vulkano::sync::now(device.clone()));
+    let prev_frame = Box::new(
+        vulkano::sync::now(device.clone())
+            .join(remap_future)
+            .join(no_remap_future)
+    );
     let mut previous_frame = prev_frame as Box<GpuFuture>;
 
     let hidpi = surface.window().get_hidpi_factor();
@@ -285,21 +619,23 @@ fn main() -> Result<(), Box<std::error::Error>> {
         recreate_swapchain: false,
         lmb_pressed: false,
         grid_on: false,
+        grid: grid,
+        align_on: false,
+        has_calib: intrinsics.is_some(),
+        thirds_on: false,
         hist_on: false,
-        fps_on: false,
-        is_grey: is_grey,
+        pixel_grid_on: false,
+        pixel_values_on: false,
+        linear_filter: false,
         done: false,
         hidpi: hidpi,
         dimensions: [dimensions[0] as f64, dimensions[1] as f64],
         init_coor: [0f32; 2],
         mouse_coor: [0f32; 2],
         old_offset: [0., 0.],
-        resolution: resolution,
-        push_consts: PushConstant {
-            aspect: [1.0, 1.0], zoom: 1.0, offset: [0., 0.],
-        },
         pause: pause,
-        cam_mutex: cam_mutex,
+        gpu_convert: args.gpu_convert && gpu_available,
+        gpu_available: gpu_available,
         dyn_state: DynamicState {
             line_width: None,
             viewports: Some(vec![Viewport {
@@ -309,27 +645,99 @@ fn main() -> Result<(), Box<std::error::Error>> {
             }]),
             scissors: None,
         },
-        frame_ts: 0u64,
+        tiles: tiles,
+        active: 0,
+        maximized: false,
+        stereo: stereo::StereoMode::Off,
+        guides_on: false,
+        view_mode: view::ViewMode::Color,
+        diff_mode: diff::DiffMode::Off,
+        diff_gain: 1.,
+        noise: noise::Noise {
+            view: noise::NoiseView::Off,
+            frames: args.noise_frames,
+            rois: args.noise_rois.clone(),
+            format: args.noise_format,
+        },
+        defects: defects::Defects {
+            dir: PathBuf::from(&args.defects_dir),
+            threshold: args.defect_threshold,
+            overlay_on: false,
+            correct_on: args.correct_defects,
+        },
+        flat: flat::FlatField {
+            dir: PathBuf::from(&args.flat_dir),
+            correct_on: args.correct_flat,
+        },
+        isp: isp::Settings {
+            dir: PathBuf::from(&args.isp_dir),
+            picker_on: false,
+            auto_wb: args.auto_wb,
+        },
+        orientation: orientation,
+        save_oriented: args.save_oriented,
+        snapshot: snapshot::Snapshot {
+            dir: PathBuf::from(&args.save_dir),
+            template: args.save_name.clone(),
+            format: args.save_format,
+            writer: snapshot::Writer::new(),
+        },
+        osd: osd::Osd::new(),
+        capture: capture,
+        sync: sync,
     };
 
-    let buf_pool = CpuBufferPool::upload(device.clone());
-    let mut chunk = buf_pool.chunk(
-        (0..resolution[0]*resolution[1]).map(|_| [0u8, 0, 0, 255]))
-        .unwrap();
+    let dims = [dimensions[0] as f32, dimensions[1] as f32];
+    tile::arrange(&mut state, dims);
+    defects::apply_correction(&state);
+    flat::apply_correction(&mut state);
 
-    let mut hist_vertices: Vec<Vertex> = (0..=255)
-        .map(|x| Vertex { position: [(x as f32)/255., 0.] })
+    let buf_pool = CpuBufferPool::upload(device.clone());
+    let mut chunks: Vec<_> = state.tiles.iter().map(|tile| {
+        let res = tile.resolution;
+        buf_pool.chunk((0..res[0]*res[1]).map(|_| [0u8, 0, 0, 255])).unwrap()
+    }).collect();
+    // raw frames converted by the compute shader into per-tile buffers,
+    // which are then copied into the textures
+    let raw_pool = CpuBufferPool::<u8>::new(
+        device.clone(),
+        BufferUsage { storage_buffer: true, ..BufferUsage::none() },
+    );
+    let mut raw_chunks: Vec<_> = state.tiles.iter().map(|_| None).collect();
+    let converted: Vec<_> = state.tiles.iter().map(|tile| {
+        let res = tile.resolution;
+        DeviceLocalBuffer::<[[u8; 4]]>::array(
+            device.clone(), (res[0]*res[1]) as usize,
+            BufferUsage {
+                storage_buffer: true, transfer_source: true,
+                ..BufferUsage::none()
+            },
+            Some(queue.family()),
+        ).expect("failed to create conversion buffer")
+    }).collect();
+
+    let mut hist_vertices: Vec<Vec<Vertex>> = state.tiles.iter()
+        .map(|_| {
+            (0..=255)
+                .map(|x| Vertex { position: [(x as f32)/255., 0.] })
+                .collect()
+        })
         .collect();
+    let mut value_vertices: Vec<Vec<ColorVertex>> = Vec::new();
+
+    let mut zoom_percent = 0;
 
     let mut t = Instant::now();
     let mut fc = 0;
     loop {
         let dt = t.elapsed();
         if dt > Duration::from_secs(1) {
-            if state.fps_on {
-                let micros = dt.subsec_micros() as f32;
-                let secs = dt.as_secs() as f32 + micros/1_000_000.;
-                println!("fps: {:?}", (fc as f32)/secs);
+            let micros = dt.subsec_micros() as f32;
+            let secs = dt.as_secs() as f32 + micros/1_000_000.;
+            state.osd.fps = (fc as f32)/secs;
+            for tile in state.tiles.iter_mut() {
+                tile.fps = (tile.frames as f32)/secs;
+                tile.frames = 0;
             }
 
             t = Instant::now();
@@ -338,9 +746,26 @@ fn main() -> Result<(), Box<std::error::Error>> {
 
         previous_frame.cleanup_finished();
         events_loop.poll_events(|event| events::handle(event, &mut state));
+        if state.stereo != stereo::StereoMode::Off {
+            stereo::sync(&mut state);
+        }
+
+        if let Some(err) = state.sync.take_error() {
+            state.osd.notify(format!("Recording failed: {}", err));
+        }
+        noise::update(&mut state);
+        isp::update(&mut state);
+        snapshot::update(&mut state);
+
+        if state.capture.update() {
+            let msg = match state.capture.accept() {
+                Ok(msg) => msg,
+                Err(err) => format!("Save failed: {}", err),
+            };
+            state.osd.notify(msg);
+        }
 
         if state.recreate_swapchain {
-            let res = state.resolution;
             let default_dims = [
                 (state.dimensions[0]*state.hidpi) as u32,
                 (state.dimensions[1]*state.hidpi) as u32,
@@ -360,14 +785,7 @@ fn main() -> Result<(), Box<std::error::Error>> {
                 Err(err) => panic!("{:?}", err)
             };
 
-            let r_p1 = (dims[0] as f32)/(dims[1] as f32);
-            let r_p2 = (res[0] as f32)/(res[1] as f32);
-
-            state.push_consts.aspect = if r_p1 > r_p2 {
-                [r_p2/r_p1, 1.]
-            } else {
-                [1.0, r_p1/r_p2]
-            };
+            tile::arrange(&mut state, [dims[0] as f32, dims[1] as f32]);
 
             framebuffers = images.iter().map(|image|
                 Arc::new(Framebuffer::start(renderpass.clone())
@@ -385,6 +803,14 @@ fn main() -> Result<(), Box<std::error::Error>> {
             state.recreate_swapchain = false;
         }
 
+        let new_percent = overlay::pixel_size(&state, state.tile());
+        let new_percent = (100.*new_percent).round() as u32;
+        if new_percent != zoom_percent {
+            zoom_percent = new_percent;
+            surface.window()
+                .set_title(&format!("Camera ({}%)", zoom_percent));
+        }
+
         let next_img = acquire_next_image(swapchain.clone(), None);
         let (image_num, future) = match next_img {
             Ok(r) => r,
@@ -395,51 +821,327 @@ fn main() -> Result<(), Box<std::error::Error>> {
             Err(err) => panic!("{:?}", err)
         };
 
-        {
-            let guard = state.cam_mutex.lock().unwrap();
-            if guard.ts != state.frame_ts {
-                state.frame_ts = guard.ts;
-                chunk = buf_pool
-                    .chunk(guard.buf.iter().map(rgb2rgba))
-                    .unwrap();
-
-                let hist_max = guard.hist.iter().cloned().max().unwrap() as f32;
-                for (&val, vert) in guard.hist.iter().zip(hist_vertices.iter_mut()) {
-                    vert.position[1] = 1.0 - (val as f32)/hist_max;
+        let view_mode = state.view_mode;
+        let (diff_mode, diff_gain) = (state.diff_mode, state.diff_gain);
+        let noise_view = state.noise.view;
+        let paths: Vec<_> = (0..state.tiles.len())
+            .map(|i| (gpu::displays_raw(&state, i), gpu::needs_cpu(&state, i)))
+            .collect();
+        value_vertices.clear();
+        for (i, &(gpu_display, needs_cpu)) in paths.iter().enumerate() {
+            // the guard does not borrow the state, so pixel values are
+            // built without locking the frame again
+            let cam_mutex = state.tiles[i].cam_mutex.clone();
+            let mut guard = cam_mutex.lock().unwrap();
+            {
+                let tile = &mut state.tiles[i];
+                tile.cpu_convert.store(needs_cpu, Ordering::Relaxed);
+                let conv = tile.conversion;
+                let params = gpu::ConvertParams::new(
+                    tile.format, tile.resolution, &conv,
+                );
+                let gpu_display = gpu_display && params.is_some();
+                if gpu_display != tile.gpu_display {
+                    tile.gpu_display = gpu_display;
+                    tile.update_texture = true;
+                }
+
+                tile.drops = guard.drops;
+                tile.bad_frames = guard.bad_frames;
+                let is_new = guard.ts != tile.frame_ts;
+                if needs_cpu {
+                    guard.convert(tile.format, tile.resolution, &conv);
+                }
+                if is_new {
+                    tile.frames += 1;
+                    if diff_mode != diff::DiffMode::Off {
+                        tile.diff.push(&guard);
+                    }
+                }
+                if is_new || tile.update_texture {
+                    tile.frame_ts = guard.ts;
+                    tile.update_texture = false;
+                    if let (true, Some(params)) = (gpu_display, params) {
+                        let raw = gpu::padded(&guard.raw);
+                        let raw = raw_pool.chunk(raw).unwrap();
+                        raw_chunks[i] = Some((raw, params));
+                    } else {
+                        let mode = view_mode.for_source(tile.is_bayer);
+                        let width = tile.resolution[0] as usize;
+                        let map = match tile.noise {
+                            Some(ref mut m) => m.map(noise_view),
+                            None => None,
+                        };
+                        let data = match map {
+                            Some(map) => map,
+                            None => tile.diff.to_rgba(
+                                diff_mode, mode, diff_gain, &guard, width,
+                            ),
+                        };
+                        chunks[i] = buf_pool.chunk(data).unwrap();
+                    }
+
+                    let hist = &guard.hist;
+                    let hist_max = hist.iter().cloned().max().unwrap() as f32;
+                    let verts = hist_vertices[i].iter_mut();
+                    for (&val, vert) in hist.iter().zip(verts) {
+                        vert.position[1] = 1.0 - (val as f32)/hist_max;
+                    }
                 }
             }
-        };
+
+            let tile = &state.tiles[i];
+            value_vertices.push(if state.pixel_values_on && tile.visible {
+                overlay::pixel_values(&state, tile, &guard)
+            } else {
+                Vec::new()
+            });
+        }
+
+        let sets = if state.linear_filter { &linear_sets } else { &sets };
 
         let mut cbb = AutoCommandBufferBuilder
             ::primary_one_time_submit(device.clone(), queue.family())
-            .unwrap()
-            .copy_buffer_to_image(chunk.clone(), texture.clone())
-            .expect("Failed to copy data to texture")
+            .unwrap();
+        for (i, (chunk, texture)) in chunks.iter().zip(textures.iter())
+            .enumerate()
+        {
+            if let Some((raw, params)) = raw_chunks[i].take() {
+                let set = Arc::new(PersistentDescriptorSet::start(
+                        convert_pipeline.clone(), 0,
+                    )
+                    .add_buffer(raw).unwrap()
+                    .add_buffer(converted[i].clone()).unwrap()
+                    .build().unwrap()
+                );
+                cbb = cbb
+                    .dispatch(
+                        params.groups(), convert_pipeline.clone(), set, params,
+                    )
+                    .expect("Failed to dispatch frame conversion")
+                    .copy_buffer_to_image(converted[i].clone(), texture.clone())
+                    .expect("Failed to copy converted frame to texture");
+            } else if !state.tiles[i].gpu_display {
+                cbb = cbb
+                    .copy_buffer_to_image(chunk.clone(), texture.clone())
+                    .expect("Failed to copy data to texture");
+            }
+        }
+        cbb = cbb
             .begin_render_pass(
                 framebuffers[image_num].clone(), false,
-                vec![[0.0, 0.0, 0.0, 1.0].into()]).unwrap()
-            .draw(
-                pipeline.clone(),
+                vec![[0.0, 0.0, 0.0, 1.0].into()]).unwrap();
+
+        if (state.grid.step, state.grid.centered) != grid_params {
+            grid_params = (state.grid.step, state.grid.centered);
+            grid_vertex_buffers = state.tiles.iter()
+                .map(|t| build_lines(&device, state.grid.lines(t.resolution)))
+                .collect();
+        }
+
+        for (i, tile) in state.tiles.iter().enumerate() {
+            if !tile.visible { continue; }
+            let dyn_state = &tile.dyn_state;
+            let mut push_consts = tile.push_consts;
+            push_consts.line_color = state.grid.color;
+
+            push_consts.stereo = state.stereo.composite();
+            match stereo_sets {
+                Some((ref set, ref linear_set))
+                    if i == 0 && push_consts.stereo != 0 =>
+                {
+                    let set = if state.linear_filter {
+                        linear_set
+                    } else {
+                        set
+                    };
+                    cbb = cbb
+                        .draw(
+                            stereo_pipeline.clone(),
+                            dyn_state,
+                            vertex_buffer.clone(),
+                            set.clone(), push_consts,
+                        ).expect("stereo pipeline draw fail");
+                },
+                _ => {
+                    cbb = cbb
+                        .draw(
+                            pipeline.clone(),
+                            dyn_state,
+                            vertex_buffer.clone(),
+                            sets[i].clone(), push_consts,
+                        ).expect("Main pipeline draw fail");
+                },
+            }
+
+            match grid_vertex_buffers[i] {
+                Some(ref buf) if state.grid_on => {
+                    cbb = cbb
+                        .draw(
+                            grid_pipeline.clone(),
+                            dyn_state,
+                            buf.clone(),
+                            (), push_consts,
+                        ).expect("grid pipeline draw fail");
+                },
+                _ => (),
+            }
+
+            let align_buffers = [
+                (&align_vertex_buffers[i], state.align_on),
+                (&thirds_vertex_buffers[i], state.thirds_on),
+            ];
+            for &(buf, is_on) in align_buffers.iter() {
+                let buf = match *buf {
+                    Some(ref buf) if is_on => buf.clone(),
+                    _ => continue,
+                };
+                let mut push_consts = push_consts;
+                push_consts.line_color = args.align_color;
+                cbb = cbb
+                    .draw(
+                        grid_pipeline.clone(),
+                        dyn_state,
+                        buf, (), push_consts,
+                    ).expect("alignment overlay draw fail");
+            }
+
+            if state.grid_on && state.grid.labels {
+                let labels = grid::labels(&state, tile);
+                if !labels.is_empty() {
+                    let buf = CpuAccessibleBuffer::from_iter(
+                        device.clone(),
+                        vulkano::buffer::BufferUsage::all(),
+                        labels.into_iter(),
+                    ).expect("failed to create buffer");
+                    cbb = cbb.draw(
+                        text_pipeline.clone(),
+                        dyn_state,
+                        buf, (), (),
+                    ).expect("grid labels draw fail");
+                }
+            }
+
+            if state.pixel_grid_on {
+                let lines = overlay::pixel_grid(&state, tile);
+                if !lines.is_empty() {
+                    let buf = CpuAccessibleBuffer::from_iter(
+                        device.clone(),
+                        vulkano::buffer::BufferUsage::all(),
+                        lines.into_iter(),
+                    ).expect("failed to create buffer");
+                    cbb = cbb.draw(
+                        overlay_pipeline.clone(),
+                        dyn_state,
+                        buf, (), (),
+                    ).expect("pixel grid draw fail");
+                }
+            }
+
+            if state.defects.overlay_on {
+                let lines = defects::markers(tile);
+                if !lines.is_empty() {
+                    let buf = CpuAccessibleBuffer::from_iter(
+                        device.clone(),
+                        vulkano::buffer::BufferUsage::all(),
+                        lines.into_iter(),
+                    ).expect("failed to create buffer");
+                    cbb = cbb.draw(
+                        overlay_pipeline.clone(),
+                        dyn_state,
+                        buf, (), (),
+                    ).expect("defect markers draw fail");
+                }
+            }
+
+            if !value_vertices[i].is_empty() {
+                let buf = CpuAccessibleBuffer::from_iter(
+                    device.clone(),
+                    vulkano::buffer::BufferUsage::all(),
+                    value_vertices[i].iter().cloned(),
+                ).expect("failed to create buffer");
+                cbb = cbb.draw(
+                    text_pipeline.clone(),
+                    dyn_state,
+                    buf, (), (),
+                ).expect("pixel values draw fail");
+            }
+
+            if state.capture.on && i == 0 {
+                let triangles = capture::coverage(&state);
+                if !triangles.is_empty() {
+                    let buf = CpuAccessibleBuffer::from_iter(
+                        device.clone(),
+                        vulkano::buffer::BufferUsage::all(),
+                        triangles.into_iter(),
+                    ).expect("failed to create buffer");
+                    cbb = cbb.draw(
+                        text_pipeline.clone(),
+                        dyn_state,
+                        buf, (), (),
+                    ).expect("coverage draw fail");
+                }
+                let lines = capture::corners(&state);
+                if !lines.is_empty() {
+                    let buf = CpuAccessibleBuffer::from_iter(
+                        device.clone(),
+                        vulkano::buffer::BufferUsage::all(),
+                        lines.into_iter(),
+                    ).expect("failed to create buffer");
+                    cbb = cbb.draw(
+                        overlay_pipeline.clone(),
+                        dyn_state,
+                        buf, (), (),
+                    ).expect("checkerboard corners draw fail");
+                }
+            }
+
+            if state.tiles.len() == 1 { continue; }
+
+            let is_stereo = state.stereo != stereo::StereoMode::Off;
+            if i == state.active && !state.maximized && !is_stereo {
+                let buf = CpuAccessibleBuffer::from_iter(
+                    device.clone(),
+                    vulkano::buffer::BufferUsage::all(),
+                    tile::border().into_iter(),
+                ).expect("failed to create buffer");
+                cbb = cbb.draw(
+                    overlay_pipeline.clone(),
+                    dyn_state,
+                    buf, (), (),
+                ).expect("tile border draw fail");
+            }
+
+            let buf = CpuAccessibleBuffer::from_iter(
+                device.clone(),
+                vulkano::buffer::BufferUsage::all(),
+                osd::tile_status(&state, i).into_iter(),
+            ).expect("failed to create buffer");
+            cbb = cbb.draw(
+                text_pipeline.clone(),
+                dyn_state,
+                buf, (), (),
+            ).expect("tile status draw fail");
+        }
+
+        if state.guides_on {
+            let buf = CpuAccessibleBuffer::from_iter(
+                device.clone(),
+                vulkano::buffer::BufferUsage::all(),
+                stereo::guide_lines().into_iter(),
+            ).expect("failed to create buffer");
+            cbb = cbb.draw(
+                overlay_pipeline.clone(),
                 &state.dyn_state,
-                vertex_buffer.clone(),
-                set.clone(), state.push_consts,
-            ).expect("Main pipeline draw fail");
-
-        if state.grid_on {
-            cbb = cbb
-                .draw(
-                    grid_pipeline.clone(),
-                    &state.dyn_state,
-                    grid_vertex_buffer.clone(),
-                    (), state.push_consts,
-                ).expect("grid pipeline draw fail");
+                buf, (), (),
+            ).expect("guide lines draw fail");
         }
 
         if state.hist_on {
             let hist_vertices = CpuAccessibleBuffer::from_iter(
                 device.clone(),
                 vulkano::buffer::BufferUsage::all(),
-                hist_vertices.iter().cloned()
+                hist_vertices[state.active].iter().cloned()
             ).expect("failed to create buffer");
 
             let [w, h] = events::get_dims(&state);
@@ -457,10 +1159,25 @@ fn main() -> Result<(), Box<std::error::Error>> {
                 hist_pipeline.clone(),
                 &dyn_state,
                 hist_vertices.clone(),
-                set.clone(), ()
+                sets[state.active].clone(), ()
             ).unwrap()
         }
 
+        state.osd.cleanup();
+        let osd_vertices = osd::build(&state);
+        if !osd_vertices.is_empty() {
+            let buf = CpuAccessibleBuffer::from_iter(
+                device.clone(),
+                vulkano::buffer::BufferUsage::all(),
+                osd_vertices.into_iter(),
+            ).expect("failed to create buffer");
+            cbb = cbb.draw(
+                text_pipeline.clone(),
+                &state.dyn_state,
+                buf, (), (),
+            ).expect("OSD draw fail");
+        }
+
         let cb = cbb.end_render_pass().unwrap().build().unwrap();
 
         let future = previous_frame.join(future)
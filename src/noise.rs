@@ -0,0 +1,471 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::EngineState;
+use cam::FrameBuf;
+use save;
+use tile::Tile;
+
+/// Temporal noise and FPN maps use 255 for this multiple of the full frame
+/// value
+const MAP_RANGE: f64 = 4.;
+
+/// What is shown instead of the camera image after noise measurement
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum NoiseView {
+    Off,
+    /// Per-pixel temporal mean
+    Mean,
+    /// Per-pixel temporal standard deviation
+    Temporal,
+    /// Deviation of the per-pixel mean from the channel mean
+    FixedPattern,
+}
+
+impl NoiseView {
+    pub fn next(self) -> Self {
+        use self::NoiseView::*;
+        match self {
+            Off => Mean,
+            Mean => Temporal,
+            Temporal => FixedPattern,
+            FixedPattern => Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use self::NoiseView::*;
+        match self {
+            Off => "off",
+            Mean => "temporal mean",
+            Temporal => "temporal noise",
+            FixedPattern => "fixed-pattern noise",
+        }
+    }
+}
+
+/// Format of the exported mean and standard deviation images
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    /// NumPy array of 32-bit floats
+    Npy,
+    /// 16-bit TIFF image with values multiplied by 256
+    Tiff,
+}
+
+/// Parse export format name
+pub(crate) fn parse_format(s: &str) -> Result<ExportFormat, &'static str> {
+    match s {
+        "npy" => Ok(ExportFormat::Npy),
+        "tiff" => Ok(ExportFormat::Tiff),
+        _ => Err("export format must be npy or tiff"),
+    }
+}
+
+/// Rectangular region of interest in sensor pixels
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Roi {
+    pub pos: [u32; 2],
+    pub size: [u32; 2],
+}
+
+/// Parse region of interest in the `x,y,w,h` format
+pub(crate) fn parse_roi(s: &str) -> Result<Roi, &'static str> {
+    let err = "ROI must be in the x,y,w,h format";
+    let v = s.split(',')
+        .map(|v| v.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| err)?;
+    match v[..] {
+        [x, y, w, h] if w > 0 && h > 0 => Ok(Roi { pos: [x, y], size: [w, h] }),
+        _ => Err(err),
+    }
+}
+
+/// Noise measurement settings
+pub(crate) struct Noise {
+    pub view: NoiseView,
+    /// Number of accumulated frames
+    pub frames: u32,
+    /// Regions summarized in addition to the full frame
+    pub rois: Vec<Roi>,
+    pub format: ExportFormat,
+}
+
+/// Which samples of the frame are measured
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Layout {
+    /// Raw RGGB mosaic, one sample per pixel
+    Bayer,
+    /// Raw greyscale data, one sample per pixel
+    Grey,
+    /// Converted RGB data, three samples per pixel
+    Rgb,
+}
+
+impl Layout {
+    fn channels(self) -> usize {
+        if self == Layout::Rgb { 3 } else { 1 }
+    }
+
+    /// Channel name of the sample `i` of pixel `[x, y]`
+    fn channel(self, x: usize, y: usize, i: usize) -> &'static str {
+        match self {
+            Layout::Bayer => match (x % 2, y % 2) {
+                (0, 0) => "R",
+                (1, 0) => "Gr",
+                (0, 1) => "Gb",
+                _ => "B",
+            },
+            Layout::Grey => "Y",
+            Layout::Rgb => ["R", "G", "B"][i],
+        }
+    }
+
+    fn samples(self, frame: &FrameBuf, pixels: usize) -> Vec<u8> {
+        match self {
+            Layout::Rgb => frame.buf.iter()
+                .flat_map(|p| p.iter().cloned())
+                .collect(),
+            _ => frame.raw[..pixels].to_vec(),
+        }
+    }
+}
+
+/// Summary of a single channel inside a region
+pub(crate) struct Summary {
+    pub roi: String,
+    pub channel: &'static str,
+    /// Mean signal level
+    pub mean: f64,
+    /// RMS of the per-pixel temporal standard deviation
+    pub temporal: f64,
+    /// Spatial standard deviation of the per-pixel mean with the residual
+    /// temporal noise removed
+    pub fpn: f64,
+}
+
+/// Per-pixel temporal statistics of a static scene
+struct NoiseStats {
+    frames: u32,
+    ts: u64,
+    /// Some of the frames were flat-field corrected
+    flat_corrected: bool,
+    mean: Vec<f32>,
+    std: Vec<f32>,
+}
+
+struct Progress {
+    frames: u32,
+    result: Option<NoiseStats>,
+}
+
+/// Noise measurement of a single camera
+pub(crate) struct Measurement {
+    pub total: u32,
+    layout: Layout,
+    resolution: [u32; 2],
+    rois: Vec<Roi>,
+    progress: Arc<Mutex<Progress>>,
+    stats: Option<NoiseStats>,
+    pub summary: Vec<Summary>,
+    /// Last built map
+    map: Option<(NoiseView, Vec<[u8; 4]>)>,
+}
+
+impl Measurement {
+    /// Start thread accumulating `total` consecutive frames of the tile
+    pub fn start(tile: &Tile, total: u32, rois: &[Roi]) -> Self {
+        let layout = if tile.is_bayer {
+            Layout::Bayer
+        } else if tile.is_grey {
+            Layout::Grey
+        } else {
+            Layout::Rgb
+        };
+        let res = tile.resolution;
+        let pixels = (res[0]*res[1]) as usize;
+        let progress = Arc::new(Mutex::new(Progress {
+            frames: 0, result: None,
+        }));
+        let thread_progress = progress.clone();
+        let cam_mutex = tile.cam_mutex.clone();
+
+        thread::spawn(move || {
+            let n = pixels*layout.channels();
+            let mut sum = vec![0u32; n];
+            let mut sq_sum = vec![0u32; n];
+            let (mut prev, mut frames) = (0u64, 0u32);
+            let mut flat_corrected = false;
+            while frames < total {
+                let samples = {
+                    let guard = cam_mutex.lock().unwrap();
+                    let skip = layout == Layout::Rgb && !guard.converted;
+                    if guard.ts == prev || skip {
+                        drop(guard);
+                        thread::sleep(Duration::from_millis(2));
+                        continue;
+                    }
+                    prev = guard.ts;
+                    flat_corrected |= guard.flat.is_some();
+                    layout.samples(&guard, pixels)
+                };
+                let iter = sum.iter_mut().zip(sq_sum.iter_mut()).zip(samples);
+                for ((s, sq), v) in iter {
+                    let v = u32::from(v);
+                    *s += v;
+                    *sq += v*v;
+                }
+                frames += 1;
+                thread_progress.lock().unwrap().frames = frames;
+            }
+
+            // unbiased variance estimate
+            let k = f64::from(frames);
+            let (mean, std) = sum.iter().zip(sq_sum.iter()).map(|(&s, &sq)| {
+                let m = f64::from(s)/k;
+                let var = (f64::from(sq) - k*m*m)/(k - 1.);
+                (m as f32, var.max(0.).sqrt() as f32)
+            }).unzip();
+            let stats = NoiseStats {
+                frames, ts: prev, flat_corrected, mean, std,
+            };
+            thread_progress.lock().unwrap().result = Some(stats);
+        });
+
+        Measurement {
+            total,
+            layout,
+            resolution: res,
+            rois: rois.to_vec(),
+            progress,
+            stats: None,
+            summary: Vec::new(),
+            map: None,
+        }
+    }
+
+    /// Number of accumulated frames
+    pub fn frames(&self) -> u32 {
+        match self.stats {
+            Some(ref stats) => stats.frames,
+            None => self.progress.lock().unwrap().frames,
+        }
+    }
+
+    /// Per-pixel temporal mean and standard deviation, `None` if
+    /// measurement is not finished
+    pub fn stats(&self) -> Option<(&[f32], &[f32])> {
+        self.stats.as_ref().map(|s| (&s.mean[..], &s.std[..]))
+    }
+
+    /// Check if flat-field correction was applied to accumulated frames
+    pub fn flat_corrected(&self) -> bool {
+        self.stats.as_ref().map_or(false, |s| s.flat_corrected)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stats.is_some()
+    }
+
+    /// Fetch result of the accumulation thread, returns `true` if the
+    /// measurement has just finished
+    pub fn update(&mut self) -> bool {
+        if self.stats.is_some() { return false; }
+        let stats = match self.progress.lock().unwrap().result.take() {
+            Some(stats) => stats,
+            None => return false,
+        };
+        let res = self.resolution;
+        let mut regions = vec![
+            ("full".to_string(), Roi { pos: [0, 0], size: res }),
+        ];
+        regions.extend(self.rois.iter().map(|r| {
+            let name = format!(
+                "{},{} {}x{}", r.pos[0], r.pos[1], r.size[0], r.size[1],
+            );
+            (name, *r)
+        }));
+        self.summary = regions.into_iter()
+            .flat_map(|(name, roi)| self.summarize(&stats, name, roi))
+            .collect();
+        self.stats = Some(stats);
+        true
+    }
+
+    /// Compute per-channel summary of the region
+    fn summarize(&self, stats: &NoiseStats, name: String, roi: Roi)
+        -> Vec<Summary>
+    {
+        let res = self.resolution;
+        let (w, h) = (res[0] as usize, res[1] as usize);
+        let x1 = ((roi.pos[0] + roi.size[0]) as usize).min(w);
+        let y1 = ((roi.pos[1] + roi.size[1]) as usize).min(h);
+        let nc = self.layout.channels();
+
+        // sums of mean, squared mean and variance for each channel
+        let mut acc: Vec<(&'static str, usize, [f64; 3])> = Vec::new();
+        for y in (roi.pos[1] as usize)..y1 {
+            for x in (roi.pos[0] as usize)..x1 {
+                for c in 0..nc {
+                    let i = (y*w + x)*nc + c;
+                    let (m, s) = (f64::from(stats.mean[i]), stats.std[i]);
+                    let channel = self.layout.channel(x, y, c);
+                    let pos = match acc.iter().position(|a| a.0 == channel) {
+                        Some(pos) => pos,
+                        None => {
+                            acc.push((channel, 0, [0.; 3]));
+                            acc.len() - 1
+                        },
+                    };
+                    let a = &mut acc[pos];
+                    a.1 += 1;
+                    a.2[0] += m;
+                    a.2[1] += m*m;
+                    a.2[2] += f64::from(s)*f64::from(s);
+                }
+            }
+        }
+
+        let frames = f64::from(stats.frames);
+        acc.into_iter().map(|(channel, n, s)| {
+            let n = n as f64;
+            let mean = s[0]/n;
+            let temporal_var = s[2]/n;
+            // temporal noise averaged over frames still adds to the
+            // spatial variance of the mean
+            let spatial_var = s[1]/n - mean*mean - temporal_var/frames;
+            Summary {
+                roi: name.clone(),
+                channel,
+                mean,
+                temporal: temporal_var.sqrt(),
+                fpn: spatial_var.max(0.).sqrt(),
+            }
+        }).collect()
+    }
+
+    /// Get RGBA texture data with the noise map, `None` if measurement is
+    /// not finished or view is off
+    pub fn map(&mut self, view: NoiseView) -> Option<Vec<[u8; 4]>> {
+        if view == NoiseView::Off { return None; }
+        match self.map {
+            Some((v, ref map)) if v == view => return Some(map.clone()),
+            _ => (),
+        }
+        let map = {
+            let stats = self.stats.as_ref()?;
+            let full: Vec<&Summary> = self.summary.iter()
+                .filter(|s| s.roi == "full")
+                .collect();
+            let max = |f: &Fn(&Summary) -> f64| full.iter()
+                .map(|s| f(s))
+                .fold(0., f64::max);
+            let w = self.resolution[0] as usize;
+            let nc = self.layout.channels();
+            let layout = self.layout;
+
+            let values: Vec<f32> = match view {
+                NoiseView::Mean => stats.mean.clone(),
+                NoiseView::Temporal => {
+                    let k = 255./(MAP_RANGE*max(&|s| s.temporal)).max(1e-3);
+                    stats.std.iter().map(|&s| s*(k as f32)).collect()
+                },
+                NoiseView::FixedPattern => {
+                    let k = 255./(MAP_RANGE*max(&|s| s.fpn)).max(1e-3);
+                    stats.mean.iter().enumerate().map(|(i, &m)| {
+                        let p = i/nc;
+                        let channel = layout.channel(p % w, p / w, i % nc);
+                        let channel_mean = full.iter()
+                            .find(|s| s.channel == channel)
+                            .map(|s| s.mean)
+                            .unwrap_or(0.);
+                        ((f64::from(m) - channel_mean).abs()*k) as f32
+                    }).collect()
+                },
+                NoiseView::Off => unreachable!(),
+            };
+
+            let to_u8 = |v: f32| v.round().max(0.).min(255.) as u8;
+            if nc == 3 {
+                values.chunks(3)
+                    .map(|p| [to_u8(p[0]), to_u8(p[1]), to_u8(p[2]), 255])
+                    .collect::<Vec<_>>()
+            } else {
+                values.iter()
+                    .map(|&v| { let v = to_u8(v); [v, v, v, 255] })
+                    .collect()
+            }
+        };
+        self.map = Some((view, map.clone()));
+        Some(map)
+    }
+
+    /// Save mean and standard deviation images as
+    /// `noise_<ts><suffix>_{mean,std}.<ext>`, returns paths of the written
+    /// files
+    pub fn export(&self, suffix: &str, format: ExportFormat)
+        -> Result<Vec<String>, Box<Error>>
+    {
+        let stats = match self.stats {
+            Some(ref stats) => stats,
+            None => Err("noise measurement is not finished")?,
+        };
+        let prefix = format!("noise_{}{}", stats.ts, suffix);
+        let res = self.resolution;
+        let nc = self.layout.channels();
+        let mut paths = Vec::new();
+        let images = [("mean", &stats.mean), ("std", &stats.std)];
+        for &(name, data) in images.iter() {
+            let path = match format {
+                ExportFormat::Npy => {
+                    let path = format!("{}_{}.npy", prefix, name);
+                    let mut shape = vec![res[1] as usize, res[0] as usize];
+                    if nc == 3 { shape.push(3); }
+                    save::npy_f32(&path, data, &shape)?;
+                    path
+                },
+                ExportFormat::Tiff => {
+                    let path = format!("{}_{}.tiff", prefix, name);
+                    // 8.8 fixed point keeps fractional part of the values
+                    let data: Vec<u16> = data.iter()
+                        .map(|&v| (v*256.).round().max(0.).min(65535.) as u16)
+                        .collect();
+                    save::tiff16(&path, &data, res, nc, "")?;
+                    path
+                },
+            };
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+/// Poll running measurements and report finished ones
+pub(crate) fn update(state: &mut EngineState) {
+    let n = state.tiles.len();
+    for (i, tile) in state.tiles.iter_mut().enumerate() {
+        let m = match tile.noise {
+            Some(ref mut m) => m,
+            None => continue,
+        };
+        if !m.update() { continue; }
+        tile.update_texture = true;
+
+        eprintln!("Noise measurement of {} ({} frames):", tile.name, m.total);
+        for s in m.summary.iter() {
+            eprintln!(
+                "  {} {}: mean {:.2}, temporal {:.3}, FPN {:.3}",
+                s.roi, s.channel, s.mean, s.temporal, s.fpn,
+            );
+        }
+        let msg = if n > 1 {
+            format!("noise measurement of camera {} finished", i + 1)
+        } else {
+            "noise measurement finished".to_string()
+        };
+        state.osd.notify(msg);
+    }
+}
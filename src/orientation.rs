@@ -0,0 +1,126 @@
+/// Image orientation on the screen
+///
+/// Orientation is stored as a signed permutation matrix which maps sensor
+/// coordinates relative to the image center into display coordinates
+/// (x axis points right, y axis points down).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Orientation {
+    m: [[i8; 2]; 2],
+}
+
+const ROTATE_CW: [[i8; 2]; 2] = [[0, -1], [1, 0]];
+const ROTATE_CCW: [[i8; 2]; 2] = [[0, 1], [-1, 0]];
+const FLIP_H: [[i8; 2]; 2] = [[-1, 0], [0, 1]];
+const FLIP_V: [[i8; 2]; 2] = [[1, 0], [0, -1]];
+
+fn mul(a: [[i8; 2]; 2], b: [[i8; 2]; 2]) -> [[i8; 2]; 2] {
+    [
+        [a[0][0]*b[0][0] + a[0][1]*b[1][0], a[0][0]*b[0][1] + a[0][1]*b[1][1]],
+        [a[1][0]*b[0][0] + a[1][1]*b[1][0], a[1][0]*b[0][1] + a[1][1]*b[1][1]],
+    ]
+}
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Orientation { m: [[1, 0], [0, 1]] }
+    }
+}
+
+impl Orientation {
+    /// Create orientation which rotates image clockwise by `rotation`
+    /// degrees (must be a multiple of 90) and after that mirrors it
+    pub fn new(rotation: u32, flip_h: bool, flip_v: bool) -> Self {
+        assert_eq!(rotation % 90, 0);
+        let mut o = Self::default();
+        for _ in 0..(rotation/90) % 4 { o = o.rotate_cw(); }
+        if flip_h { o = o.flip_h(); }
+        if flip_v { o = o.flip_v(); }
+        o
+    }
+
+    pub fn rotate_cw(self) -> Self {
+        Orientation { m: mul(ROTATE_CW, self.m) }
+    }
+
+    pub fn rotate_ccw(self) -> Self {
+        Orientation { m: mul(ROTATE_CCW, self.m) }
+    }
+
+    /// Mirror displayed image horizontally
+    pub fn flip_h(self) -> Self {
+        Orientation { m: mul(FLIP_H, self.m) }
+    }
+
+    /// Mirror displayed image vertically
+    pub fn flip_v(self) -> Self {
+        Orientation { m: mul(FLIP_V, self.m) }
+    }
+
+    /// Returns `true` if image axes are swapped, i.e. image is rotated by 90
+    /// or 270 degrees
+    pub fn is_transposed(&self) -> bool {
+        self.m[0][0] == 0
+    }
+
+    /// Get image resolution after applying orientation
+    pub fn apply_res(&self, res: [u32; 2]) -> [u32; 2] {
+        if self.is_transposed() { [res[1], res[0]] } else { res }
+    }
+
+    /// Column-major matrix which converts display coordinates into sensor
+    /// coordinates, used by the shaders
+    pub fn shader_matrix(&self) -> [[f32; 2]; 2] {
+        let m = self.m;
+        [
+            [m[0][0] as f32, m[0][1] as f32],
+            [m[1][0] as f32, m[1][1] as f32],
+        ]
+    }
+
+    /// Clockwise rotation in degrees and horizontal mirroring applied after
+    /// it which together are equivalent to this orientation
+    pub fn decompose(&self) -> (u32, bool) {
+        let det = self.m[0][0]*self.m[1][1] - self.m[0][1]*self.m[1][0];
+        let flipped = det < 0;
+        let m = if flipped { mul(FLIP_H, self.m) } else { self.m };
+        let rotation = match m {
+            [[1, 0], [0, 1]] => 0,
+            [[0, -1], [1, 0]] => 90,
+            [[-1, 0], [0, -1]] => 180,
+            _ => 270,
+        };
+        (rotation, flipped)
+    }
+
+    pub fn name(&self) -> String {
+        match self.decompose() {
+            (r, false) => format!("rotation {}", r),
+            (r, true) => format!("rotation {}, mirrored", r),
+        }
+    }
+
+    /// Apply orientation to image data with the given resolution
+    pub fn apply<T: Copy>(&self, buf: &[T], res: [u32; 2]) -> Vec<T> {
+        assert_eq!(buf.len(), (res[0]*res[1]) as usize);
+        let (w, h) = (res[0] as i64, res[1] as i64);
+        let [dw, dh] = self.apply_res(res);
+        let (dw, dh) = (dw as i64, dh as i64);
+        let m = self.m;
+
+        let mut out = Vec::with_capacity(buf.len());
+        for dy in 0..dh {
+            for dx in 0..dw {
+                // doubled coordinates of the pixel center relative
+                // to the image center
+                let cx = 2*dx + 1 - dw;
+                let cy = 2*dy + 1 - dh;
+                let sx = (m[0][0] as i64)*cx + (m[1][0] as i64)*cy;
+                let sy = (m[0][1] as i64)*cx + (m[1][1] as i64)*cy;
+                let x = (sx + w - 1)/2;
+                let y = (sy + h - 1)/2;
+                out.push(buf[(y*w + x) as usize]);
+            }
+        }
+        out
+    }
+}
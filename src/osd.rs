@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use super::{ColorVertex, EngineState};
+use events::get_dims;
+use defects::DefectKind;
+use diff::DiffMode;
+use overlay::pixel_size;
+use stereo::{self, StereoMode};
+use text;
+
+/// How long notifications stay on the screen
+const NOTIFICATION_TIME: Duration = Duration::from_secs(3);
+/// Maximum number of simultaneously shown notifications
+const MAX_NOTIFICATIONS: usize = 5;
+/// Font pixel size in logical pixels
+const FONT_SCALE: f32 = 2.;
+/// Distance between text and window border in font pixels
+const MARGIN: f32 = 4.;
+
+const TEXT_COLOR: [f32; 4] = [1., 1., 1., 1.];
+const BACKGROUND_COLOR: [f32; 4] = [0., 0., 0., 0.6];
+
+/// On-screen display state
+pub(crate) struct Osd {
+    /// Show information panel
+    pub on: bool,
+    /// Show FPS counter even if information panel is off
+    pub fps_on: bool,
+    pub fps: f32,
+    notifications: VecDeque<(Instant, String)>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd {
+            on: false,
+            fps_on: false,
+            fps: 0.,
+            notifications: VecDeque::new(),
+        }
+    }
+
+    /// Show transient message
+    pub fn notify<S: Into<String>>(&mut self, msg: S) {
+        if self.notifications.len() == MAX_NOTIFICATIONS {
+            self.notifications.pop_front();
+        }
+        self.notifications.push_back((Instant::now(), msg.into()));
+    }
+
+    /// Remove expired notifications
+    pub fn cleanup(&mut self) {
+        while let Some(&(t, _)) = self.notifications.front() {
+            if t.elapsed() < NOTIFICATION_TIME { break; }
+            self.notifications.pop_front();
+        }
+    }
+}
+
+fn fourcc(f: &[u8; 4]) -> String {
+    f.iter().map(|&c| c as char).collect()
+}
+
+/// Build information panel text
+fn info(state: &EngineState) -> String {
+    let osd = &state.osd;
+    let mut lines = vec![format!("FPS: {:.1}", osd.fps)];
+    if osd.on {
+        let tile = state.tile();
+        let res = tile.resolution;
+        let ts = tile.frame_ts;
+        if state.tiles.len() > 1 {
+            lines.push(format!(
+                "camera {}/{}: {}",
+                state.active + 1, state.tiles.len(), tile.name,
+            ));
+        }
+        lines.push(format!("{}x{} {}", res[0], res[1], fourcc(&tile.format)));
+        lines.push(format!(
+            "camera FPS: {:.1}, drops: {}", tile.fps, tile.drops,
+        ));
+        if tile.bad_frames != 0 {
+            lines.push(format!("bad frames: {}", tile.bad_frames));
+        }
+        lines.push(format!("zoom: {:.0}%", 100.*pixel_size(state, tile)));
+        lines.push(format!("view: {}", state.view_mode.name()));
+        let conv = tile.conversion;
+        if tile.is_bayer {
+            lines.push(format!("demosaic: {}", conv.algorithm.name()));
+            let auto = state.isp.auto_wb && conv.isp.enabled;
+            lines.push(format!(
+                "color processing: {}{}",
+                conv.isp.name(), if auto { " (auto WB)" } else { "" },
+            ));
+        }
+        if &tile.format == b"YUYV" {
+            lines.push(format!("YUV: {}", conv.yuv.name()));
+        }
+        if state.gpu_convert {
+            let on = if tile.gpu_display { "GPU" } else { "CPU" };
+            lines.push(format!("conversion: {}", on));
+        }
+        lines.push(state.orientation.name());
+        if state.diff_mode != DiffMode::Off {
+            lines.push(format!(
+                "difference: {}, gain: x{}, motion: {:.1}%",
+                state.diff_mode.name(), state.diff_gain, tile.diff.motion,
+            ));
+        }
+        match tile.noise {
+            Some(ref m) if m.is_done() => {
+                lines.push(format!(
+                    "noise: {} frames, map: {}",
+                    m.total, state.noise.view.name(),
+                ));
+                for s in m.summary.iter() {
+                    lines.push(format!(
+                        "{} {}: mean {:.2}, temporal {:.3}, FPN {:.3}",
+                        s.roi, s.channel, s.mean, s.temporal, s.fpn,
+                    ));
+                }
+            },
+            Some(ref m) => {
+                lines.push(format!("noise: {}/{} frames", m.frames(), m.total));
+            },
+            None => (),
+        }
+        let map = &tile.defects;
+        if !map.defects.is_empty() {
+            lines.push(format!(
+                "defects: {} hot, {} dead, {} stuck{}",
+                map.count(DefectKind::Hot), map.count(DefectKind::Dead),
+                map.count(DefectKind::Stuck),
+                if state.defects.correct_on { ", corrected" } else { "" },
+            ));
+        }
+        if let Some(ref map) = tile.flat {
+            lines.push(format!(
+                "flat field: max gain x{:.2}{}",
+                map.max_gain(),
+                if state.flat.correct_on { ", corrected" } else { "" },
+            ));
+        }
+        if tile.push_consts.undistort != 0 {
+            lines.push("undistorted".to_string());
+        }
+        lines.push(format!("ts: {}.{:06}", ts/1_000_000, ts%1_000_000));
+        let stats = state.sync.stats();
+        if state.tiles.len() > 1 {
+            lines.push(format!(
+                "synced sets: {}{}, tolerance: {:.1} ms",
+                stats.sets,
+                if state.sync.is_displayed() { " (shown)" } else { "" },
+                state.sync.tolerance as f32/1000.,
+            ));
+        }
+        if state.sync.is_recording() {
+            lines.push(format!(
+                "REC: {} sets, {} skipped",
+                stats.recorded, stats.record_dropped,
+            ));
+        }
+        let capture = &state.capture;
+        if capture.on {
+            let board = match capture.found() {
+                Some(true) => "found",
+                Some(false) => "not found",
+                None => "waiting",
+            };
+            let [c, r] = capture.pattern;
+            lines.push(format!("board {}x{}: {}", c, r, board));
+            lines.push(format!(
+                "views: {}, coverage: {:.0}%{}",
+                capture.views(), 100.*capture.coverage(),
+                if capture.auto { ", auto" } else { "" },
+            ));
+        }
+        if state.pause.load(Ordering::Relaxed) {
+            lines.push("PAUSED".to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+/// Draw text block on a semi-transparent background
+fn draw_block(
+    out: &mut Vec<ColorVertex>, msg: &str, pos: [f32; 2], scale: f32,
+    dims: [f32; 2],
+) {
+    let [w, h] = text::text_size(msg, scale);
+    let pad = scale;
+    text::draw_rect(
+        out, [pos[0] - pad, pos[1] - pad], [w + 2.*pad, h + pad],
+        BACKGROUND_COLOR, dims,
+    );
+    text::draw(out, msg, pos, scale, TEXT_COLOR, dims);
+}
+
+/// Build vertices for the information panel in the top left corner, stereo
+/// pair status at the top center and notifications in the bottom left
+/// corner of the window
+pub(crate) fn build(state: &EngineState) -> Vec<ColorVertex> {
+    let mut out = Vec::new();
+    let dims = get_dims(state);
+    let scale = (FONT_SCALE*state.hidpi as f32).round();
+    let margin = MARGIN*scale;
+
+    if state.osd.on || state.osd.fps_on {
+        draw_block(&mut out, &info(state), [margin, margin], scale, dims);
+    }
+
+    if state.stereo != StereoMode::Off {
+        let msg = format!(
+            "stereo: {}, right - left: {:+.3} ms",
+            state.stereo.name(), stereo::ts_diff(state),
+        );
+        let w = text::text_size(&msg, scale)[0];
+        draw_block(&mut out, &msg, [(dims[0] - w)/2., margin], scale, dims);
+    }
+
+    let mut y = dims[1] - margin;
+    for &(_, ref msg) in state.osd.notifications.iter().rev() {
+        y -= text::text_size(msg, scale)[1] + scale;
+        draw_block(&mut out, msg, [margin, y], scale, dims);
+    }
+    out
+}
+
+/// Build status lines with camera name, format, frame rate, number of
+/// drops and bad frames and synchronization offset in the top right corner
+/// of the tile
+pub(crate) fn tile_status(state: &EngineState, i: usize)
+    -> Vec<ColorVertex>
+{
+    let mut out = Vec::new();
+    let tile = &state.tiles[i];
+    let stats = state.sync.stats();
+    let scale = (state.hidpi as f32).max(1.).round();
+    let margin = MARGIN*scale;
+    let res = tile.resolution;
+    let mut msg = format!(
+        "{} {} {}x{} {:.1} FPS, drops: {}\n\
+        sync offset: {:+.3} ms, unmatched: {}",
+        tile.name, fourcc(&tile.format), res[0], res[1], tile.fps, tile.drops,
+        stats.offsets[i] as f64/1000., stats.unmatched[i],
+    );
+    if tile.bad_frames != 0 {
+        msg += &format!("\nbad frames: {}", tile.bad_frames);
+    }
+    if state.diff_mode != DiffMode::Off {
+        msg += &format!("\nmotion: {:.1}%", tile.diff.motion);
+    }
+    let w = text::text_size(&msg, scale)[0];
+    let pos = [(tile.dims[0] - w - margin).max(margin), margin];
+    draw_block(&mut out, &msg, pos, scale, tile.dims);
+    out
+}
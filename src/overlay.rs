@@ -0,0 +1,102 @@
+use super::{ColorVertex, EngineState};
+use cam::FrameBuf;
+use text;
+use tile::Tile;
+use view;
+
+/// Minimal size of a sensor pixel in screen pixels for the pixel grid
+const PIXEL_GRID_MIN: f32 = 8.;
+const PIXEL_GRID_COLOR: [f32; 4] = [0.5, 0.5, 0.5, 0.6];
+
+/// Size of a sensor pixel of the tile in screen pixels
+pub(crate) fn pixel_size(state: &EngineState, tile: &Tile) -> f32 {
+    let pc = &tile.push_consts;
+    let res = state.orientation.apply_res(tile.resolution);
+    tile.dims[0]*pc.aspect[0]*pc.zoom/(res[0] as f32)
+}
+
+/// Get range of sensor pixels which are visible in the tile
+fn visible_range(tile: &Tile) -> ([u32; 2], [u32; 2]) {
+    let res = tile.resolution;
+    let a = tile.push_consts.from_ndc([-1., -1.], res);
+    let b = tile.push_consts.from_ndc([1., 1.], res);
+    let clamp = |v: f32, max: u32| v.max(0.).min(max as f32) as u32;
+    // window corners may swap places in sensor coordinates depending
+    // on the image orientation
+    let (x0, x1) = (a[0].min(b[0]), a[0].max(b[0]));
+    let (y0, y1) = (a[1].min(b[1]), a[1].max(b[1]));
+    (
+        [clamp(x0.floor(), res[0]), clamp(x1.ceil(), res[0])],
+        [clamp(y0.floor(), res[1]), clamp(y1.ceil(), res[1])],
+    )
+}
+
+/// Build lines separating sensor pixels, lines are generated only for the
+/// visible part of the image and only if pixels are large enough
+pub(crate) fn pixel_grid(state: &EngineState, tile: &Tile)
+    -> Vec<ColorVertex>
+{
+    let mut lines = Vec::new();
+    if pixel_size(state, tile) < PIXEL_GRID_MIN { return lines; }
+
+    let ([x0, x1], [y0, y1]) = visible_range(tile);
+    let pc = &tile.push_consts;
+    let res = tile.resolution;
+    let color = PIXEL_GRID_COLOR;
+    for x in x0..=x1 {
+        let a = pc.to_ndc([x as f32, y0 as f32], res);
+        let b = pc.to_ndc([x as f32, y1 as f32], res);
+        lines.push(ColorVertex { position: a, color });
+        lines.push(ColorVertex { position: b, color });
+    }
+    for y in y0..=y1 {
+        let a = pc.to_ndc([x0 as f32, y as f32], res);
+        let b = pc.to_ndc([x1 as f32, y as f32], res);
+        lines.push(ColorVertex { position: a, color });
+        lines.push(ColorVertex { position: b, color });
+    }
+    lines
+}
+
+/// Build text with numeric values of the visible pixels, text is generated
+/// only if pixels are large enough to fit it
+pub(crate) fn pixel_values(state: &EngineState, tile: &Tile, frame: &FrameBuf)
+    -> Vec<ColorVertex>
+{
+    let mut vertices = Vec::new();
+
+    let scale = (state.hidpi as f32).max(1.).round();
+    let mode = state.view_mode.for_source(tile.is_bayer);
+    let sample = match mode {
+        view::ViewMode::Color => "255\n255\n255",
+        _ => "255",
+    };
+    let [tw, th] = text::text_size(sample, scale);
+    let size = pixel_size(state, tile);
+    if size < 1.5*tw || size < 1.5*th { return vertices; }
+
+    let dims = tile.dims;
+    let pc = &tile.push_consts;
+    let res = tile.resolution;
+    let ([x0, x1], [y0, y1]) = visible_range(tile);
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let i = (y*res[0] + x) as usize;
+            let (val, brightness) = view::pixel_value(mode, frame, i);
+            let color = if brightness < 128 {
+                [1., 1., 1., 1.]
+            } else {
+                [0., 0., 0., 1.]
+            };
+
+            let c = pc.to_ndc([x as f32 + 0.5, y as f32 + 0.5], res);
+            let [w, h] = text::text_size(&val, scale);
+            let pos = [
+                (c[0] + 1.)*dims[0]/2. - w/2.,
+                (c[1] + 1.)*dims[1]/2. - h/2.,
+            ];
+            text::draw(&mut vertices, &val, pos, scale, color, dims);
+        }
+    }
+    vertices
+}
@@ -0,0 +1,127 @@
+//! Splitting of CPU frame conversion between threads
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Minimal number of rows converted by one thread, smaller frames are not
+/// worth the thread start overhead
+const MIN_BAND_ROWS: usize = 64;
+
+/// Number of conversion threads per frame, zero divides the available cores
+/// between the cameras
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+/// Number of cameras converting frames at the same time
+static CAMERAS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn set_threads(n: usize) {
+    THREADS.store(n, Ordering::Relaxed);
+}
+
+pub fn set_cameras(n: usize) {
+    CAMERAS.store(n.max(1), Ordering::Relaxed);
+}
+
+/// Number of threads used to convert a single frame
+pub fn threads() -> usize {
+    match THREADS.load(Ordering::Relaxed) {
+        0 => {
+            let cores = thread::available_parallelism()
+                .map(|n| n.get()).unwrap_or(1);
+            (cores/CAMERAS.load(Ordering::Relaxed)).max(1)
+        },
+        n => n,
+    }
+}
+
+/// Split image `buf` of `rows` rows into contiguous bands consisting of
+/// whole groups of `step` rows and process them in parallel, `f` receives
+/// index of the first band row and the band data
+pub fn for_each_band<T, F>(buf: &mut [T], rows: usize, step: usize, f: F)
+    where T: Send, F: Fn(usize, &mut [T]) + Sync
+{
+    assert_eq!(rows % step, 0);
+    let groups = rows/step;
+    let n = threads().min(rows/MIN_BAND_ROWS).min(groups).max(1);
+    if n == 1 {
+        f(0, buf);
+        return;
+    }
+    let band_rows = step*((groups + n - 1)/n);
+    let band_len = band_rows*(buf.len()/rows);
+
+    let f = &f;
+    thread::scope(|s| {
+        let mut bands = buf.chunks_mut(band_len).enumerate();
+        // the first band is processed by the calling thread
+        let first = bands.next();
+        for (i, band) in bands {
+            s.spawn(move || f(i*band_rows, band));
+        }
+        if let Some((_, band)) = first {
+            f(0, band);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use demosaic::{self, Conversion};
+    use isp;
+    use rggb::{self, Algorithm};
+    use testutil::random_frame;
+    use yuv::Encoding;
+
+    #[test]
+    fn bands_cover_all_rows() {
+        let rows = 4*MIN_BAND_ROWS + 2;
+        let mut buf = vec![0; 5*rows];
+        for_each_band(&mut buf, rows, 2, |y0, band| {
+            for (i, row) in band.chunks_mut(5).enumerate() {
+                for v in row.iter_mut() { *v += y0 + i; }
+            }
+        });
+        let expected: Vec<usize> = (0..rows)
+            .flat_map(|y| vec![y; 5])
+            .collect();
+        assert_eq!(buf, expected);
+    }
+
+    /// Conversion split between threads matches the single-threaded one,
+    /// thread count is global, so all conversions are checked in one test
+    #[test]
+    fn bands_match_single_thread() {
+        let (w, h) = (70, 3*MIN_BAND_ROWS + 2);
+        let raw = random_frame(w*h, 7);
+        let yuyv = random_frame(2*w*h, 7);
+        let convert = |threads: usize| {
+            set_threads(threads);
+            let mut out = Vec::new();
+            for &algorithm in [Algorithm::Bilinear, Algorithm::Malvar].iter() {
+                let mut buf = vec![0; 3*w*h];
+                match algorithm {
+                    Algorithm::Bilinear =>
+                        rggb::demosaic(&raw, &mut buf, w, h),
+                    Algorithm::Malvar =>
+                        rggb::demosaic_malvar(&raw, &mut buf, w, h),
+                }
+                out.push(buf);
+            }
+            let conv = Conversion {
+                algorithm: Algorithm::Bilinear,
+                yuv: Encoding::default(),
+                isp: isp::Params::default(),
+            };
+            let mut buf = vec![[0; 3]; w*h];
+            let res = [w as u32, h as u32];
+            demosaic::demosaic(*b"YUYV", res, &mut buf, &yuyv, &conv);
+            out.push(buf.iter().flat_map(|p| p.to_vec()).collect());
+            out
+        };
+        let single = convert(1);
+        let parallel = convert(3);
+        set_threads(0);
+        for (i, (a, b)) in single.iter().zip(parallel.iter()).enumerate() {
+            assert!(a == b, "conversion {} differs", i);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::f32::consts::PI;
+
+/// Number of segments used to approximate circles
+const CIRCLE_SEGMENTS: usize = 128;
+/// Half-size of the reference marker cross in sensor pixels
+const MARKER_SIZE: f32 = 16.;
+
+/// Reticle shape, all coordinates are given in sensor pixels
+#[derive(Debug, Clone)]
+pub(crate) enum Shape {
+    Line([f32; 2], [f32; 2]),
+    Circle { center: [f32; 2], radius: f32 },
+    /// Closed polygon
+    Polygon(Vec<[f32; 2]>),
+    /// Reference marker drawn as a small cross
+    Marker([f32; 2]),
+}
+
+impl Shape {
+    fn push_lines(&self, out: &mut Vec<[f32; 2]>) {
+        match *self {
+            Shape::Line(a, b) => out.extend_from_slice(&[a, b]),
+            Shape::Circle { center, radius } => {
+                let point = |i: usize| {
+                    let phi = 2.*PI*(i as f32)/(CIRCLE_SEGMENTS as f32);
+                    [center[0] + radius*phi.cos(), center[1] + radius*phi.sin()]
+                };
+                for i in 0..CIRCLE_SEGMENTS {
+                    out.extend_from_slice(&[point(i), point(i + 1)]);
+                }
+            },
+            Shape::Polygon(ref points) => {
+                let n = points.len();
+                for i in 0..n {
+                    out.extend_from_slice(&[points[i], points[(i + 1) % n]]);
+                }
+            },
+            Shape::Marker(p) => {
+                let s = MARKER_SIZE;
+                out.extend_from_slice(&[
+                    [p[0] - s, p[1]], [p[0] + s, p[1]],
+                    [p[0], p[1] - s], [p[0], p[1] + s],
+                ]);
+            },
+        }
+    }
+}
+
+/// Set of shapes drawn over the image
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reticle {
+    pub shapes: Vec<Shape>,
+}
+
+impl Reticle {
+    /// Load reticle definition from file
+    ///
+    /// File contains one shape per line, coordinates are given in sensor
+    /// pixels. Empty lines and lines starting with `#` are ignored:
+    ///
+    /// ```text
+    /// line <x1> <y1> <x2> <y2>
+    /// circle <x> <y> <radius>
+    /// polygon <x1> <y1> <x2> <y2> <x3> <y3> ...
+    /// marker <x> <y>
+    /// ```
+    pub fn load(path: &str) -> Result<Self, Box<Error>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut shapes = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let mut tokens = line.split_whitespace();
+            let kind = tokens.next().unwrap_or("");
+            let vals = tokens
+                .map(|t| t.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|e| format!("{}:{}: {}", path, n + 1, e))?;
+
+            let shape = match (kind, vals.len()) {
+                ("line", 4) => Shape::Line(
+                    [vals[0], vals[1]], [vals[2], vals[3]],
+                ),
+                ("circle", 3) => Shape::Circle {
+                    center: [vals[0], vals[1]], radius: vals[2],
+                },
+                ("polygon", n) if n >= 6 && n % 2 == 0 => Shape::Polygon(
+                    vals.chunks(2).map(|c| [c[0], c[1]]).collect()
+                ),
+                ("marker", 2) => Shape::Marker([vals[0], vals[1]]),
+                _ => Err(format!(
+                    "{}:{}: invalid shape: {}", path, n + 1, line,
+                ))?,
+            };
+            shapes.push(shape);
+        }
+        Ok(Reticle { shapes })
+    }
+
+    /// Lines crossing at the image center
+    pub fn crosshair(res: [u32; 2]) -> Self {
+        let (w, h) = (res[0] as f32, res[1] as f32);
+        Reticle { shapes: vec![
+            Shape::Line([w/2., 0.], [w/2., h]),
+            Shape::Line([0., h/2.], [w, h/2.]),
+        ]}
+    }
+
+    /// Lines dividing image into thirds
+    pub fn thirds(res: [u32; 2]) -> Self {
+        let (w, h) = (res[0] as f32, res[1] as f32);
+        Reticle { shapes: vec![
+            Shape::Line([w/3., 0.], [w/3., h]),
+            Shape::Line([2.*w/3., 0.], [2.*w/3., h]),
+            Shape::Line([0., h/3.], [w, h/3.]),
+            Shape::Line([0., 2.*h/3.], [w, 2.*h/3.]),
+        ]}
+    }
+
+    /// Concentric circles with center at the image center
+    pub fn circles(res: [u32; 2], radii: &[f32]) -> Self {
+        let center = [(res[0] as f32)/2., (res[1] as f32)/2.];
+        let shapes = radii.iter()
+            .map(|&radius| Shape::Circle { center, radius })
+            .collect();
+        Reticle { shapes }
+    }
+
+    pub fn extend(&mut self, other: Reticle) {
+        self.shapes.extend(other.shapes);
+    }
+
+    /// Build line list in normalized sensor coordinates
+    pub fn lines(&self, res: [u32; 2]) -> Vec<[f32; 2]> {
+        let mut lines = Vec::new();
+        for shape in self.shapes.iter() {
+            shape.push_lines(&mut lines);
+        }
+        let (w, h) = ((res[0] as f32)/2., (res[1] as f32)/2.);
+        lines.iter().map(|p| [p[0]/w - 1., p[1]/h - 1.]).collect()
+    }
+}
+
+/// Parse point in the `x,y` format
+pub(crate) fn parse_point(s: &str) -> Result<[f32; 2], &'static str> {
+    let err = "point must be in the x,y format";
+    let mut iter = s.split(',').map(|v| v.trim().parse::<f32>());
+    match (iter.next(), iter.next(), iter.next()) {
+        (Some(Ok(x)), Some(Ok(y)), None) => Ok([x, y]),
+        _ => Err(err),
+    }
+}
@@ -1,31 +1,99 @@
-/// Demosaic image using bi-linear approach assuming BGGR pattern
+use parallel;
+#[cfg(target_arch = "x86_64")]
+use simd;
+
+/// Demosaicing algorithm used for raw Bayer frames
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Bilinear,
+    /// Malvar-He-Cutler gradient-corrected linear interpolation
+    Malvar,
+}
+
+impl Algorithm {
+    pub fn next(self) -> Self {
+        match self {
+            Algorithm::Bilinear => Algorithm::Malvar,
+            Algorithm::Malvar => Algorithm::Bilinear,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Bilinear => "bilinear",
+            Algorithm::Malvar => "Malvar-He-Cutler",
+        }
+    }
+}
+
+/// Parse demosaicing algorithm name
+pub fn parse_algorithm(s: &str) -> Result<Algorithm, &'static str> {
+    match s {
+        "bilinear" => Ok(Algorithm::Bilinear),
+        "malvar" => Ok(Algorithm::Malvar),
+        _ => Err("demosaicing algorithm must be bilinear or malvar"),
+    }
+}
+
+/// Demosaic image using bi-linear approach assuming BGGR pattern, row
+/// pairs are split between conversion threads
 pub fn demosaic(data: &[u8], buf: &mut [u8], width: usize, height: usize) {
     assert_eq!(data.len(), width*height);
     assert_eq!(width % 2, 0);
     assert_eq!(height % 2, 0);
     assert_eq!(buf.len(), 3*width*height);
 
-    unsafe {
-        top_left_corner(buf, data, width, height);
-        for x in (1..width/2 - 1).map(|v| 2*v) {
-            first_row(buf, data, x, width, height);
-        }
-        top_right_corner(buf, data, width, height);
+    parallel::for_each_band(buf, height, 2, |y0, band| {
+        demosaic_rows(data, band, y0, width, height);
+    });
+}
 
-        for y in (1..height/2 - 1).map(|v| 2*v) {
-            first_column(buf, data, y, width, height);
-            for x in (1..width/2 - 1).map(|v| 2*v) {
-                core(buf, data, x, y, width, height);
+/// Demosaic row pairs starting from the row `y0` into `band`
+fn demosaic_rows(data: &[u8], band: &mut [u8], y0: usize, w: usize, h: usize) {
+    for (i, buf) in band.chunks_mut(6*w).enumerate() {
+        let y = y0 + 2*i;
+        unsafe {
+            if y == 0 {
+                top_left_corner(buf, data, w, h);
+                for x in (1..w/2 - 1).map(|v| 2*v) {
+                    first_row(buf, data, x, w, h);
+                }
+                top_right_corner(buf, data, w, h);
+            } else if y == h - 2 {
+                bottom_left_corner(buf, data, w, h);
+                for x in (1..w/2 - 1).map(|v| 2*v) {
+                    last_row(buf, data, x, w, h);
+                }
+                bottom_right_corner(buf, data, w, h);
+            } else {
+                first_column(buf, data, y, w, h);
+                core_row(buf, data, y, w, h);
+                last_column(buf, data, y, w, h);
             }
-            last_column(buf, data, y, width, height);
         }
+    }
+}
 
-        bottom_left_corner(buf, data, width, height);
-        for x in (1..width/2 - 1).map(|v| 2*v) {
-            last_row(buf, data, x, width, height);
-        }
-        bottom_right_corner(buf, data, width, height);
+/// Interpolate inner pixels of the row pair `y`, output must be followed
+/// by the last column
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn core_row(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize) {
+    let mut x = 2;
+    while x + 8 <= w - 2 {
+        simd::core8(buf, data, x, y, w);
+        x += 8;
+    }
+    for x in (x/2..w/2 - 1).map(|v| 2*v) {
+        core(buf, data, x, y, w, h);
+    }
+}
 
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+unsafe fn core_row(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize) {
+    for x in (1..w/2 - 1).map(|v| 2*v) {
+        core(buf, data, x, y, w, h);
     }
 }
 
@@ -41,7 +109,7 @@ pub fn demosaic(data: &[u8], buf: &mut [u8], width: usize, height: usize) {
 //    b  g  b
 #[inline(always)]
 unsafe fn core(buf: &mut [u8], data: &[u8], x: usize, y: usize, w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
     debug_assert!(x > 0);
     debug_assert!(y > 0);
@@ -55,30 +123,30 @@ unsafe fn core(buf: &mut [u8], data: &[u8], x: usize, y: usize, w: usize, h: usi
     let g10 = get(data, x+2, y+1, w);
     let g12 = get(data, x+1, y+2, w);
 
-    set(buf, x, y, 1, w, (g1+g3+g5+g8)/4);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g10+g12)/4);
+    set(buf, x, 0, 1, w, (g1+g3+g5+g8)/4);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g10+g12)/4);
 
     let r0 = get(data, x-1, y-1, w);
     let r2 = get(data, x+1, y-1, w);
     let r7 = get(data, x-1, y+1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r0+r2+r7+r9)/4);
-    set(buf, x+1, y, 2, w, (r2+r9)/2);
-    set(buf, x, y+1, 2, w, (r7+r9)/2);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r0+r2+r7+r9)/4);
+    set(buf, x+1, 0, 2, w, (r2+r9)/2);
+    set(buf, x, 1, 2, w, (r7+r9)/2);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b6 = get(data, x+2, y, w);
     let b11 = get(data, x, y+2, w);
     let b13 = get(data, x+2, y+2, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, (b4+b6)/2);
-    set(buf, x, y+1, 0, w, (b4+b11)/2);
-    set(buf, x+1, y+1, 0, w, (b4+b6+b11+b13)/4);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, (b4+b6)/2);
+    set(buf, x, 1, 0, w, (b4+b11)/2);
+    set(buf, x+1, 1, 0, w, (b4+b6+b11+b13)/4);
 }
 
 #[inline(always)]
@@ -89,22 +157,23 @@ fn get_idx(x: usize, y: usize, width: usize) -> usize {
 #[inline(always)]
 unsafe fn get(data: &[u8], x: usize, y: usize, width: usize) -> u16 {
     let idx = get_idx(x, y, width);
-    debug_assert!(data.len() < idx);
+    debug_assert!(idx < data.len());
     debug_assert!(x < width);
     *data.get_unchecked(idx) as u16
 }
 
+/// Set color of the pixel in the row pair, `y` is 0 or 1
 #[inline(always)]
 unsafe fn set(data: &mut [u8], x: usize, y: usize, col: u8, width: usize, val: u16) {
     let idx = 3*get_idx(x, y, width) + col as usize;
-    debug_assert!(data.len() < idx);
+    debug_assert!(idx < data.len());
     debug_assert!(x < width);
     *(data.get_unchecked_mut(idx)) = val as u8;
 }
 
 #[inline(always)]
 unsafe fn first_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
     debug_assert!(x < w);
 
@@ -116,33 +185,33 @@ unsafe fn first_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
     let g10 = get(data, x+2, y+1, w);
     let g12 = get(data, x+1, y+2, w);
 
-    set(buf, x, y, 1, w, (g3+g5+g8)/3);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g10+g12)/4);
+    set(buf, x, 0, 1, w, (g3+g5+g8)/3);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g10+g12)/4);
 
     let r7 = get(data, x-1, y+1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r7+r9)/2);
-    set(buf, x+1, y, 2, w, r9);
-    set(buf, x, y+1, 2, w, (r7+r9)/2);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r7+r9)/2);
+    set(buf, x+1, 0, 2, w, r9);
+    set(buf, x, 1, 2, w, (r7+r9)/2);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b6 = get(data, x+2, y, w);
     let b11 = get(data, x, y+2, w);
     let b13 = get(data, x+2, y+2, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, (b4+b6)/2);
-    set(buf, x, y+1, 0, w, (b4+b11)/2);
-    set(buf, x+1, y+1, 0, w, (b4+b6+b11+b13)/4);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, (b4+b6)/2);
+    set(buf, x, 1, 0, w, (b4+b11)/2);
+    set(buf, x+1, 1, 0, w, (b4+b6+b11+b13)/4);
 }
 
 #[inline(always)]
 unsafe fn last_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
     debug_assert!(x < w);
 
@@ -154,33 +223,33 @@ unsafe fn last_row(buf: &mut [u8], data: &[u8], x: usize, w: usize, h: usize) {
     let g8 = get(data, x  , y+1, w);
     let g10 = get(data, x+2, y+1, w);
 
-    set(buf, x, y, 1, w, (g1+g3+g5+g8)/4);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g10)/3);
+    set(buf, x, 0, 1, w, (g1+g3+g5+g8)/4);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g10)/3);
 
     let r0 = get(data, x-1, y-1, w);
     let r2 = get(data, x+1, y-1, w);
     let r7 = get(data, x-1, y+1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r0+r2+r7+r9)/4);
-    set(buf, x+1, y, 2, w, (r2+r9)/2);
-    set(buf, x, y+1, 2, w, (r7+r9)/2);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r0+r2+r7+r9)/4);
+    set(buf, x+1, 0, 2, w, (r2+r9)/2);
+    set(buf, x, 1, 2, w, (r7+r9)/2);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b6 = get(data, x+2, y, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, (b4+b6)/2);
-    set(buf, x, y+1, 0, w, b4);
-    set(buf, x+1, y+1, 0, w, (b4+b6)/2);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, (b4+b6)/2);
+    set(buf, x, 1, 0, w, b4);
+    set(buf, x+1, 1, 0, w, (b4+b6)/2);
 }
 
 #[inline(always)]
 unsafe fn first_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
     debug_assert!(y < h);
 
@@ -192,33 +261,33 @@ unsafe fn first_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize
     let g10 = get(data, x+2, y+1, w);
     let g12 = get(data, x+1, y+2, w);
 
-    set(buf, x, y, 1, w, (g1+g5+g8)/3);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g10+g12)/4);
+    set(buf, x, 0, 1, w, (g1+g5+g8)/3);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g10+g12)/4);
 
     let r2 = get(data, x+1, y-1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r2+r9)/2);
-    set(buf, x+1, y, 2, w, (r2+r9)/2);
-    set(buf, x, y+1, 2, w, r9);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r2+r9)/2);
+    set(buf, x+1, 0, 2, w, (r2+r9)/2);
+    set(buf, x, 1, 2, w, r9);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b6 = get(data, x+2, y, w);
     let b11 = get(data, x, y+2, w);
     let b13 = get(data, x+2, y+2, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, (b4+b6)/2);
-    set(buf, x, y+1, 0, w, (b4+b11)/2);
-    set(buf, x+1, y+1, 0, w, (b4+b6+b11+b13)/4);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, (b4+b6)/2);
+    set(buf, x, 1, 0, w, (b4+b11)/2);
+    set(buf, x+1, 1, 0, w, (b4+b6+b11+b13)/4);
 }
 
 #[inline(always)]
 unsafe fn last_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
     debug_assert!(y < h);
 
@@ -230,33 +299,33 @@ unsafe fn last_column(buf: &mut [u8], data: &[u8], y: usize, w: usize, h: usize)
     let g8 = get(data, x  , y+1, w);
     let g12 = get(data, x+1, y+2, w);
 
-    set(buf, x, y, 1, w, (g1+g3+g5+g8)/4);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g12)/3);
+    set(buf, x, 0, 1, w, (g1+g3+g5+g8)/4);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g12)/3);
 
     let r0 = get(data, x-1, y-1, w);
     let r2 = get(data, x+1, y-1, w);
     let r7 = get(data, x-1, y+1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r0+r2+r7+r9)/4);
-    set(buf, x+1, y, 2, w, (r2+r9)/2);
-    set(buf, x, y+1, 2, w, (r7+r9)/2);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r0+r2+r7+r9)/4);
+    set(buf, x+1, 0, 2, w, (r2+r9)/2);
+    set(buf, x, 1, 2, w, (r7+r9)/2);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b11 = get(data, x, y+2, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, b4);
-    set(buf, x, y+1, 0, w, (b4+b11)/2);
-    set(buf, x+1, y+1, 0, w, (b4+b11)/2);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, b4);
+    set(buf, x, 1, 0, w, (b4+b11)/2);
+    set(buf, x+1, 1, 0, w, (b4+b11)/2);
 }
 
 #[inline(always)]
 unsafe fn top_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
 
     let x = 0;
@@ -267,32 +336,32 @@ unsafe fn top_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     let g10 = get(data, x+2, y+1, w);
     let g12 = get(data, x+1, y+2, w);
 
-    set(buf, x, y, 1, w, (g5+g8)/2);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g10+g12)/4);
+    set(buf, x, 0, 1, w, (g5+g8)/2);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g10+g12)/4);
 
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, r9);
-    set(buf, x+1, y, 2, w, r9);
-    set(buf, x, y+1, 2, w, r9);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, r9);
+    set(buf, x+1, 0, 2, w, r9);
+    set(buf, x, 1, 2, w, r9);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b6 = get(data, x+2, y, w);
     let b11 = get(data, x, y+2, w);
     let b13 = get(data, x+2, y+2, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, (b4+b6)/2);
-    set(buf, x, y+1, 0, w, (b4+b11)/2);
-    set(buf, x+1, y+1, 0, w, (b4+b6+b11+b13)/4);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, (b4+b6)/2);
+    set(buf, x, 1, 0, w, (b4+b11)/2);
+    set(buf, x+1, 1, 0, w, (b4+b6+b11+b13)/4);
 }
 
 #[inline(always)]
 unsafe fn top_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
 
     let x = w - 2;
@@ -303,31 +372,31 @@ unsafe fn top_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     let g8 = get(data, x  , y+1, w);
     let g12 = get(data, x+1, y+2, w);
 
-    set(buf, x, y, 1, w, (g3+g5+g8)/3);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g12)/3);
+    set(buf, x, 0, 1, w, (g3+g5+g8)/3);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g12)/3);
 
     let r7 = get(data, x-1, y+1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r7+r9)/2);
-    set(buf, x+1, y, 2, w, r9);
-    set(buf, x, y+1, 2, w, (r7+r9)/2);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r7+r9)/2);
+    set(buf, x+1, 0, 2, w, r9);
+    set(buf, x, 1, 2, w, (r7+r9)/2);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b11 = get(data, x, y+2, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, b4);
-    set(buf, x, y+1, 0, w, (b4+b11)/2);
-    set(buf, x+1, y+1, 0, w, (b4+b11)/2);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, b4);
+    set(buf, x, 1, 0, w, (b4+b11)/2);
+    set(buf, x+1, 1, 0, w, (b4+b11)/2);
 }
 
 #[inline(always)]
 unsafe fn bottom_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
 
     let x = 0;
@@ -338,31 +407,31 @@ unsafe fn bottom_left_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     let g8 = get(data, x  , y+1, w);
     let g10 = get(data, x+2, y+1, w);
 
-    set(buf, x, y, 1, w, (g1+g5+g8)/3);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8+g10)/3);
+    set(buf, x, 0, 1, w, (g1+g5+g8)/3);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8+g10)/3);
 
     let r2 = get(data, x+1, y-1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r2+r9)/2);
-    set(buf, x+1, y, 2, w, (r2+r9)/2);
-    set(buf, x, y+1, 2, w, r9);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r2+r9)/2);
+    set(buf, x+1, 0, 2, w, (r2+r9)/2);
+    set(buf, x, 1, 2, w, r9);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
     let b6 = get(data, x+2, y, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, (b4+b6)/2);
-    set(buf, x, y+1, 0, w, b4);
-    set(buf, x+1, y+1, 0, w, (b4+b6)/2);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, (b4+b6)/2);
+    set(buf, x, 1, 0, w, b4);
+    set(buf, x+1, 1, 0, w, (b4+b6)/2);
 }
 
 #[inline(always)]
 unsafe fn bottom_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
-    debug_assert!(buf.len() == 3*w*h);
+    debug_assert!(buf.len() == 6*w);
     debug_assert!(data.len() == w*h);
 
     let x = w - 2;
@@ -373,25 +442,205 @@ unsafe fn bottom_right_corner(buf: &mut [u8], data: &[u8], w: usize, h: usize) {
     let g5 = get(data, x+1, y, w);
     let g8 = get(data, x  , y+1, w);
 
-    set(buf, x, y, 1, w, (g1+g3+g5+g8)/4);
-    set(buf, x+1, y, 1, w, g5);
-    set(buf, x, y+1, 1, w, g8);
-    set(buf, x+1, y+1, 1, w, (g5+g8)/2);
+    set(buf, x, 0, 1, w, (g1+g3+g5+g8)/4);
+    set(buf, x+1, 0, 1, w, g5);
+    set(buf, x, 1, 1, w, g8);
+    set(buf, x+1, 1, 1, w, (g5+g8)/2);
 
     let r0 = get(data, x-1, y-1, w);
     let r2 = get(data, x+1, y-1, w);
     let r7 = get(data, x-1, y+1, w);
     let r9 = get(data, x+1, y+1, w);
 
-    set(buf, x, y, 2, w, (r0+r2+r7+r9)/4);
-    set(buf, x+1, y, 2, w, (r2+r9)/2);
-    set(buf, x, y+1, 2, w, (r7+r9)/2);
-    set(buf, x+1, y+1, 2, w, r9);
+    set(buf, x, 0, 2, w, (r0+r2+r7+r9)/4);
+    set(buf, x+1, 0, 2, w, (r2+r9)/2);
+    set(buf, x, 1, 2, w, (r7+r9)/2);
+    set(buf, x+1, 1, 2, w, r9);
 
     let b4 = get(data, x  , y, w);
 
-    set(buf, x, y, 0, w, b4);
-    set(buf, x+1, y, 0, w, b4);
-    set(buf, x, y+1, 0, w, b4);
-    set(buf, x+1, y+1, 0, w, b4);
+    set(buf, x, 0, 0, w, b4);
+    set(buf, x+1, 0, 0, w, b4);
+    set(buf, x, 1, 0, w, b4);
+    set(buf, x+1, 1, 0, w, b4);
+}
+
+/// Demosaic image using Malvar-He-Cutler gradient-corrected linear
+/// interpolation, two pixels wide border is interpolated bi-linearly
+pub fn demosaic_malvar(
+    data: &[u8], buf: &mut [u8], width: usize, height: usize,
+) {
+    assert_eq!(data.len(), width*height);
+    assert_eq!(width % 2, 0);
+    assert_eq!(height % 2, 0);
+    assert_eq!(buf.len(), 3*width*height);
+
+    parallel::for_each_band(buf, height, 2, |y0, band| {
+        demosaic_rows(data, band, y0, width, height);
+        if width < 6 || height < 6 { return; }
+
+        for (i, buf) in band.chunks_mut(6*width).enumerate() {
+            let y = y0 + 2*i;
+            if y == 0 || y == height - 2 { continue; }
+            for x in (1..width/2 - 1).map(|v| 2*v) {
+                malvar_core(buf, data, x, y, width);
+            }
+        }
+    });
+}
+
+// Filter coefficients are multiplied by 16 to keep them integer, offsets
+// are given in pixels relative to the interpolated one.
+
+/// Green at a red or blue pixel
+#[inline(always)]
+fn g_at_rb<F: Fn(isize, isize) -> i32>(p: &F) -> i32 {
+    8*p(0, 0)
+        + 4*(p(0, -1) + p(0, 1) + p(-1, 0) + p(1, 0))
+        - 2*(p(0, -2) + p(0, 2) + p(-2, 0) + p(2, 0))
+}
+
+/// Red or blue at a green pixel which has pixels of the target color as its
+/// horizontal neighbors
+#[inline(always)]
+fn rb_at_g_row<F: Fn(isize, isize) -> i32>(p: &F) -> i32 {
+    10*p(0, 0)
+        + 8*(p(-1, 0) + p(1, 0))
+        - 2*(p(-2, 0) + p(2, 0))
+        - 2*(p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1))
+        + p(0, -2) + p(0, 2)
+}
+
+/// Red or blue at a green pixel which has pixels of the target color as its
+/// vertical neighbors
+#[inline(always)]
+fn rb_at_g_column<F: Fn(isize, isize) -> i32>(p: &F) -> i32 {
+    10*p(0, 0)
+        + 8*(p(0, -1) + p(0, 1))
+        - 2*(p(0, -2) + p(0, 2))
+        - 2*(p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1))
+        + p(-2, 0) + p(2, 0)
+}
+
+/// Red at a blue pixel or blue at a red pixel
+#[inline(always)]
+fn rb_at_br<F: Fn(isize, isize) -> i32>(p: &F) -> i32 {
+    12*p(0, 0)
+        + 4*(p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1))
+        - 3*(p(0, -2) + p(0, 2) + p(-2, 0) + p(2, 0))
+}
+
+#[inline(always)]
+fn clamp16(v: i32) -> u16 {
+    ((v + 8) >> 4).max(0).min(255) as u16
+}
+
+/// Interpolate 2x2 block with the red pixel at `[x, y]` into the row pair
+/// `buf`, block must be at least two pixels away from the image border
+#[inline(always)]
+fn malvar_core(buf: &mut [u8], data: &[u8], x: usize, y: usize, w: usize) {
+    debug_assert!(x >= 2 && y >= 2 && x + 3 < w);
+    debug_assert!((y + 3)*w < data.len());
+
+    let at = |x: usize, y: usize| {
+        move |dx: isize, dy: isize| {
+            let i = ((y as isize + dy)*(w as isize) + x as isize + dx) as usize;
+            unsafe { i32::from(*data.get_unchecked(i)) }
+        }
+    };
+    let (r, gr, gb, b) = (at(x, y), at(x+1, y), at(x, y+1), at(x+1, y+1));
+
+    unsafe {
+        set(buf, x, 0, 1, w, clamp16(g_at_rb(&r)));
+        set(buf, x, 0, 2, w, clamp16(rb_at_br(&r)));
+
+        set(buf, x+1, 0, 0, w, clamp16(rb_at_g_row(&gr)));
+        set(buf, x+1, 0, 2, w, clamp16(rb_at_g_column(&gr)));
+
+        set(buf, x, 1, 0, w, clamp16(rb_at_g_column(&gb)));
+        set(buf, x, 1, 2, w, clamp16(rb_at_g_row(&gb)));
+
+        set(buf, x+1, 1, 0, w, clamp16(rb_at_br(&b)));
+        set(buf, x+1, 1, 1, w, clamp16(g_at_rb(&b)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::random_frame;
+
+    #[test]
+    fn core_rows_match_scalar() {
+        // widths which leave pixels for the scalar code after SIMD blocks
+        for &(w, h) in [(10, 6), (22, 8), (38, 6), (70, 6)].iter() {
+            let data = random_frame(w*h, w as u32);
+            let mut buf = vec![0; 3*w*h];
+            demosaic(&data, &mut buf, w, h);
+
+            let mut expected = buf.clone();
+            for (i, rows) in expected.chunks_mut(6*w).enumerate() {
+                let y = 2*i;
+                if y == 0 || y == h - 2 { continue; }
+                for x in (1..w/2 - 1).map(|v| 2*v) {
+                    unsafe { core(rows, &data, x, y, w, h); }
+                }
+            }
+            assert!(buf == expected, "width {}", w);
+        }
+    }
+
+    /// Mean of the neighbors of `(x, y)` at `offsets` inside the image,
+    /// which is the edge rule of the GPU shader
+    fn mean(
+        data: &[u8], x: usize, y: usize, w: usize, h: usize,
+        offsets: &[(isize, isize)],
+    ) -> u8 {
+        let (mut sum, mut n) = (0, 0);
+        for &(dx, dy) in offsets {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx >= 0 && ny >= 0 && nx < w as isize && ny < h as isize {
+                sum += data[ny as usize*w + nx as usize] as u32;
+                n += 1;
+            }
+        }
+        (sum/n) as u8
+    }
+
+    #[test]
+    fn border_rows_match_shader() {
+        let cross = [(0, -1), (0, 1), (-1, 0), (1, 0)];
+        let diag = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+        let row = [(-1, 0), (1, 0)];
+        let col = [(0, -1), (0, 1)];
+        let (w, h) = (10, 6);
+        let data = random_frame(w*h, 7);
+        let mut buf = vec![0; 3*w*h];
+        demosaic(&data, &mut buf, w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let c = data[y*w + x];
+                let m = |o: &[(isize, isize)]| mean(&data, x, y, w, h, o);
+                let expected = match (x % 2, y % 2) {
+                    (0, 0) => [c, m(&cross), m(&diag)],
+                    (1, 0) => [m(&row), c, m(&col)],
+                    (0, _) => [m(&col), c, m(&row)],
+                    _ => [m(&diag), m(&cross), c],
+                };
+                let i = 3*(y*w + x);
+                assert_eq!(&buf[i..i + 3], &expected[..], "({}, {})", x, y);
+            }
+        }
+
+        // the two pixels wide border of Malvar-He-Cutler is bi-linear
+        let mut malvar = vec![0; 3*w*h];
+        demosaic_malvar(&data, &mut malvar, w, h);
+        for y in 0..h {
+            for x in 0..w {
+                if x >= 2 && y >= 2 && x < w - 2 && y < h - 2 { continue; }
+                let i = 3*(y*w + x);
+                assert_eq!(&malvar[i..i + 3], &buf[i..i + 3], "({}, {})", x, y);
+            }
+        }
+    }
 }
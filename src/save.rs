@@ -0,0 +1,500 @@
+use png::HasParameters;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::slice;
+
+/// Write frame as an 8-bit PNG image, for greyscale frames only the first
+/// channel is stored
+pub(crate) fn png(path: &str, frame: &[[u8; 3]], res: [u32; 2], grey: bool)
+    -> Result<(), Box<Error>>
+{
+    png_described(path, frame, res, grey, "")
+}
+
+/// Write frame as an 8-bit PNG image with a `Description` text chunk if
+/// `description` is not empty
+pub(crate) fn png_described(
+    path: &str, frame: &[[u8; 3]], res: [u32; 2], grey: bool,
+    description: &str,
+) -> Result<(), Box<Error>> {
+    let file = File::create(path)?;
+    let mut bw = BufWriter::new(file);
+    let mut encoder = png::Encoder::new(&mut bw, res[0], res[1]);
+    encoder.set(png::BitDepth::Eight);
+    encoder.set(if grey {
+        png::ColorType::Grayscale
+    } else {
+        png::ColorType::RGB
+    });
+    let mut w = encoder.write_header()?;
+    if !description.is_empty() {
+        // tEXt chunk consists of keyword and Latin-1 text separated by NUL
+        let mut chunk = b"Description\0".to_vec();
+        chunk.extend(description.chars().map(|c| {
+            if (c as u32) < 256 { c as u8 } else { b'?' }
+        }));
+        w.write_chunk(*b"tEXt", &chunk)?;
+    }
+    if grey {
+        let data: Vec<u8> = frame.iter().map(|p| p[0]).collect();
+        w.write_image_data(&data)?;
+    } else {
+        w.write_image_data(rgb_bytes(frame))?;
+    }
+    Ok(())
+}
+
+fn rgb_bytes(frame: &[[u8; 3]]) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(frame.as_ptr() as *const u8, 3*frame.len())
+    }
+}
+
+/// Write frame as a binary 8-bit PPM image or, for greyscale frames, PGM
+/// image with the first channel, non-empty `comment` is stored in the
+/// header
+pub(crate) fn pnm(
+    path: &str, frame: &[[u8; 3]], res: [u32; 2], grey: bool, comment: &str,
+) -> Result<(), Box<Error>> {
+    let mut bw = BufWriter::new(File::create(path)?);
+    writeln!(bw, "{}", if grey { "P5" } else { "P6" })?;
+    for line in comment.lines() {
+        writeln!(bw, "# {}", line)?;
+    }
+    writeln!(bw, "{} {}\n255", res[0], res[1])?;
+    if grey {
+        let data: Vec<u8> = frame.iter().map(|p| p[0]).collect();
+        bw.write_all(&data)?;
+    } else {
+        bw.write_all(rgb_bytes(frame))?;
+    }
+    bw.flush()?;
+    Ok(())
+}
+
+/// Write data as is
+pub(crate) fn raw(path: &str, data: &[u8]) -> Result<(), Box<Error>> {
+    let mut bw = BufWriter::new(File::create(path)?);
+    bw.write_all(data)?;
+    bw.flush()?;
+    Ok(())
+}
+
+/// Write 8-bit data as a binary PGM image with the given number of rows,
+/// row length is derived from the data length
+pub(crate) fn pgm(path: &str, data: &[u8], height: u32)
+    -> Result<(), Box<Error>>
+{
+    let height = height as usize;
+    if height == 0 || data.len() % height != 0 {
+        Err("data length is not a multiple of the image height")?
+    }
+    let mut bw = BufWriter::new(File::create(path)?);
+    write!(bw, "P5\n{} {}\n255\n", data.len()/height, height)?;
+    bw.write_all(data)?;
+    bw.flush()?;
+    Ok(())
+}
+
+/// Write 32-bit floats as a NumPy array with the given row-major shape
+pub(crate) fn npy_f32(path: &str, data: &[f32], shape: &[usize])
+    -> Result<(), Box<Error>>
+{
+    if shape.iter().product::<usize>() != data.len() {
+        Err("data length does not match the array shape")?
+    }
+    let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}",
+        dims.join(", "),
+    );
+    // magic, version and header length take 10 bytes, header is padded
+    // with spaces and terminated by a newline to align data to 64 bytes
+    while (10 + header.len() + 1) % 64 != 0 { header.push(' '); }
+    header.push('\n');
+
+    let mut bw = BufWriter::new(File::create(path)?);
+    bw.write_all(b"\x93NUMPY\x01\x00")?;
+    let len = header.len() as u16;
+    bw.write_all(&[len as u8, (len >> 8) as u8])?;
+    bw.write_all(header.as_bytes())?;
+    for v in data {
+        let bits = v.to_bits();
+        bw.write_all(&[
+            bits as u8, (bits >> 8) as u8, (bits >> 16) as u8,
+            (bits >> 24) as u8,
+        ])?;
+    }
+    bw.flush()?;
+    Ok(())
+}
+
+/// Value of a TIFF directory entry
+pub(crate) enum TiffValue {
+    Byte(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<[u32; 2]>),
+    SRational(Vec<[i32; 2]>),
+}
+
+impl TiffValue {
+    /// Field type code and number of values
+    fn kind(&self) -> (u16, usize) {
+        match *self {
+            TiffValue::Byte(ref v) => (1, v.len()),
+            // NUL terminator is counted
+            TiffValue::Ascii(ref s) => (2, s.len() + 1),
+            TiffValue::Short(ref v) => (3, v.len()),
+            TiffValue::Long(ref v) => (4, v.len()),
+            TiffValue::Rational(ref v) => (5, v.len()),
+            TiffValue::SRational(ref v) => (10, v.len()),
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            TiffValue::Byte(ref v) => out.extend_from_slice(v),
+            TiffValue::Ascii(ref s) => {
+                // TIFF strings are 7-bit ASCII
+                out.extend(s.bytes().map(|b| if b < 128 { b } else { b'?' }));
+                out.push(0);
+            },
+            TiffValue::Short(ref v) => for &x in v {
+                out.extend_from_slice(&u16_le(x));
+            },
+            TiffValue::Long(ref v) => for &x in v {
+                out.extend_from_slice(&u32_le(x));
+            },
+            TiffValue::Rational(ref v) => for r in v {
+                out.extend_from_slice(&u32_le(r[0]));
+                out.extend_from_slice(&u32_le(r[1]));
+            },
+            TiffValue::SRational(ref v) => for r in v {
+                out.extend_from_slice(&u32_le(r[0] as u32));
+                out.extend_from_slice(&u32_le(r[1] as u32));
+            },
+        }
+        out
+    }
+}
+
+fn u16_le(v: u16) -> [u8; 2] {
+    [v as u8, (v >> 8) as u8]
+}
+
+fn u32_le(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+/// Write uncompressed little-endian TIFF file with a single image stored
+/// as one strip, strip offset and size tags are added to `entries`
+pub(crate) fn tiff(path: &str, data: &[u8], mut entries: Vec<(u16, TiffValue)>)
+    -> Result<(), Box<Error>>
+{
+    // header, image data, IFD and values longer than 4 bytes, the IFD and
+    // the values start on word boundaries
+    let data_len = data.len() as u32;
+    let ifd = (8 + data_len + 1) & !1;
+    entries.push((273, TiffValue::Long(vec![8])));
+    entries.push((279, TiffValue::Long(vec![data_len])));
+    entries.sort_by_key(|e| e.0);
+
+    let mut extra = Vec::new();
+    let extra_offset = ifd + 2 + 12*entries.len() as u32 + 4;
+    let mut dir = Vec::new();
+    dir.extend_from_slice(&u16_le(entries.len() as u16));
+    for &(tag, ref value) in entries.iter() {
+        let (kind, count) = value.kind();
+        let mut bytes = value.bytes();
+        dir.extend_from_slice(&u16_le(tag));
+        dir.extend_from_slice(&u16_le(kind));
+        dir.extend_from_slice(&u32_le(count as u32));
+        if bytes.len() <= 4 {
+            // values shorter than 4 bytes are stored left-justified
+            bytes.resize(4, 0);
+            dir.extend_from_slice(&bytes);
+        } else {
+            let offset = extra_offset + extra.len() as u32;
+            dir.extend_from_slice(&u32_le(offset));
+            extra.extend_from_slice(&bytes);
+            if extra.len() % 2 != 0 { extra.push(0); }
+        }
+    }
+    dir.extend_from_slice(&u32_le(0));
+
+    let mut bw = BufWriter::new(File::create(path)?);
+    bw.write_all(b"II*\x00")?;
+    bw.write_all(&u32_le(ifd))?;
+    bw.write_all(data)?;
+    if data.len() % 2 != 0 { bw.write_all(&[0])?; }
+    bw.write_all(&dir)?;
+    bw.write_all(&extra)?;
+    bw.flush()?;
+    Ok(())
+}
+
+/// Write 16-bit data as an uncompressed little-endian TIFF image with one
+/// (greyscale) or three (RGB) interleaved channels, non-empty
+/// `description` is stored in the image description tag
+pub(crate) fn tiff16(
+    path: &str, data: &[u16], res: [u32; 2], channels: usize,
+    description: &str,
+) -> Result<(), Box<Error>> {
+    if channels != 1 && channels != 3 {
+        Err("TIFF image must have one or three channels")?
+    }
+    if (res[0]*res[1]) as usize*channels != data.len() {
+        Err("data length does not match the image size")?
+    }
+    let photometric = if channels == 3 { 2 } else { 1 };
+    let mut entries = vec![
+        (256, TiffValue::Long(vec![res[0]])),
+        (257, TiffValue::Long(vec![res[1]])),
+        (258, TiffValue::Short(vec![16; channels])),
+        (259, TiffValue::Short(vec![1])),
+        (262, TiffValue::Short(vec![photometric])),
+        (277, TiffValue::Short(vec![channels as u16])),
+        (278, TiffValue::Long(vec![res[1]])),
+        (284, TiffValue::Short(vec![1])),
+    ];
+    if !description.is_empty() {
+        entries.push((270, TiffValue::Ascii(description.to_string())));
+    }
+    let bytes: Vec<u8> = data.iter()
+        .flat_map(|&v| u16_le(v).to_vec())
+        .collect();
+    tiff(path, &bytes, entries)
+}
+
+/// Description of the raw Bayer mosaic stored in a DNG image
+pub(crate) struct Dng {
+    /// Unique name of the camera model
+    pub model: String,
+    /// Colors of the 2x2 filter cell in row order: 0 is red, 1 is green
+    /// and 2 is blue
+    pub cfa: [u8; 4],
+    pub black_level: f32,
+    /// CIE XYZ to camera RGB matrix
+    pub color_matrix: [[f32; 3]; 3],
+    /// Camera RGB of the white point of the shot
+    pub neutral: [f32; 3],
+    pub description: String,
+}
+
+/// Colors of the 2x2 filter cell of the Bayer format in the DNG notation,
+/// `None` for other formats
+pub(crate) fn cfa_pattern(format: [u8; 4]) -> Option<[u8; 4]> {
+    match &format {
+        b"RGGB" => Some([0, 1, 1, 2]),
+        b"GRBG" => Some([1, 0, 2, 1]),
+        b"GBRG" => Some([1, 2, 0, 1]),
+        b"BGGR" => Some([2, 1, 1, 0]),
+        _ => None,
+    }
+}
+
+/// Fixed-point rational with 4 decimal digits
+fn rational(v: f32) -> [u32; 2] {
+    [(v.max(0.)*10000.).round() as u32, 10000]
+}
+
+/// Write raw 8-bit Bayer mosaic as a DNG image
+pub(crate) fn dng(path: &str, raw: &[u8], res: [u32; 2], info: &Dng)
+    -> Result<(), Box<Error>>
+{
+    if (res[0]*res[1]) as usize != raw.len() {
+        Err("data length does not match the image size")?
+    }
+    let matrix = info.color_matrix.iter()
+        .flat_map(|row| row.iter())
+        .map(|&v| [(v*10000.).round() as i32, 10000])
+        .collect();
+    let mut entries = vec![
+        // main image with full resolution raw data
+        (254, TiffValue::Long(vec![0])),
+        (256, TiffValue::Long(vec![res[0]])),
+        (257, TiffValue::Long(vec![res[1]])),
+        (258, TiffValue::Short(vec![8])),
+        (259, TiffValue::Short(vec![1])),
+        // color filter array
+        (262, TiffValue::Short(vec![32803])),
+        (277, TiffValue::Short(vec![1])),
+        (278, TiffValue::Long(vec![res[1]])),
+        (284, TiffValue::Short(vec![1])),
+        (305, TiffValue::Ascii("cam-vis".to_string())),
+        (33421, TiffValue::Short(vec![2, 2])),
+        (33422, TiffValue::Byte(info.cfa.to_vec())),
+        // DNG version 1.4, readable by 1.1 readers
+        (50706, TiffValue::Byte(vec![1, 4, 0, 0])),
+        (50707, TiffValue::Byte(vec![1, 1, 0, 0])),
+        (50708, TiffValue::Ascii(info.model.clone())),
+        (50714, TiffValue::Rational(vec![rational(info.black_level)])),
+        (50717, TiffValue::Long(vec![255])),
+        (50721, TiffValue::SRational(matrix)),
+        (50728, TiffValue::Rational(
+            info.neutral.iter().map(|&v| rational(v)).collect(),
+        )),
+        // D65
+        (50778, TiffValue::Short(vec![21])),
+    ];
+    if !info.description.is_empty() {
+        entries.push((270, TiffValue::Ascii(info.description.clone())));
+    }
+    tiff(path, raw, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        let name = format!("cam-vis-{}-{}", ::std::process::id(), name);
+        env::temp_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    fn read_u16(b: &[u8], pos: usize) -> u16 {
+        u16::from(b[pos]) | u16::from(b[pos + 1]) << 8
+    }
+
+    fn read_u32(b: &[u8], pos: usize) -> u32 {
+        u32::from(read_u16(b, pos)) | u32::from(read_u16(b, pos + 2)) << 16
+    }
+
+    /// IFD entries as tag, type, count and value or offset
+    fn ifd(b: &[u8]) -> Vec<(u16, u16, u32, u32)> {
+        assert_eq!(&b[..4], b"II*\x00");
+        let pos = read_u32(b, 4) as usize;
+        assert_eq!(pos % 2, 0, "IFD is not word aligned");
+        let n = read_u16(b, pos) as usize;
+        assert_eq!(read_u32(b, pos + 2 + 12*n), 0, "more than one IFD");
+        (0..n).map(|i| {
+            let e = pos + 2 + 12*i;
+            (read_u16(b, e), read_u16(b, e + 2), read_u32(b, e + 4),
+                read_u32(b, e + 8))
+        }).collect()
+    }
+
+    fn entry(entries: &[(u16, u16, u32, u32)], tag: u16) -> (u16, u32, u32) {
+        let e = entries.iter().find(|e| e.0 == tag).expect("missing tag");
+        (e.1, e.2, e.3)
+    }
+
+    #[test]
+    fn tiff16_layout() {
+        let path = temp_path("layout.tiff");
+        let data: Vec<u16> = (0..3*2*3).map(|i| i*1000).collect();
+        tiff16(&path, &data, [3, 2], 3, "{}").unwrap();
+        let b = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let entries = ifd(&b);
+        let tags: Vec<u16> = entries.iter().map(|e| e.0).collect();
+        assert_eq!(tags, [256, 257, 258, 259, 262, 270, 273, 277, 278, 279,
+            284]);
+        assert_eq!(entry(&entries, 256), (4, 1, 3));
+        assert_eq!(entry(&entries, 257), (4, 1, 2));
+        assert_eq!(entry(&entries, 262), (3, 1, 2));
+
+        let (_, count, offset) = entry(&entries, 258);
+        assert_eq!(count, 3);
+        let offset = offset as usize;
+        assert_eq!(offset % 2, 0);
+        let bits: Vec<u16> = (0..3).map(|i| read_u16(&b, offset + 2*i))
+            .collect();
+        assert_eq!(bits, [16, 16, 16]);
+        // description fits into the entry
+        assert_eq!(entry(&entries, 270), (2, 3, u32::from_le_bytes(
+            [b'{', b'}', 0, 0])));
+
+        let (_, _, strip) = entry(&entries, 273);
+        let (_, _, len) = entry(&entries, 279);
+        assert_eq!((strip, len), (8, 36));
+        let stored: Vec<u16> = (0..data.len())
+            .map(|i| read_u16(&b, strip as usize + 2*i))
+            .collect();
+        assert_eq!(stored, data);
+    }
+
+    #[test]
+    fn dng_layout() {
+        let path = temp_path("layout.dng");
+        let raw: Vec<u8> = (0..9).collect();
+        let info = Dng {
+            model: "Test camera".to_string(),
+            cfa: cfa_pattern(*b"GRBG").unwrap(),
+            black_level: 16.5,
+            color_matrix: [[1., -0.5, 0.], [0., 1., 0.], [0., 0., 1.]],
+            neutral: [0.5, 1., 0.25],
+            description: String::new(),
+        };
+        dng(&path, &raw, [3, 3], &info).unwrap();
+        let b = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // odd length data is padded so that the IFD is word aligned
+        assert_eq!(read_u32(&b, 4), 18);
+        let entries = ifd(&b);
+        assert_eq!(entries.len(), 22);
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0),
+            "tags are not sorted");
+        assert_eq!(entry(&entries, 273), (4, 1, 8));
+        assert_eq!(entry(&entries, 279), (4, 1, 9));
+        assert_eq!(&b[8..17], &raw[..]);
+
+        assert_eq!(entry(&entries, 262), (3, 1, 32803));
+        assert_eq!(entry(&entries, 33422), (1, 4, u32::from_le_bytes(
+            [1, 0, 2, 1])));
+
+        let (kind, count, offset) = entry(&entries, 50714);
+        assert_eq!((kind, count), (5, 1));
+        let offset = offset as usize;
+        assert_eq!((read_u32(&b, offset), read_u32(&b, offset + 4)),
+            (165000, 10000));
+
+        let (kind, count, offset) = entry(&entries, 50721);
+        assert_eq!((kind, count), (10, 9));
+        let offset = offset as usize;
+        assert_eq!(read_u32(&b, offset + 8) as i32, -5000);
+        assert_eq!(read_u32(&b, offset + 12), 10000);
+
+        let (_, count, offset) = entry(&entries, 50708);
+        let model = &b[offset as usize..][..count as usize];
+        assert_eq!(model, b"Test camera\x00");
+    }
+
+    #[test]
+    fn npy_layout() {
+        let path = temp_path("layout.npy");
+        let data = [0.5f32, -1., 2., 3., 4., 5.];
+        npy_f32(&path, &data, &[2, 3]).unwrap();
+        let b = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&b[..8], b"\x93NUMPY\x01\x00");
+        let len = read_u16(&b, 8) as usize;
+        assert_eq!((10 + len) % 64, 0);
+        let header = String::from_utf8(b[10..10 + len].to_vec()).unwrap();
+        assert!(header.starts_with(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3,), }"));
+        assert!(header.ends_with('\n'));
+
+        assert_eq!(b.len(), 10 + len + 4*data.len());
+        let stored: Vec<f32> = (0..data.len())
+            .map(|i| f32::from_bits(read_u32(&b, 10 + len + 4*i)))
+            .collect();
+        assert_eq!(stored, data);
+    }
+
+    #[test]
+    fn npy_rejects_wrong_shape() {
+        let path = temp_path("shape.npy");
+        assert!(npy_f32(&path, &[0.; 5], &[2, 3]).is_err());
+    }
+}
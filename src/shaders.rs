@@ -19,6 +19,27 @@ pub mod fs3 {
     }
 }
 
+pub mod fs4 {
+    vulkano_shaders::shader!{
+        ty: "fragment",
+        path: "src/shaders/fs4.glsl"
+    }
+}
+
+pub mod fs5 {
+    vulkano_shaders::shader!{
+        ty: "fragment",
+        path: "src/shaders/fs5.glsl"
+    }
+}
+
+pub mod cs {
+    vulkano_shaders::shader!{
+        ty: "compute",
+        path: "src/shaders/cs.glsl"
+    }
+}
+
 pub mod vs {
     vulkano_shaders::shader!{
         ty: "vertex",
@@ -39,3 +60,10 @@ pub mod vs3 {
         path: "src/shaders/vs3.glsl"
     }
 }
+
+pub mod vs4 {
+    vulkano_shaders::shader!{
+        ty: "vertex",
+        path: "src/shaders/vs4.glsl"
+    }
+}
@@ -0,0 +1,163 @@
+#version 450
+layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+// raw frame bytes packed into little-endian words
+layout(set = 0, binding = 0) readonly buffer Raw {
+    uint data[];
+} raw;
+// RGBA pixels packed into words
+layout(set = 0, binding = 1) writeonly buffer Rgba {
+    uint data[];
+} rgba;
+layout(push_constant) uniform pushConstants {
+    uint width;
+    uint height;
+    // 0: GREY, 1: RGGB, 2: YUYV, 3: RGB3, 4: BGR3
+    uint format;
+    // 0: bilinear, 1: Malvar-He-Cutler
+    uint algorithm;
+    // fixed-point YUV conversion coefficients multiplied by 2^13
+    int y_offset;
+    int y_scale;
+    int cr_to_r;
+    int cb_to_g;
+    int cr_to_g;
+    int cb_to_b;
+} pc;
+
+float byte_at(uint i) {
+    return float((raw.data[i >> 2] >> ((i & 3u)*8u)) & 0xffu);
+}
+
+bool inside(ivec2 p) {
+    return all(greaterThanEqual(p, ivec2(0)))
+        && all(lessThan(p, ivec2(pc.width, pc.height)));
+}
+
+// raw sample, coordinates are clamped to the image
+float px(ivec2 p) {
+    p = clamp(p, ivec2(0), ivec2(pc.width, pc.height) - 1);
+    return byte_at(uint(p.y)*pc.width + uint(p.x));
+}
+
+// sum and number of the neighbors of `p` at offsets `a` and `b` which lie
+// inside the image
+vec2 pair(ivec2 p, ivec2 a, ivec2 b) {
+    vec2 s = vec2(0.);
+    if (inside(p + a)) { s += vec2(px(p + a), 1.); }
+    if (inside(p + b)) { s += vec2(px(p + b), 1.); }
+    return s;
+}
+
+// means of the neighbors inside the image rounded down, so border pixels
+// are averaged over the available neighbors as in the CPU implementation
+float mean(vec2 s) { return floor(s.x/s.y); }
+float cross_mean(ivec2 p) {
+    return mean(pair(p, ivec2(0, -1), ivec2(0, 1))
+        + pair(p, ivec2(-1, 0), ivec2(1, 0)));
+}
+float diag_mean(ivec2 p) {
+    return mean(pair(p, ivec2(-1, -1), ivec2(1, -1))
+        + pair(p, ivec2(-1, 1), ivec2(1, 1)));
+}
+float row_mean(ivec2 p) { return mean(pair(p, ivec2(-1, 0), ivec2(1, 0))); }
+float col_mean(ivec2 p) { return mean(pair(p, ivec2(0, -1), ivec2(0, 1))); }
+
+// sums of the same pixel neighbors used by Malvar-He-Cutler filters
+float cross1(ivec2 p) {
+    return px(p + ivec2(0, -1)) + px(p + ivec2(0, 1))
+        + px(p + ivec2(-1, 0)) + px(p + ivec2(1, 0));
+}
+
+float diag1(ivec2 p) {
+    return px(p + ivec2(-1, -1)) + px(p + ivec2(1, -1))
+        + px(p + ivec2(-1, 1)) + px(p + ivec2(1, 1));
+}
+
+float cross2(ivec2 p) {
+    return px(p + ivec2(0, -2)) + px(p + ivec2(0, 2))
+        + px(p + ivec2(-2, 0)) + px(p + ivec2(2, 0));
+}
+
+float row1(ivec2 p) { return px(p + ivec2(-1, 0)) + px(p + ivec2(1, 0)); }
+float col1(ivec2 p) { return px(p + ivec2(0, -1)) + px(p + ivec2(0, 1)); }
+float row2(ivec2 p) { return px(p + ivec2(-2, 0)) + px(p + ivec2(2, 0)); }
+float col2(ivec2 p) { return px(p + ivec2(0, -2)) + px(p + ivec2(0, 2)); }
+
+// Bayer interpolation for the pixel `p` of the RGGB mosaic
+vec3 bilinear(ivec2 p) {
+    float c = px(p);
+    ivec2 site = p & 1;
+    if (site == ivec2(0, 0)) {
+        return vec3(c, cross_mean(p), diag_mean(p));
+    } else if (site == ivec2(1, 0)) {
+        return vec3(row_mean(p), c, col_mean(p));
+    } else if (site == ivec2(0, 1)) {
+        return vec3(col_mean(p), c, row_mean(p));
+    } else {
+        return vec3(diag_mean(p), cross_mean(p), c);
+    }
+}
+
+// Malvar-He-Cutler gradient-corrected interpolation, coefficients are
+// multiplied by 16 as in the CPU implementation, two pixels wide border is
+// interpolated bi-linearly
+vec3 malvar(ivec2 p) {
+    ivec2 size = ivec2(pc.width, pc.height);
+    if (any(lessThan(p, ivec2(2))) || any(greaterThanEqual(p, size - 2))) {
+        return bilinear(p);
+    }
+    float c = px(p);
+    ivec2 site = p & 1;
+    float g_at_rb = 8.*c + 4.*cross1(p) - 2.*cross2(p);
+    float rb_at_br = 12.*c + 4.*diag1(p) - 3.*cross2(p);
+    float at_g_row = 10.*c + 8.*row1(p) - 2.*row2(p) - 2.*diag1(p) + col2(p);
+    float at_g_col = 10.*c + 8.*col1(p) - 2.*col2(p) - 2.*diag1(p) + row2(p);
+    vec3 v;
+    if (site == ivec2(0, 0)) {
+        v = vec3(16.*c, g_at_rb, rb_at_br);
+    } else if (site == ivec2(1, 0)) {
+        v = vec3(at_g_row, 16.*c, at_g_col);
+    } else if (site == ivec2(0, 1)) {
+        v = vec3(at_g_col, 16.*c, at_g_row);
+    } else {
+        v = vec3(rb_at_br, g_at_rb, 16.*c);
+    }
+    return floor(v/16. + 0.5);
+}
+
+// fixed-point conversion with the same coefficients and rounding as the
+// CPU implementation
+vec3 yuyv(uint i) {
+    // chroma samples of the macropixel shared by both of its pixels
+    uint m = 2u*(i & ~1u);
+    int y = (int(byte_at(2u*i)) - pc.y_offset)*pc.y_scale + 4096;
+    int cb = int(byte_at(m + 1u)) - 128;
+    int cr = int(byte_at(m + 3u)) - 128;
+    return vec3(
+        (y + pc.cr_to_r*cr) >> 13,
+        (y + pc.cb_to_g*cb + pc.cr_to_g*cr) >> 13,
+        (y + pc.cb_to_b*cb) >> 13
+    );
+}
+
+void main() {
+    ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+    if (p.x >= int(pc.width) || p.y >= int(pc.height)) {
+        return;
+    }
+    uint i = uint(p.y)*pc.width + uint(p.x);
+    vec3 v;
+    if (pc.format == 0u) {
+        v = vec3(byte_at(i));
+    } else if (pc.format == 1u) {
+        v = pc.algorithm == 1u ? malvar(p) : bilinear(p);
+    } else if (pc.format == 2u) {
+        v = yuyv(i);
+    } else if (pc.format == 3u) {
+        v = vec3(byte_at(3u*i), byte_at(3u*i + 1u), byte_at(3u*i + 2u));
+    } else {
+        v = vec3(byte_at(3u*i + 2u), byte_at(3u*i + 1u), byte_at(3u*i));
+    }
+    uvec3 c = uvec3(clamp(v, 0., 255.));
+    rgba.data[i] = c.r | (c.g << 8) | (c.b << 16) | (255u << 24);
+}
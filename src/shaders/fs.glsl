@@ -2,6 +2,18 @@
 layout(location = 0) in vec2 tex_coords;
 layout(location = 0) out vec4 f_color;
 layout(set = 0, binding = 0) uniform sampler2D tex;
+layout(set = 0, binding = 1) uniform sampler2D remap;
+layout(push_constant) uniform pushConstants {
+    layout(offset = 64) uint undistort;
+} push_const;
 void main() {
-    f_color = texture(tex, tex_coords);
+    vec2 coords = tex_coords;
+    if (push_const.undistort != 0) {
+        if (any(lessThan(coords, vec2(0))) || any(greaterThan(coords, vec2(1)))) {
+            f_color = vec4(0);
+            return;
+        }
+        coords = texture(remap, coords).xy;
+    }
+    f_color = texture(tex, coords);
 }
@@ -3,6 +3,10 @@
 //layout(location = 0) in vec2 position;
 layout(location = 0) out vec4 f_color;
 
+layout(push_constant) uniform pushConstants {
+    layout(offset = 48) vec4 color;
+} push_const;
+
 void main() {
-    f_color = vec4(0, 0, 1, 1);
+    f_color = push_const.color;
 }
@@ -0,0 +1,32 @@
+#version 450
+layout(location = 0) in vec2 tex_coords;
+layout(location = 0) out vec4 f_color;
+layout(set = 0, binding = 0) uniform sampler2D left;
+layout(set = 0, binding = 1) uniform sampler2D right;
+layout(set = 0, binding = 2) uniform sampler2D remap;
+layout(push_constant) uniform pushConstants {
+    layout(offset = 64) uint undistort;
+    uint mode;
+} push_const;
+void main() {
+    // calibration belongs to the first camera, only the left image is
+    // undistorted
+    vec4 l = vec4(0);
+    if (push_const.undistort == 0) {
+        l = texture(left, tex_coords);
+    } else if (all(greaterThanEqual(tex_coords, vec2(0)))
+            && all(lessThanEqual(tex_coords, vec2(1)))) {
+        l = texture(left, texture(remap, tex_coords).xy);
+    }
+    vec4 r = texture(right, tex_coords);
+    float alpha = max(l.a, r.a);
+    if (push_const.mode == 1) {
+        f_color = mix(l, r, 0.5);
+    } else if (push_const.mode == 2) {
+        vec3 lum = vec3(0.299, 0.587, 0.114);
+        float r_lum = dot(r.rgb, lum);
+        f_color = vec4(dot(l.rgb, lum), r_lum, r_lum, alpha);
+    } else {
+        f_color = vec4(abs(l.rgb - r.rgb), alpha);
+    }
+}
@@ -0,0 +1,140 @@
+//! SSE2 kernels of the CPU frame conversion, results are identical to the
+//! scalar code which processes the remaining pixels
+use std::arch::x86_64::*;
+use std::ptr;
+
+use yuv::{Coeffs, SHIFT};
+
+/// Left neighbors, centers and right neighbors of 8 pixels
+type Row = (__m128i, __m128i, __m128i);
+
+/// Load 8 samples starting from `i` widened to 16 bits
+#[inline(always)]
+unsafe fn load8(data: &[u8], i: usize) -> __m128i {
+    debug_assert!(i + 8 <= data.len());
+    let v = _mm_loadl_epi64(data.as_ptr().add(i) as *const __m128i);
+    _mm_unpacklo_epi8(v, _mm_setzero_si128())
+}
+
+/// Take 16-bit lanes of even pixels from `even` and of odd ones from `odd`
+#[inline(always)]
+unsafe fn blend(even: __m128i, odd: __m128i) -> __m128i {
+    let mask = _mm_set1_epi32(0xffff);
+    _mm_or_si128(_mm_and_si128(mask, even), _mm_andnot_si128(mask, odd))
+}
+
+/// Store 8 pixels given as 16-bit channel values saturated to 8 bits as
+/// RGB triplets, every pixel is written as 4 bytes, so the byte following
+/// the last pixel is overwritten and must be written afterwards
+#[inline(always)]
+unsafe fn store8(dst: *mut u8, r: __m128i, g: __m128i, b: __m128i) {
+    let zero = _mm_setzero_si128();
+    let rg = _mm_unpacklo_epi8(
+        _mm_packus_epi16(r, zero), _mm_packus_epi16(g, zero),
+    );
+    let b = _mm_unpacklo_epi8(_mm_packus_epi16(b, zero), zero);
+    let mut px = [0u32; 8];
+    let p = px.as_mut_ptr() as *mut __m128i;
+    _mm_storeu_si128(p, _mm_unpacklo_epi16(rg, b));
+    _mm_storeu_si128(p.add(1), _mm_unpackhi_epi16(rg, b));
+    for (i, &v) in px.iter().enumerate() {
+        ptr::write_unaligned(dst.add(3*i) as *mut u32, v);
+    }
+}
+
+/// Bi-linear interpolation of 8 pixels of the row pair `y` into `buf`
+/// starting from the even column `x`, matches `rggb::core`, pixel `x + 8`
+/// of both rows has to be interpolated after this call
+#[inline(always)]
+pub unsafe fn core8(
+    buf: &mut [u8], data: &[u8], x: usize, y: usize, w: usize,
+) {
+    debug_assert!(x >= 2 && x + 8 < w && y >= 1);
+    debug_assert!(buf.len() == 6*w);
+    debug_assert!((y + 3)*w <= data.len());
+
+    let row = |r: usize| -> Row {
+        let i = r*w + x;
+        (load8(data, i - 1), load8(data, i), load8(data, i + 1))
+    };
+    let (a, b, c, d) = (row(y - 1), row(y), row(y + 1), row(y + 2));
+
+    // sums of neighbors of the center row pixels, divided by the number
+    // of summed values
+    let sums = |u: Row, m: Row, l: Row| {
+        let vert = _mm_add_epi16(u.1, l.1);
+        let horiz = _mm_add_epi16(m.0, m.2);
+        let cross = _mm_add_epi16(vert, horiz);
+        let diag = _mm_add_epi16(
+            _mm_add_epi16(u.0, u.2), _mm_add_epi16(l.0, l.2),
+        );
+        (
+            _mm_srli_epi16(vert, 1), _mm_srli_epi16(horiz, 1),
+            _mm_srli_epi16(cross, 2), _mm_srli_epi16(diag, 2),
+        )
+    };
+
+    // first row: even pixels have color of channel 0, odd pixels are green
+    let (vert, horiz, cross, diag) = sums(a, b, c);
+    store8(
+        buf.as_mut_ptr().add(3*x),
+        blend(b.1, horiz), blend(cross, b.1), blend(diag, vert),
+    );
+
+    // second row: even pixels are green, odd pixels have color of channel 2
+    let (vert, horiz, cross, diag) = sums(b, c, d);
+    store8(
+        buf.as_mut_ptr().add(3*(w + x)),
+        blend(vert, diag), blend(c.1, cross), blend(horiz, c.1),
+    );
+}
+
+/// Convert 8 pixels of YUYV frame starting from the even pixel `i`,
+/// matches `demosaic::yuv_to_rgb`, pixel `i + 8` has to be converted after
+/// this call
+#[inline(always)]
+pub unsafe fn yuyv8(
+    buf: &mut [[u8; 3]], frame: &[u8], i: usize, coeffs: &Coeffs,
+) {
+    debug_assert!(i + 8 < buf.len());
+    debug_assert!(frame.len() == 2*buf.len());
+
+    let v = _mm_loadu_si128(frame.as_ptr().add(2*i) as *const __m128i);
+    let zero = _mm_setzero_si128();
+    let y = _mm_sub_epi16(
+        _mm_and_si128(v, _mm_set1_epi16(0xff)),
+        _mm_set1_epi16(coeffs.y_offset as i16),
+    );
+    let c = _mm_sub_epi16(_mm_srli_epi16(v, 8), _mm_set1_epi16(128));
+    // chroma lanes alternate Cb and Cr, both pixels of a macropixel
+    // share them
+    let cb = _mm_shufflehi_epi16(_mm_shufflelo_epi16(c, 0xa0), 0xa0);
+    let cr = _mm_shufflehi_epi16(_mm_shufflelo_epi16(c, 0xf5), 0xf5);
+
+    // 16-bit lane pairs multiplied by a pair of coefficients and summed
+    // into 32-bit lanes
+    let pair = |a: i32, b: i32| _mm_set1_epi32((b << 16) | (a & 0xffff));
+    let y_scale = pair(coeffs.y_scale, 0);
+    let round = _mm_set1_epi32(1 << (SHIFT - 1));
+    let luma = [
+        _mm_add_epi32(
+            _mm_madd_epi16(_mm_unpacklo_epi16(y, zero), y_scale), round,
+        ),
+        _mm_add_epi32(
+            _mm_madd_epi16(_mm_unpackhi_epi16(y, zero), y_scale), round,
+        ),
+    ];
+    let cbcr = [_mm_unpacklo_epi16(cb, cr), _mm_unpackhi_epi16(cb, cr)];
+    let channel = |kb: i32, kr: i32| {
+        let k = pair(kb, kr);
+        let half = |j: usize| _mm_srai_epi32(
+            _mm_add_epi32(luma[j], _mm_madd_epi16(cbcr[j], k)), SHIFT,
+        );
+        _mm_packs_epi32(half(0), half(1))
+    };
+    let k = coeffs.k;
+    store8(
+        buf.as_mut_ptr().add(i) as *mut u8,
+        channel(0, k[0]), channel(k[1], k[2]), channel(k[3], 0),
+    );
+}
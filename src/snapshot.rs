@@ -0,0 +1,386 @@
+//! Saving of the active camera frame in several formats together with the
+//! capture metadata, frames are converted, encoded and written by
+//! a background thread
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use super::EngineState;
+use cam::FrameBuf;
+use demosaic::Conversion;
+use orientation::Orientation;
+use save;
+use tile::Tile;
+use v4l2;
+
+/// Maximum number of frames waiting to be written
+const WRITE_QUEUE: usize = 4;
+
+/// Format of the saved frames
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SaveFormat {
+    /// 8-bit PNG image with metadata in a text chunk
+    Png,
+    /// TIFF image with metadata in the image description, 8-bit values are
+    /// scaled by 257 into 16-bit samples, so the precision is still 8 bits
+    Tiff,
+    /// Binary PPM or, for greyscale cameras, PGM image with metadata in
+    /// the header comment
+    Pnm,
+    /// Frame data as received from the camera with a JSON sidecar file
+    Raw,
+    /// DNG image with the raw Bayer mosaic and color matrices of the
+    /// processing profile
+    Dng,
+}
+
+impl SaveFormat {
+    pub fn next(self) -> Self {
+        match self {
+            SaveFormat::Png => SaveFormat::Tiff,
+            SaveFormat::Tiff => SaveFormat::Pnm,
+            SaveFormat::Pnm => SaveFormat::Raw,
+            SaveFormat::Raw => SaveFormat::Dng,
+            SaveFormat::Dng => SaveFormat::Png,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SaveFormat::Png => "PNG",
+            SaveFormat::Tiff => "16-bit TIFF",
+            SaveFormat::Pnm => "PPM/PGM",
+            SaveFormat::Raw => "raw + JSON",
+            SaveFormat::Dng => "DNG",
+        }
+    }
+
+    /// Frames are saved after conversion into RGB
+    pub fn is_converted(self) -> bool {
+        match self {
+            SaveFormat::Png | SaveFormat::Tiff | SaveFormat::Pnm => true,
+            SaveFormat::Raw | SaveFormat::Dng => false,
+        }
+    }
+
+    fn extension(self, grey: bool) -> &'static str {
+        match self {
+            SaveFormat::Png => "png",
+            SaveFormat::Tiff => "tiff",
+            SaveFormat::Pnm if grey => "pgm",
+            SaveFormat::Pnm => "ppm",
+            SaveFormat::Raw => "raw",
+            SaveFormat::Dng => "dng",
+        }
+    }
+}
+
+/// Parse saved frame format name
+pub(crate) fn parse_save_format(s: &str) -> Result<SaveFormat, &'static str> {
+    match s {
+        "png" => Ok(SaveFormat::Png),
+        "tiff" => Ok(SaveFormat::Tiff),
+        "pnm" => Ok(SaveFormat::Pnm),
+        "raw" => Ok(SaveFormat::Raw),
+        "dng" => Ok(SaveFormat::Dng),
+        _ => Err("save format must be png, tiff, pnm, raw or dng"),
+    }
+}
+
+/// Frame saving settings
+pub(crate) struct Snapshot {
+    /// Directory for saved frames
+    pub dir: PathBuf,
+    /// File name template without extension
+    pub template: String,
+    pub format: SaveFormat,
+    pub writer: Writer,
+}
+
+/// Camera parameters needed to save its frames, stored with the frames as
+/// metadata
+#[derive(Clone)]
+pub(crate) struct Camera {
+    device: String,
+    /// Device name reported by the driver
+    card: String,
+    fourcc: [u8; 4],
+    resolution: [u32; 2],
+    is_grey: bool,
+    /// Conversion of the converted formats, its color processing profile
+    /// is used for DNG color matrices
+    conversion: Conversion,
+    controls: Vec<v4l2::Control>,
+}
+
+/// Quote string as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Camera {
+    /// Take parameters of the tile camera, device is not queried
+    pub fn new(tile: &Tile) -> Self {
+        Camera {
+            device: tile.name.clone(),
+            card: String::new(),
+            fourcc: tile.format,
+            resolution: tile.resolution,
+            is_grey: tile.is_grey,
+            conversion: tile.conversion,
+            controls: Vec::new(),
+        }
+    }
+
+    /// Query driver name and control values of the device, they are left
+    /// empty if they can not be read. Controls of USB cameras are read
+    /// over the bus, so it is not done on the rendering thread.
+    pub fn query_device(&mut self) {
+        self.card = v4l2::card(&self.device).unwrap_or_default();
+        self.controls = v4l2::controls(&self.device).unwrap_or_default();
+    }
+
+    /// Convert frame into RGB if the format needs it and the camera worker
+    /// has not done it
+    pub fn prepare(&self, frame: &mut FrameBuf, format: SaveFormat) {
+        if format.is_converted() {
+            frame.convert(self.fourcc, self.resolution, &self.conversion);
+        }
+    }
+
+    pub fn is_bayer(&self) -> bool {
+        save::cfa_pattern(self.fourcc).is_some()
+    }
+
+    fn fourcc(&self) -> String {
+        String::from_utf8_lossy(&self.fourcc).into_owned()
+    }
+
+    /// Metadata of the frame with timestamp `ts` as a single line JSON
+    /// object
+    fn to_json(&self, ts: u64) -> String {
+        let controls: Vec<String> = self.controls.iter()
+            .map(|c| format!("{}: {}", json_string(&c.name), c.value))
+            .collect();
+        format!(
+            "{{\"device\": {}, \"card\": {}, \"fourcc\": {}, \"width\": {}, \
+            \"height\": {}, \"timestamp_us\": {}, \"controls\": {{{}}}}}",
+            json_string(&self.device), json_string(&self.card),
+            json_string(&self.fourcc()), self.resolution[0],
+            self.resolution[1], ts, controls.join(", "),
+        )
+    }
+
+    /// Description of the raw frame with timestamp `ts` for DNG, color
+    /// matrices are derived from the white balance and color correction of
+    /// the processing profile, without a profile camera primaries are
+    /// assumed to match sRGB
+    fn dng(&self, ts: u64) -> Result<save::Dng, Box<Error>> {
+        let cfa = match save::cfa_pattern(self.fourcc) {
+            Some(cfa) => cfa,
+            None => Err("DNG requires a raw Bayer camera")?,
+        };
+        let isp = &self.conversion.isp;
+        let (color_matrix, neutral) = match isp.xyz_to_camera() {
+            Some(m) => m,
+            None => Err("color matrix of the profile is singular")?,
+        };
+        Ok(save::Dng {
+            // unique camera model must not be empty
+            model: if self.card.is_empty() {
+                self.device.clone()
+            } else {
+                self.card.clone()
+            },
+            cfa,
+            black_level: isp.black_level,
+            color_matrix,
+            neutral,
+            description: self.to_json(ts),
+        })
+    }
+
+    /// Expand `{ts}`, `{cam}`, `{dev}` and `{fourcc}` placeholders of the
+    /// file name template, camera index is appended to templates without
+    /// camera placeholders if there are several cameras
+    pub fn file_name(
+        &self, template: &str, ts: u64, cam: usize, cameras: usize,
+    ) -> String {
+        let mut name = template.to_string();
+        if cameras > 1 && !name.contains("{cam}") && !name.contains("{dev}")
+        {
+            name.push_str("_cam{cam}");
+        }
+        // same naming as the per-camera files, e.g. `dev_video0`
+        let dev = self.device.trim_start_matches('/').replace('/', "_");
+        name.replace("{ts}", &ts.to_string())
+            .replace("{cam}", &cam.to_string())
+            .replace("{dev}", &dev)
+            .replace("{fourcc}", &self.fourcc())
+    }
+}
+
+/// Write frame into `path` with the format extension appended, converted
+/// formats require converted frame, which is optionally oriented. Returns
+/// path of the written file.
+pub(crate) fn write(
+    path: &Path, format: SaveFormat, frame: &FrameBuf, camera: &Camera,
+    orientation: Option<&Orientation>,
+) -> Result<String, Box<Error>> {
+    let ext = format.extension(camera.is_grey);
+    let path = format!("{}.{}", path.display(), ext);
+    let json = camera.to_json(frame.ts);
+    let grey = camera.is_grey;
+    let mut res = camera.resolution;
+    let oriented;
+    let buf: &[[u8; 3]] = match orientation {
+        Some(o) if format.is_converted() => {
+            oriented = o.apply(&frame.buf, res);
+            res = o.apply_res(res);
+            &oriented
+        },
+        _ => &frame.buf,
+    };
+
+    match format {
+        SaveFormat::Png => save::png_described(&path, buf, res, grey, &json)?,
+        SaveFormat::Pnm => save::pnm(&path, buf, res, grey, &json)?,
+        SaveFormat::Tiff => {
+            let nc = if grey { 1 } else { 3 };
+            // 8-bit values are scaled to the full 16-bit range
+            let data: Vec<u16> = buf.iter()
+                .flat_map(|p| p[..nc].iter())
+                .map(|&v| 257*u16::from(v))
+                .collect();
+            save::tiff16(&path, &data, res, nc, &json)?
+        },
+        SaveFormat::Raw => {
+            save::raw(&path, &frame.raw)?;
+            let sidecar = format!("{}.json", path);
+            fs::write(&sidecar, format!("{}\n", json))
+                .map_err(|err| format!("{}: {}", sidecar, err))?;
+        },
+        SaveFormat::Dng => {
+            let info = camera.dng(frame.ts)?;
+            save::dng(&path, &frame.raw, camera.resolution, &info)?
+        },
+    }
+    Ok(path)
+}
+
+/// Frame saving request
+struct Job {
+    /// Path without extension
+    path: PathBuf,
+    format: SaveFormat,
+    frame: FrameBuf,
+    camera: Camera,
+    orientation: Option<Orientation>,
+}
+
+/// Background thread writing frames from a bounded queue
+pub(crate) struct Writer {
+    tx: Option<SyncSender<Job>>,
+    /// Paths of the written files or errors
+    results: Receiver<Result<String, String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Job>(WRITE_QUEUE);
+        let (results_tx, results) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for mut job in rx.iter() {
+                job.camera.query_device();
+                job.camera.prepare(&mut job.frame, job.format);
+                let res = write(
+                    &job.path, job.format, &job.frame, &job.camera,
+                    job.orientation.as_ref(),
+                ).map_err(|err| format!("{}: {}", job.path.display(), err));
+                // receiver is dropped only together with the sender
+                let _ = results_tx.send(res);
+            }
+        });
+        Writer { tx: Some(tx), results, thread: Some(thread) }
+    }
+
+    fn send(&self, job: Job) -> Result<(), Box<Error>> {
+        let tx = self.tx.as_ref().expect("writer is stopped");
+        match tx.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(format!(
+                "{} frames are waiting to be written, frame skipped",
+                WRITE_QUEUE,
+            ).into()),
+            Err(TrySendError::Disconnected(_)) => {
+                Err("writer thread has stopped".into())
+            },
+        }
+    }
+}
+
+impl Drop for Writer {
+    /// Queued frames are written before exit
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Queue the current frame of the active camera for saving, it is copied,
+/// so the camera worker is not blocked while frame is encoded
+pub(crate) fn save(state: &EngineState) -> Result<(), Box<Error>> {
+    let settings = &state.snapshot;
+    let format = settings.format;
+    let tile = state.tile();
+    let camera = Camera::new(tile);
+    if format == SaveFormat::Dng && !camera.is_bayer() {
+        Err("DNG requires a raw Bayer camera")?
+    }
+
+    let frame = tile.cam_mutex.lock().unwrap().clone();
+    let name = camera.file_name(
+        &settings.template, frame.ts, state.active, state.tiles.len(),
+    );
+    let path = settings.dir.join(name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let orientation = if state.save_oriented {
+        Some(state.orientation)
+    } else {
+        None
+    };
+    settings.writer.send(Job { path, format, frame, camera, orientation })
+}
+
+/// Report frames written since the last update
+pub(crate) fn update(state: &mut EngineState) {
+    let results: Vec<_> = state.snapshot.writer.results.try_iter().collect();
+    for res in results {
+        let msg = match res {
+            Ok(path) => format!("Saved: {}", path),
+            Err(err) => format!("Save failed: {}", err),
+        };
+        state.osd.notify(msg);
+    }
+}
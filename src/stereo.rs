@@ -0,0 +1,86 @@
+use super::{ColorVertex, EngineState};
+
+/// Number of intervals between horizontal guide lines
+const GUIDE_INTERVALS: usize = 16;
+const GUIDE_COLOR: [f32; 4] = [0., 1., 0., 0.5];
+
+/// How the first two cameras are shown as a stereo pair, the first camera
+/// is the left one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum StereoMode {
+    /// Cameras are shown as independent tiles
+    Off,
+    /// Left and right images side by side with shared zoom and pan
+    SideBySide,
+    /// Average of the left and right images
+    Blend,
+    /// Left image in the red channel and right image in the green and blue
+    /// channels
+    Anaglyph,
+    /// Absolute difference of the left and right images
+    Difference,
+}
+
+impl StereoMode {
+    pub fn next(self) -> Self {
+        use self::StereoMode::*;
+        match self {
+            Off => SideBySide,
+            SideBySide => Blend,
+            Blend => Anaglyph,
+            Anaglyph => Difference,
+            Difference => Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use self::StereoMode::*;
+        match self {
+            Off => "off",
+            SideBySide => "side-by-side",
+            Blend => "blend",
+            Anaglyph => "anaglyph",
+            Difference => "difference",
+        }
+    }
+
+    /// Composition code used by the stereo fragment shader, zero if images
+    /// are drawn separately
+    pub fn composite(self) -> u32 {
+        use self::StereoMode::*;
+        match self {
+            Off | SideBySide => 0,
+            Blend => 1,
+            Anaglyph => 2,
+            Difference => 3,
+        }
+    }
+}
+
+/// Copy zoom and pan of the active stereo tile to the other one
+pub(crate) fn sync(state: &mut EngineState) {
+    let (src, dst) = if state.active == 1 { (1, 0) } else { (0, 1) };
+    let pc = state.tiles[src].push_consts;
+    let dst = &mut state.tiles[dst].push_consts;
+    dst.zoom = pc.zoom;
+    dst.offset = pc.offset;
+}
+
+/// Timestamp difference between the right and left frames in milliseconds
+pub(crate) fn ts_diff(state: &EngineState) -> f64 {
+    let (l, r) = (state.tiles[0].frame_ts, state.tiles[1].frame_ts);
+    (r as i64 - l as i64) as f64/1000.
+}
+
+/// Build horizontal guide lines spanning the whole window, used to check
+/// vertical alignment of the rectified stereo pair
+pub(crate) fn guide_lines() -> Vec<ColorVertex> {
+    let mut out = Vec::new();
+    for i in 1..GUIDE_INTERVALS {
+        let y = 2.*(i as f32)/(GUIDE_INTERVALS as f32) - 1.;
+        for &x in [-1., 1.].iter() {
+            out.push(ColorVertex { position: [x, y], color: GUIDE_COLOR });
+        }
+    }
+    out
+}
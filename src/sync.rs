@@ -0,0 +1,342 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use cam::FrameBuf;
+use snapshot::{self, Camera, SaveFormat};
+use tile::Tile;
+
+/// Maximum number of frames waiting for a match per camera, also limits
+/// frames waiting for the matching thread
+const MAX_QUEUE: usize = 8;
+/// Maximum number of frame sets waiting to be written by the recorder
+const RECORD_QUEUE: usize = 4;
+
+/// Sending end of the synchronization channel owned by a camera worker
+pub struct Sink {
+    index: usize,
+    tx: SyncSender<(usize, FrameBuf)>,
+    active: Arc<AtomicBool>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Sink {
+    /// Check if frames are matched for display, statistics or recording,
+    /// so copies of the frames have to be sent
+    pub fn wants_frames(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Send copy of the frame to the matching thread, frame is counted as
+    /// unmatched if the thread falls behind
+    pub fn send(&self, frame: FrameBuf) {
+        // matching thread lives as long as the application
+        if let Err(TrySendError::Full(_)) =
+            self.tx.try_send((self.index, frame))
+        {
+            self.shared.lock().unwrap().stats.unmatched[self.index] += 1;
+        }
+    }
+}
+
+/// Frame matching statistics
+#[derive(Clone)]
+pub(crate) struct SyncStats {
+    /// Number of matched frame sets
+    pub sets: u64,
+    /// Number of frames of each camera dropped without a match
+    pub unmatched: Vec<u64>,
+    /// Timestamp offset of each camera relative to the first one in the
+    /// latest matched set in microseconds
+    pub offsets: Vec<i64>,
+    /// Number of sets queued for recording
+    pub recorded: u64,
+    /// Number of sets skipped because recorder could not keep up
+    pub record_dropped: u64,
+}
+
+struct Shared {
+    stats: SyncStats,
+    recorder: Option<SyncSender<Vec<FrameBuf>>>,
+    /// Recorder error not reported yet
+    error: Option<String>,
+}
+
+/// Matches frames from several cameras by their driver timestamps and
+/// feeds matched sets to display and recording
+pub(crate) struct Synchronizer {
+    /// Maximum timestamp difference inside a set in microseconds
+    pub tolerance: u64,
+    shared: Arc<Mutex<Shared>>,
+    active: Arc<AtomicBool>,
+    rx: Option<Receiver<(usize, FrameBuf)>>,
+    /// Frames of the camera workers
+    workers: Vec<Arc<Mutex<FrameBuf>>>,
+    /// Latest matched set, frames are matched continuously with several
+    /// cameras
+    outputs: Vec<Arc<Mutex<FrameBuf>>>,
+    /// Matched sets are shown instead of the latest frames
+    display: bool,
+    dir: PathBuf,
+    format: SaveFormat,
+}
+
+/// Take the oldest set of frames with timestamps within the tolerance,
+/// frames which can not be matched anymore are dropped and counted
+fn match_set(
+    queues: &mut [VecDeque<FrameBuf>], tolerance: u64, unmatched: &mut [u64],
+) -> Option<Vec<FrameBuf>> {
+    loop {
+        let (mut min, mut max) = ((0, ::std::u64::MAX), 0);
+        for (i, q) in queues.iter().enumerate() {
+            let ts = q.front()?.ts;
+            if ts < min.1 { min = (i, ts); }
+            max = max.max(ts);
+        }
+        if max - min.1 <= tolerance {
+            return Some(queues.iter_mut()
+                .map(|q| q.pop_front().unwrap())
+                .collect());
+        }
+        // newer frames of the other cameras will be even further away
+        queues[min.0].pop_front();
+        unmatched[min.0] += 1;
+    }
+}
+
+/// Write frame sets into the directory until the sending side is dropped
+fn record(
+    rx: Receiver<Vec<FrameBuf>>, dir: PathBuf, format: SaveFormat,
+    mut cameras: Vec<Camera>, shared: Arc<Mutex<Shared>>,
+) {
+    for camera in cameras.iter_mut() {
+        camera.query_device();
+    }
+    for mut set in rx.iter() {
+        let n = set.len();
+        let ts = set[0].ts;
+        for (i, frame) in set.iter_mut().enumerate() {
+            let name = if n > 1 {
+                format!("{}_cam{}", ts, i)
+            } else {
+                frame.ts.to_string()
+            };
+            let path = dir.join(name);
+            let cam = &cameras[i];
+            cam.prepare(frame, format);
+            if let Err(err) = snapshot::write(&path, format, frame, cam, None) {
+                let mut shared = shared.lock().unwrap();
+                shared.error = Some(format!("{}: {}", path.display(), err));
+                shared.recorder = None;
+                return;
+            }
+        }
+    }
+}
+
+impl Synchronizer {
+    /// Create synchronizer for `n` cameras together with sinks which should
+    /// be passed to the camera workers
+    pub fn new(n: usize, tolerance: u64, dir: &str, format: SaveFormat)
+        -> (Self, Vec<Sink>)
+    {
+        let (tx, rx) = mpsc::sync_channel(n*MAX_QUEUE);
+        let active = Arc::new(AtomicBool::new(false));
+        let shared = Arc::new(Mutex::new(Shared {
+            stats: SyncStats {
+                sets: 0,
+                unmatched: vec![0; n],
+                offsets: vec![0; n],
+                recorded: 0,
+                record_dropped: 0,
+            },
+            recorder: None,
+            error: None,
+        }));
+        let sinks = (0..n)
+            .map(|index| Sink {
+                index,
+                tx: tx.clone(),
+                active: active.clone(),
+                shared: shared.clone(),
+            })
+            .collect();
+        let sync = Synchronizer {
+            tolerance,
+            shared,
+            active,
+            rx: Some(rx),
+            workers: Vec::new(),
+            outputs: Vec::new(),
+            display: false,
+            dir: PathBuf::from(dir),
+            format,
+        };
+        (sync, sinks)
+    }
+
+    /// Start matching thread, with several cameras frames are matched
+    /// continuously and tiles show matched sets if `display` is set
+    pub fn start(&mut self, tiles: &mut [Tile], display: bool) {
+        if tiles.len() > 1 {
+            for tile in tiles.iter() {
+                let frame = tile.cam_mutex.lock().unwrap().clone();
+                self.workers.push(tile.cam_mutex.clone());
+                self.outputs.push(Arc::new(Mutex::new(frame)));
+            }
+        }
+        self.active.store(self.is_matching(), Ordering::Relaxed);
+        // single camera has nothing to match
+        if display && self.is_matching() {
+            self.show_sets(true, tiles);
+        }
+        let outputs = self.outputs.clone();
+
+        let rx = self.rx.take().expect("synchronizer is already started");
+        let shared = self.shared.clone();
+        let (n, tolerance) = (tiles.len(), self.tolerance);
+        let format = self.format;
+        thread::spawn(move || {
+            let mut queues: Vec<VecDeque<FrameBuf>> = (0..n)
+                .map(|_| VecDeque::new())
+                .collect();
+            for (i, frame) in rx.iter() {
+                let mut guard = shared.lock().unwrap();
+                let shared = &mut *guard;
+                let stats = &mut shared.stats;
+                queues[i].push_back(frame);
+                if queues[i].len() > MAX_QUEUE {
+                    queues[i].pop_front();
+                    stats.unmatched[i] += 1;
+                }
+
+                while let Some(set) =
+                    match_set(&mut queues, tolerance, &mut stats.unmatched)
+                {
+                    stats.sets += 1;
+                    for (offset, frame) in stats.offsets.iter_mut().zip(&set) {
+                        *offset = frame.ts as i64 - set[0].ts as i64;
+                    }
+
+                    // sets converted only on GPU are not saved in converted
+                    // formats, such sets are possible only right after
+                    // recording has started, raw formats do not need RGB
+                    let skip = format.is_converted()
+                        && !set.iter().all(|f| f.converted);
+                    let sent = match shared.recorder {
+                        Some(ref tx) if !skip => {
+                            Some(tx.try_send(set.clone()))
+                        },
+                        Some(_) => {
+                            stats.record_dropped += 1;
+                            None
+                        },
+                        None => None,
+                    };
+                    match sent {
+                        Some(Ok(())) => stats.recorded += 1,
+                        Some(Err(TrySendError::Full(_))) => {
+                            stats.record_dropped += 1;
+                        },
+                        Some(Err(TrySendError::Disconnected(_))) => {
+                            shared.recorder = None;
+                        },
+                        None => (),
+                    }
+
+                    for (output, frame) in outputs.iter().zip(set) {
+                        *output.lock().unwrap() = frame;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Check if frames are matched regardless of recording
+    fn is_matching(&self) -> bool {
+        !self.outputs.is_empty()
+    }
+
+    pub fn is_displayed(&self) -> bool {
+        self.display
+    }
+
+    /// Show matched sets or the latest frames of the cameras
+    pub fn set_display(&mut self, on: bool, tiles: &mut [Tile])
+        -> Result<(), Box<Error>>
+    {
+        if !self.is_matching() {
+            Err("synchronized display requires several cameras")?
+        }
+        self.show_sets(on, tiles);
+        Ok(())
+    }
+
+    fn show_sets(&mut self, on: bool, tiles: &mut [Tile]) {
+        self.display = on;
+        let sources = if on { &self.outputs } else { &self.workers };
+        for (tile, source) in tiles.iter_mut().zip(sources) {
+            tile.cam_mutex = source.clone();
+            tile.update_texture = true;
+        }
+    }
+
+    pub fn stats(&self) -> SyncStats {
+        self.shared.lock().unwrap().stats.clone()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.shared.lock().unwrap().recorder.is_some()
+    }
+
+    /// Check if recorded frames are saved after conversion into RGB
+    pub fn records_converted(&self) -> bool {
+        self.format.is_converted() && self.is_recording()
+    }
+
+    /// Start or stop writing matched sets into the recording directory,
+    /// frames are saved with metadata of `cameras` taken at the start
+    pub fn set_recording(&self, on: bool, cameras: Vec<Camera>)
+        -> Result<(), Box<Error>>
+    {
+        let mut shared = self.shared.lock().unwrap();
+        if !on {
+            // recorder thread finishes queued sets and exits
+            shared.recorder = None;
+            self.active.store(self.is_matching(), Ordering::Relaxed);
+            return Ok(());
+        }
+        if shared.recorder.is_some() { return Ok(()); }
+        let format = self.format;
+        if format == SaveFormat::Dng && !cameras.iter().all(|c| c.is_bayer()) {
+            Err("DNG recording requires raw Bayer cameras")?
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        let (tx, rx) = mpsc::sync_channel(RECORD_QUEUE);
+        let dir = self.dir.clone();
+        let thread_shared = self.shared.clone();
+        thread::spawn(move || {
+            record(rx, dir, format, cameras, thread_shared);
+        });
+        shared.recorder = Some(tx);
+        shared.stats.recorded = 0;
+        shared.stats.record_dropped = 0;
+        self.active.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Take recorder error, recording is stopped after an error
+    pub fn take_error(&self) -> Option<String> {
+        let err = self.shared.lock().unwrap().error.take();
+        if err.is_some() {
+            self.active.store(self.is_matching(), Ordering::Relaxed);
+        }
+        err
+    }
+}
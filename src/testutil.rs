@@ -0,0 +1,10 @@
+//! Helpers shared by unit tests
+
+/// Pseudo-random frame data generated by a linear congruential generator
+pub fn random_frame(len: usize, seed: u32) -> Vec<u8> {
+    let mut s = seed;
+    (0..len).map(|_| {
+        s = s.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (s >> 16) as u8
+    }).collect()
+}
@@ -0,0 +1,209 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+
+use vulkano::command_buffer::DynamicState;
+use vulkano::pipeline::viewport::Viewport;
+
+use super::{ColorVertex, EngineState, PushConstant};
+use cam::{Cam, FrameBuf};
+use defects::DefectMap;
+use demosaic::Conversion;
+use diff::FrameDiff;
+use flat::GainMap;
+use noise::Measurement;
+use orientation::Orientation;
+use stereo::StereoMode;
+use sync::Sink;
+
+const BORDER_COLOR: [f32; 4] = [1., 1., 0., 0.8];
+
+/// Camera shown in a part of the window
+pub(crate) struct Tile {
+    /// Path to the camera device
+    pub name: String,
+    pub cam_mutex: Arc<Mutex<FrameBuf>>,
+    pub resolution: [u32; 2],
+    pub format: [u8; 4],
+    pub is_grey: bool,
+    pub is_bayer: bool,
+    /// Zoom, pan and aspect ratio of the image inside the tile
+    pub push_consts: PushConstant,
+    /// Viewport covering the tile
+    pub dyn_state: DynamicState,
+    /// Position of the top left corner in window pixels
+    pub origin: [f32; 2],
+    /// Size of the tile in window pixels
+    pub dims: [f32; 2],
+    pub visible: bool,
+    pub frame_ts: u64,
+    pub update_texture: bool,
+    /// Number of received frames since the last FPS update
+    pub frames: u32,
+    /// Camera frame rate
+    pub fps: f32,
+    /// Number of frame drops reported by the camera worker
+    pub drops: u64,
+    /// Number of frames of unexpected size reported by the camera worker
+    pub bad_frames: u64,
+    pub diff: FrameDiff,
+    /// Noise measurement of the camera, running or finished
+    pub noise: Option<Measurement>,
+    pub defects: DefectMap,
+    /// Flat-field gains of the camera at its current resolution
+    pub flat: Option<GainMap>,
+    /// Indices of the raw samples corrected by the camera worker
+    pub correction: Arc<Mutex<Vec<u32>>>,
+    /// Fixed-point flat-field gains applied by the camera worker
+    pub gains: Arc<Mutex<Option<Arc<Vec<u16>>>>>,
+    /// Conversion parameters, changed only through `set_conversion` and
+    /// `update_conversion` which pass them to the camera worker
+    pub conversion: Conversion,
+    /// Conversion parameters shared with the camera worker
+    worker_conversion: Arc<Mutex<Conversion>>,
+    /// Camera worker converts frames into RGB
+    pub cpu_convert: Arc<AtomicBool>,
+    /// Texture is filled by the conversion compute shader
+    pub gpu_display: bool,
+}
+
+impl Tile {
+    /// Create tile for the camera and start its capture worker
+    pub fn new(
+        name: &str, cam: Cam, pause: Arc<AtomicBool>,
+        conversion: Conversion, sink: Sink, push_consts: PushConstant,
+    ) -> Self {
+        let worker_conversion = Arc::new(Mutex::new(conversion));
+        let correction = Arc::new(Mutex::new(Vec::new()));
+        let gains = Arc::new(Mutex::new(None));
+        let cpu_convert = Arc::new(AtomicBool::new(true));
+        Tile {
+            name: name.to_string(),
+            resolution: cam.get_resolution(),
+            format: cam.get_format(),
+            is_grey: cam.is_grey(),
+            is_bayer: cam.is_bayer(),
+            defects: DefectMap::new(cam.get_resolution()),
+            flat: None,
+            cam_mutex: cam.run_worker(
+                pause, worker_conversion.clone(), sink, correction.clone(),
+                gains.clone(), cpu_convert.clone(),
+            ),
+            conversion,
+            worker_conversion,
+            correction,
+            gains,
+            cpu_convert,
+            gpu_display: false,
+            push_consts,
+            dyn_state: DynamicState {
+                line_width: None,
+                viewports: None,
+                scissors: None,
+            },
+            origin: [0., 0.],
+            dims: [1., 1.],
+            visible: true,
+            frame_ts: 0,
+            update_texture: false,
+            frames: 0,
+            fps: 0.,
+            drops: 0,
+            bad_frames: 0,
+            diff: FrameDiff::new(),
+            noise: None,
+        }
+    }
+
+    /// Change conversion parameters used for the following frames
+    pub fn set_conversion<F: FnOnce(&mut Conversion)>(&mut self, f: F) {
+        f(&mut self.conversion);
+        *self.worker_conversion.lock().unwrap() = self.conversion;
+    }
+
+    /// Change conversion parameters, the current frame is converted again,
+    /// so the change is visible while paused
+    pub fn update_conversion<F: FnOnce(&mut Conversion)>(&mut self, f: F) {
+        self.set_conversion(f);
+        self.cam_mutex.lock().unwrap().converted = false;
+        self.update_texture = true;
+    }
+
+    /// Check if point given in window pixels lies inside the tile
+    pub fn contains(&self, p: [f32; 2]) -> bool {
+        self.visible
+            && p[0] >= self.origin[0] && p[0] < self.origin[0] + self.dims[0]
+            && p[1] >= self.origin[1] && p[1] < self.origin[1] + self.dims[1]
+    }
+
+    /// Place tile into the window rectangle and fit image into it
+    fn place(&mut self, origin: [f32; 2], dims: [f32; 2], o: Orientation) {
+        self.origin = origin;
+        self.dims = dims;
+        self.dyn_state.viewports = Some(vec![Viewport {
+            origin,
+            dimensions: dims,
+            depth_range: 0.0 .. 1.0,
+        }]);
+
+        let res = o.apply_res(self.resolution);
+        let r_p1 = dims[0]/dims[1];
+        let r_p2 = (res[0] as f32)/(res[1] as f32);
+        self.push_consts.aspect = if r_p1 > r_p2 {
+            [r_p2/r_p1, 1.]
+        } else {
+            [1.0, r_p1/r_p2]
+        };
+    }
+}
+
+/// Arrange tiles into a grid filling the window, if one of the tiles is
+/// maximized only it is shown, in stereo modes only the stereo pair is shown
+pub(crate) fn arrange(state: &mut EngineState, dims: [f32; 2]) {
+    let o = state.orientation;
+    let n = state.tiles.len();
+    if state.stereo == StereoMode::SideBySide {
+        let size = [dims[0]/2., dims[1]];
+        for (i, tile) in state.tiles.iter_mut().enumerate() {
+            let x = (i.min(1) as f32)*size[0];
+            tile.visible = i < 2;
+            tile.place([x, 0.], size, o);
+        }
+        return;
+    }
+    // composed stereo pair is drawn in place of the first tile
+    if state.stereo != StereoMode::Off || state.maximized || n == 1 {
+        let shown = match state.stereo {
+            StereoMode::Off => state.active,
+            _ => 0,
+        };
+        for (i, tile) in state.tiles.iter_mut().enumerate() {
+            tile.visible = i == shown;
+            tile.place([0., 0.], dims, o);
+        }
+        return;
+    }
+
+    let cols = (n as f32).sqrt().ceil() as usize;
+    let rows = (n + cols - 1)/cols;
+    let size = [dims[0]/(cols as f32), dims[1]/(rows as f32)];
+    for (i, tile) in state.tiles.iter_mut().enumerate() {
+        let origin = [
+            ((i % cols) as f32)*size[0], ((i / cols) as f32)*size[1],
+        ];
+        tile.visible = true;
+        tile.place(origin, size, o);
+    }
+}
+
+/// Build frame around the active tile
+pub(crate) fn border() -> Vec<ColorVertex> {
+    let c = 0.999;
+    let corners = [[-c, -c], [c, -c], [c, c], [-c, c]];
+    let mut out = Vec::new();
+    for i in 0..4 {
+        for &p in [corners[i], corners[(i + 1) % 4]].iter() {
+            out.push(ColorVertex { position: p, color: BORDER_COLOR });
+        }
+    }
+    out
+}
@@ -0,0 +1,192 @@
+//! Device queries which are not exposed by `rscam`, they do not change the
+//! device state, so they can be done while camera is streaming
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::raw::{c_int, c_ulong};
+use std::os::unix::io::AsRawFd;
+
+const BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+/// `priv` value indicating that the extended format fields are valid
+const PIX_FMT_PRIV_MAGIC: u32 = 0xfeed_cafe;
+const CTRL_FLAG_DISABLED: u32 = 0x0001;
+const CTRL_FLAG_WRITE_ONLY: u32 = 0x0040;
+const CTRL_FLAG_NEXT_CTRL: u32 = 0x8000_0000;
+// control types which values can be read with `VIDIOC_G_CTRL`
+const CTRL_TYPE_INTEGER: u32 = 1;
+const CTRL_TYPE_BOOLEAN: u32 = 2;
+const CTRL_TYPE_MENU: u32 = 3;
+const CTRL_TYPE_BITMASK: u32 = 8;
+const CTRL_TYPE_INTEGER_MENU: u32 = 9;
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+/// Request code of an ioctl with the given direction (1 is write, 2 is read
+/// and 3 is both), number and argument type
+fn request<T>(dir: c_ulong, nr: c_ulong) -> c_ulong {
+    (dir << 30)
+        | ((mem::size_of::<T>() as c_ulong) << 16)
+        | (c_ulong::from(b'V') << 8)
+        | nr
+}
+
+/// Perform ioctl with the argument of type `T`
+fn query<T>(file: &File, request: c_ulong, arg: &mut T) -> io::Result<()> {
+    let ret = unsafe { ioctl(file.as_raw_fd(), request, arg as *mut T) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn open(dev: &str) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(dev)
+}
+
+/// Convert NUL-terminated string field into `String`
+fn c_string(s: &[u8]) -> String {
+    let len = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+    String::from_utf8_lossy(&s[..len]).into_owned()
+}
+
+/// `struct v4l2_capability`
+#[repr(C)]
+struct Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct v4l2_queryctrl`
+#[repr(C)]
+struct QueryCtrl {
+    id: u32,
+    kind: u32,
+    name: [u8; 32],
+    minimum: i32,
+    maximum: i32,
+    step: i32,
+    default_value: i32,
+    flags: u32,
+    reserved: [u32; 2],
+}
+
+/// `struct v4l2_control`
+#[repr(C)]
+struct Ctrl {
+    id: u32,
+    value: i32,
+}
+
+/// `struct v4l2_pix_format`
+#[repr(C)]
+struct PixFormat {
+    width: u32,
+    height: u32,
+    pixelformat: u32,
+    field: u32,
+    bytesperline: u32,
+    sizeimage: u32,
+    colorspace: u32,
+    priv_: u32,
+    flags: u32,
+    ycbcr_enc: u32,
+    quantization: u32,
+    xfer_func: u32,
+}
+
+/// `fmt` union of `struct v4l2_format`, it is pointer aligned
+#[repr(C)]
+struct FormatUnion {
+    pix: PixFormat,
+    _rest: [u8; 200 - 48],
+    _align: [usize; 0],
+}
+
+/// `struct v4l2_format`
+#[repr(C)]
+struct Format {
+    kind: u32,
+    fmt: FormatUnion,
+}
+
+/// Color related fields of the capture format
+pub struct ColorInfo {
+    pub colorspace: u32,
+    pub ycbcr_enc: u32,
+    pub quantization: u32,
+}
+
+/// Get color information of the current capture format of the device,
+/// format is not changed, so it can be queried while camera is streaming
+pub fn color_info(dev: &str) -> io::Result<ColorInfo> {
+    let file = open(dev)?;
+    let mut fmt: Format = unsafe { mem::zeroed() };
+    fmt.kind = BUF_TYPE_VIDEO_CAPTURE;
+    // VIDIOC_G_FMT = _IOWR('V', 4, struct v4l2_format)
+    query(&file, request::<Format>(3, 4), &mut fmt)?;
+    let pix = &fmt.fmt.pix;
+    // zero selects the default value for the colorspace
+    let extended = |v: u32| if pix.priv_ == PIX_FMT_PRIV_MAGIC { v } else { 0 };
+    Ok(ColorInfo {
+        colorspace: pix.colorspace,
+        ycbcr_enc: extended(pix.ycbcr_enc),
+        quantization: extended(pix.quantization),
+    })
+}
+
+/// Name of the device reported by the driver
+pub fn card(dev: &str) -> io::Result<String> {
+    let file = open(dev)?;
+    let mut cap: Capability = unsafe { mem::zeroed() };
+    // VIDIOC_QUERYCAP = _IOR('V', 0, struct v4l2_capability)
+    query(&file, request::<Capability>(2, 0), &mut cap)?;
+    Ok(c_string(&cap.card))
+}
+
+/// Current value of a device control
+#[derive(Clone)]
+pub struct Control {
+    pub name: String,
+    pub value: i32,
+}
+
+/// Get values of all enabled readable controls of the device, controls of
+/// the types without a single integer value are skipped
+pub fn controls(dev: &str) -> io::Result<Vec<Control>> {
+    let file = open(dev)?;
+    let mut out = Vec::new();
+    let mut id = CTRL_FLAG_NEXT_CTRL;
+    loop {
+        let mut q: QueryCtrl = unsafe { mem::zeroed() };
+        q.id = id;
+        // VIDIOC_QUERYCTRL = _IOWR('V', 36, struct v4l2_queryctrl),
+        // enumeration ends with an error after the last control
+        if query(&file, request::<QueryCtrl>(3, 36), &mut q).is_err() {
+            break;
+        }
+        id = q.id | CTRL_FLAG_NEXT_CTRL;
+        let readable = matches!(
+            q.kind,
+            CTRL_TYPE_INTEGER | CTRL_TYPE_BOOLEAN | CTRL_TYPE_MENU
+                | CTRL_TYPE_BITMASK | CTRL_TYPE_INTEGER_MENU
+        );
+        if !readable
+            || q.flags & (CTRL_FLAG_DISABLED | CTRL_FLAG_WRITE_ONLY) != 0
+        {
+            continue;
+        }
+        let mut ctrl = Ctrl { id: q.id, value: 0 };
+        // VIDIOC_G_CTRL = _IOWR('V', 27, struct v4l2_control)
+        if query(&file, request::<Ctrl>(3, 27), &mut ctrl).is_ok() {
+            out.push(Control { name: c_string(&q.name), value: ctrl.value });
+        }
+    }
+    Ok(out)
+}
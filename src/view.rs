@@ -0,0 +1,103 @@
+use cam::FrameBuf;
+
+/// What part of the frame is shown on the screen
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ViewMode {
+    /// Demosaiced color image
+    Color,
+    /// Red channel as greyscale
+    Red,
+    /// Green channel as greyscale
+    Green,
+    /// Blue channel as greyscale
+    Blue,
+    /// Undemosaiced Bayer mosaic as greyscale
+    RawGrey,
+    /// Undemosaiced Bayer mosaic tinted by CFA color
+    RawCfa,
+}
+
+impl ViewMode {
+    /// Get next view mode, raw modes are skipped for non-Bayer sources
+    pub fn next(self, is_bayer: bool) -> Self {
+        use self::ViewMode::*;
+        match self {
+            Color => Red,
+            Red => Green,
+            Green => Blue,
+            Blue if is_bayer => RawGrey,
+            Blue => Color,
+            RawGrey => RawCfa,
+            RawCfa => Color,
+        }
+    }
+
+    /// Get mode used for the source, raw modes fall back to the color mode
+    /// for non-Bayer sources
+    pub fn for_source(self, is_bayer: bool) -> Self {
+        use self::ViewMode::*;
+        match self {
+            RawGrey | RawCfa if !is_bayer => Color,
+            mode => mode,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use self::ViewMode::*;
+        match self {
+            Color => "color",
+            Red => "red channel",
+            Green => "green channel",
+            Blue => "blue channel",
+            RawGrey => "raw mosaic",
+            RawCfa => "raw mosaic (CFA colors)",
+        }
+    }
+}
+
+/// Convert frame into RGBA texture data according to the view mode
+///
+/// Raw modes expect an RGGB mosaic in `frame.raw`, i.e. red samples are
+/// located at even rows and columns.
+pub(crate) fn to_rgba(mode: ViewMode, frame: &FrameBuf, width: usize)
+    -> Vec<[u8; 4]>
+{
+    use self::ViewMode::*;
+    let channel = |c: usize| frame.buf.iter()
+        .map(|p| [p[c], p[c], p[c], 255])
+        .collect();
+
+    match mode {
+        Color => frame.buf.iter().map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Red => channel(0),
+        Green => channel(1),
+        Blue => channel(2),
+        RawGrey => frame.raw.iter().map(|&v| [v, v, v, 255]).collect(),
+        RawCfa => frame.raw.iter().enumerate().map(|(i, &v)| {
+            match (i % width % 2, i / width % 2) {
+                (0, 0) => [v, 0, 0, 255],
+                (1, 1) => [0, 0, v, 255],
+                _ => [0, v, 0, 255],
+            }
+        }).collect(),
+    }
+}
+
+/// Get text representation and brightness of the pixel with index `i` as
+/// shown in the given view mode
+pub(crate) fn pixel_value(mode: ViewMode, frame: &FrameBuf, i: usize)
+    -> (String, u8)
+{
+    use self::ViewMode::*;
+    let p = frame.buf[i];
+    match mode {
+        Color => {
+            let lum = (p[0] as u16 + 2*(p[1] as u16) + p[2] as u16)/4;
+            (format!("{}\n{}\n{}", p[0], p[1], p[2]), lum as u8)
+        },
+        Red => (p[0].to_string(), p[0]),
+        Green => (p[1].to_string(), p[1]),
+        Blue => (p[2].to_string(), p[2]),
+        RawGrey | RawCfa => (frame.raw[i].to_string(), frame.raw[i]),
+    }
+}
@@ -0,0 +1,184 @@
+//! YCbCr encodings of YUV frames
+
+/// Fixed-point precision of the conversion coefficients
+pub const SHIFT: i32 = 13;
+
+// V4L2 colorspace, Y'CbCr encoding and quantization values
+const COLORSPACE_SMPTE240M: u32 = 2;
+const COLORSPACE_REC709: u32 = 3;
+const COLORSPACE_BT2020: u32 = 10;
+const COLORSPACE_DCI_P3: u32 = 12;
+const YCBCR_ENC_601: u32 = 1;
+const YCBCR_ENC_709: u32 = 2;
+const YCBCR_ENC_XV601: u32 = 3;
+const YCBCR_ENC_XV709: u32 = 4;
+const YCBCR_ENC_SYCC: u32 = 5;
+const YCBCR_ENC_BT2020: u32 = 6;
+const YCBCR_ENC_BT2020_CONST_LUM: u32 = 7;
+const YCBCR_ENC_SMPTE240M: u32 = 8;
+const QUANTIZATION_FULL_RANGE: u32 = 1;
+const QUANTIZATION_LIM_RANGE: u32 = 2;
+
+/// Matrix converting Y'CbCr into R'G'B'
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl Matrix {
+    pub fn next(self) -> Self {
+        match self {
+            Matrix::Bt601 => Matrix::Bt709,
+            Matrix::Bt709 => Matrix::Bt2020,
+            Matrix::Bt2020 => Matrix::Bt601,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Matrix::Bt601 => "BT.601",
+            Matrix::Bt709 => "BT.709",
+            Matrix::Bt2020 => "BT.2020",
+        }
+    }
+
+    /// Luma weights of red and blue
+    fn weights(self) -> (f64, f64) {
+        match self {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+            Matrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// Quantization range of the Y'CbCr samples
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Range {
+    /// Samples use all 256 levels
+    Full,
+    /// Luma takes levels 16-235 and chroma 16-240
+    Limited,
+}
+
+impl Range {
+    pub fn next(self) -> Self {
+        match self {
+            Range::Full => Range::Limited,
+            Range::Limited => Range::Full,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Range::Full => "full range",
+            Range::Limited => "limited range",
+        }
+    }
+}
+
+/// Fixed-point conversion coefficients multiplied by `2^SHIFT`, with chroma
+/// centered at 128 the conversion is `Y = y_scale*(Y' - y_offset)`,
+/// `R = Y + k[0]*Cr`, `G = Y + k[1]*Cb + k[2]*Cr` and `B = Y + k[3]*Cb`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Coeffs {
+    pub y_offset: i32,
+    pub y_scale: i32,
+    pub k: [i32; 4],
+}
+
+/// Y'CbCr encoding of the camera frames
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Encoding {
+    pub matrix: Matrix,
+    pub range: Range,
+}
+
+impl Encoding {
+    /// Encoding described by the V4L2 format fields, default matrix is
+    /// resolved as prescribed by the V4L2 specification, default
+    /// quantization is treated as full range since drivers such as UVC
+    /// leave it unset for full range cameras, SMPTE 240M is approximated by
+    /// BT.709 and constant luminance BT.2020 by the non-constant luminance
+    /// one
+    pub fn from_v4l2(colorspace: u32, ycbcr_enc: u32, quantization: u32)
+        -> Self
+    {
+        let matrix = match ycbcr_enc {
+            YCBCR_ENC_601 | YCBCR_ENC_XV601 | YCBCR_ENC_SYCC => Matrix::Bt601,
+            YCBCR_ENC_709 | YCBCR_ENC_XV709 | YCBCR_ENC_SMPTE240M
+                => Matrix::Bt709,
+            YCBCR_ENC_BT2020 | YCBCR_ENC_BT2020_CONST_LUM => Matrix::Bt2020,
+            _ => match colorspace {
+                COLORSPACE_REC709 | COLORSPACE_DCI_P3
+                    | COLORSPACE_SMPTE240M => Matrix::Bt709,
+                COLORSPACE_BT2020 => Matrix::Bt2020,
+                _ => Matrix::Bt601,
+            },
+        };
+        let range = match quantization {
+            QUANTIZATION_LIM_RANGE => Range::Limited,
+            _ => Range::Full,
+        };
+        Encoding { matrix, range }
+    }
+
+    /// Whether the driver reports the quantization range instead of
+    /// leaving it default
+    pub fn reports_range(quantization: u32) -> bool {
+        quantization == QUANTIZATION_FULL_RANGE
+            || quantization == QUANTIZATION_LIM_RANGE
+    }
+
+    pub fn name(self) -> String {
+        format!("{} {}", self.matrix.name(), self.range.name())
+    }
+
+    pub fn coeffs(self) -> Coeffs {
+        let (kr, kb) = self.matrix.weights();
+        let kg = 1. - kr - kb;
+        let (y_offset, y_scale, c) = match self.range {
+            Range::Full => (0, 1., 1.),
+            Range::Limited => (16, 255./219., 255./224.),
+        };
+        let fixed = |v: f64| (v*f64::from(1 << SHIFT)).round() as i32;
+        Coeffs {
+            y_offset,
+            y_scale: fixed(y_scale),
+            k: [
+                fixed(2.*(1. - kr)*c),
+                fixed(-2.*kb*(1. - kb)/kg*c),
+                fixed(-2.*kr*(1. - kr)/kg*c),
+                fixed(2.*(1. - kb)*c),
+            ],
+        }
+    }
+}
+
+/// Full range BT.601, used when the driver does not report the encoding
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding { matrix: Matrix::Bt601, range: Range::Full }
+    }
+}
+
+/// Parse YUV matrix name
+pub fn parse_matrix(s: &str) -> Result<Matrix, &'static str> {
+    match s {
+        "bt601" => Ok(Matrix::Bt601),
+        "bt709" => Ok(Matrix::Bt709),
+        "bt2020" => Ok(Matrix::Bt2020),
+        _ => Err("YUV matrix must be bt601, bt709 or bt2020"),
+    }
+}
+
+/// Parse YUV quantization range name
+pub fn parse_range(s: &str) -> Result<Range, &'static str> {
+    match s {
+        "full" => Ok(Range::Full),
+        "limited" => Ok(Range::Limited),
+        _ => Err("YUV range must be full or limited"),
+    }
+}